@@ -0,0 +1,169 @@
+use candid::candid_method;
+use candid::Principal;
+use core_canister::state::eventlog::{Event, GetEventsArg};
+use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_stable_structures::log::Log as StableLog;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableCell, Storable,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const MAX_EVENTS_PER_QUERY: u64 = 2000;
+
+const CORE_ID_MEMORY_ID: MemoryId = MemoryId::new(0);
+const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+type VMem = VirtualMemory<DefaultMemoryImpl>;
+
+/// The principal of the core canister, the only one appending events.
+#[derive(Clone)]
+struct CoreId(Principal);
+
+impl Storable for CoreId {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static CORE_ID: RefCell<StableCell<CoreId, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(
+                      m.borrow().get(CORE_ID_MEMORY_ID),
+                      CoreId(Principal::anonymous())
+                  ).expect("failed to initialize the core id cell")
+              )
+        );
+
+    /// The raw entries of the archived events, in the order of the core log.
+    static EVENTS: RefCell<StableLog<Vec<u8>, VMem, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableLog::init(
+                      m.borrow().get(LOG_INDEX_MEMORY_ID),
+                      m.borrow().get(LOG_DATA_MEMORY_ID)
+                  ).expect("failed to initialize the event log")
+              )
+        );
+}
+
+fn main() {}
+
+#[init]
+#[candid_method(init)]
+fn init(core_id: Principal) {
+    CORE_ID.with(|cell| {
+        cell.borrow_mut()
+            .set(CoreId(core_id))
+            .expect("failed to set the core id");
+    });
+}
+
+#[post_upgrade]
+fn post_upgrade(core_id: Principal) {
+    init(core_id)
+}
+
+fn event_count() -> u64 {
+    EVENTS.with(|events| events.borrow().len())
+}
+
+fn read_raw_events(args: &GetEventsArg) -> Vec<Vec<u8>> {
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        let end = events.len().min(
+            args.start
+                .saturating_add(MAX_EVENTS_PER_QUERY.min(args.length)),
+        );
+        (args.start..end)
+            .map(|pos| {
+                let mut buf = vec![];
+                events
+                    .read_entry(pos, &mut buf)
+                    .expect("bug: missing an event log entry");
+                buf
+            })
+            .collect()
+    })
+}
+
+/// Appends the entries of the events starting at `first_index`, the
+/// entries already stored are skipped so that the core canister can
+/// retry a failed call. Returns the number of stored events.
+#[candid_method(update)]
+#[update]
+fn append_events(first_index: u64, entries: Vec<Vec<u8>>) -> Result<u64, String> {
+    let core_id = CORE_ID.with(|cell| cell.borrow().get().0);
+    if ic_cdk::caller() != core_id {
+        return Err("only the core canister can append events".to_string());
+    }
+    let length = event_count();
+    if first_index > length {
+        return Err(format!(
+            "expected the events from {}, got the events from {}",
+            length, first_index
+        ));
+    }
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        for entry in entries.iter().skip((length - first_index) as usize) {
+            events
+                .append(entry)
+                .expect("failed to append an entry to the event log");
+        }
+    });
+    Ok(event_count())
+}
+
+#[candid_method(query)]
+#[query]
+fn get_events(args: GetEventsArg) -> Vec<Event> {
+    read_raw_events(&args)
+        .iter()
+        .map(|entry| core_canister::storage::decode_event(entry))
+        .collect()
+}
+
+/// Returns the entries as hashed in the event chain of the core canister.
+#[candid_method(query)]
+#[query]
+fn get_raw_events(args: GetEventsArg) -> Vec<Vec<u8>> {
+    read_raw_events(&args)
+}
+
+#[candid_method(query)]
+#[query]
+fn get_event_count() -> u64 {
+    event_count()
+}
+
+#[test]
+fn check_candid_interface_compatibility() {
+    use candid::utils::{service_compatible, CandidSource};
+
+    candid::export_service!();
+
+    let new_interface = __export_service();
+
+    // check the public interface against the actual one
+    let old_interface =
+        std::path::PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("archive.did");
+
+    service_compatible(
+        CandidSource::Text(&new_interface),
+        CandidSource::File(old_interface.as_path()),
+    )
+    .unwrap();
+}
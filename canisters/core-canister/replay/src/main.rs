@@ -0,0 +1,496 @@
+//! Replays a dump of the core canister event log offline, checking the
+//! invariants of the state after each event.
+//!
+//! ```text
+//! core-replay --events <file> [--events <file>]... [--status <file>] [--step <n>]
+//! ```
+//!
+//! An events file holds either the Candid reply of `get_events`, from the
+//! core canister or from the archive, as written by
+//! `dfx canister call --output raw` (hex or binary), or a CBOR array of the
+//! raw log entries served by `get_raw_events`. The files are replayed in
+//! the given order, starting with the init event.
+//!
+//! The status file holds the Candid reply of `get_protocol_status`, which
+//! is compared with the status of the replayed state.
+
+use candid::Decode;
+use core_canister::state::eventlog::{apply_events, Event, GetEventsResponse};
+use core_canister::state::{Asset, CoreState, ProtocolStatus};
+use core_canister::storage::decode_event;
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+/// The number of events between two rows of the balance timeline.
+const DEFAULT_TIMELINE_STEP: u64 = 1_000;
+
+struct Args {
+    events: Vec<PathBuf>,
+    status: Option<PathBuf>,
+    step: u64,
+}
+
+fn usage() -> String {
+    "usage: core-replay --events <file> [--events <file>]... [--status <file>] [--step <n>]"
+        .to_string()
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        events: vec![],
+        status: None,
+        step: DEFAULT_TIMELINE_STEP,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--events" => parsed.events.push(PathBuf::from(value()?)),
+            "--status" => parsed.status = Some(PathBuf::from(value()?)),
+            "--step" => {
+                parsed.step = value()?
+                    .parse()
+                    .map_err(|e| format!("bad --step value: {}", e))?;
+                if parsed.step == 0 {
+                    return Err("--step must be positive".to_string());
+                }
+            }
+            _ => return Err(usage()),
+        }
+    }
+    if parsed.events.is_empty() {
+        return Err(usage());
+    }
+    Ok(parsed)
+}
+
+/// Reads a dump, `dfx` writes the raw replies as hex.
+fn read_dump(path: &PathBuf) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("failed to read {:?}: {}", path, e))?;
+    let text = String::from_utf8_lossy(&bytes);
+    let text = text.trim();
+    if !text.is_empty() && text.len() % 2 == 0 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return hex::decode(text).map_err(|e| format!("failed to decode {:?}: {}", path, e));
+    }
+    Ok(bytes)
+}
+
+/// Decodes the events of a dump, `next_index` is the index of the first
+/// event not read from the previous dumps.
+fn decode_events(dump: &[u8], next_index: u64) -> Result<Vec<Event>, String> {
+    if dump.starts_with(b"DIDL") {
+        if let Ok(response) = Decode!(dump, GetEventsResponse) {
+            if let Some(archived) = response
+                .archived_events
+                .iter()
+                .find(|archived| archived.start + archived.length > next_index)
+            {
+                return Err(format!(
+                    "the events in [{}, {}) are archived by {}, dump them first",
+                    next_index.max(archived.start),
+                    archived.start + archived.length,
+                    archived.archive_id
+                ));
+            }
+            if response.first_index > next_index {
+                return Err(format!(
+                    "expected the events from {}, got the events from {}",
+                    next_index, response.first_index
+                ));
+            }
+            let skip = (next_index - response.first_index) as usize;
+            return Ok(response.events.into_iter().skip(skip).collect());
+        }
+        // The archive replies with the events only.
+        return Decode!(dump, Vec<Event>).map_err(|e| format!("bad Candid dump: {}", e));
+    }
+
+    use ciborium::value::Value;
+
+    let entries: Vec<Value> =
+        ciborium::de::from_reader(dump).map_err(|e| format!("bad CBOR dump: {:?}", e))?;
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            Value::Bytes(buf) => Ok(decode_event(&buf)),
+            _ => Err("the CBOR dump must be an array of log entries".to_string()),
+        })
+        .collect()
+}
+
+/// The ICP and eUSD amounts of the state, e8s.
+#[derive(Debug, PartialEq, Eq)]
+struct Balances {
+    icp_collateral: u64,
+    icp_liquidity: u64,
+    icp_leverage_margin: u64,
+    icp_collateral_covered: u64,
+    eusd_supply: u64,
+    available_fees: u64,
+}
+
+impl From<&CoreState> for Balances {
+    fn from(state: &CoreState) -> Self {
+        Self {
+            icp_collateral: state.icp_collateral_amount,
+            icp_liquidity: state.icp_liqudity_amount,
+            icp_leverage_margin: state.icp_leverage_margin_amount,
+            icp_collateral_covered: state.icp_collateral_covered_amount,
+            eusd_supply: state
+                .total_eusd_minted
+                .saturating_sub(state.total_eusd_burned),
+            available_fees: state.total_available_fees,
+        }
+    }
+}
+
+/// The fees recorded by the events per asset, e8s.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FeeTotals {
+    swap: BTreeMap<Asset, u64>,
+    liquidity: BTreeMap<Asset, u64>,
+    open_leverage: BTreeMap<Asset, u64>,
+    close_leverage: BTreeMap<Asset, u64>,
+}
+
+impl FeeTotals {
+    fn record(&mut self, event: &Event) {
+        let (fees, asset, fee) = match event {
+            // The swaps charge their fee on the swapped asset.
+            Event::Swap(swap) => (&mut self.swap, swap.from.clone(), swap.fee),
+            Event::Liquidity(liquidity) => (&mut self.liquidity, Asset::ICP, liquidity.fee),
+            Event::OpenLeveragePosition(position) => {
+                (&mut self.open_leverage, Asset::ICP, position.fee)
+            }
+            Event::CloseLeveragePosition { fee, .. }
+            | Event::LiquidateLeveragePosition { fee, .. } => {
+                (&mut self.close_leverage, Asset::ICP, *fee)
+            }
+            _ => return,
+        };
+        *fees.entry(asset).or_default() += fee;
+    }
+
+    fn total(&self) -> BTreeMap<Asset, u64> {
+        let mut total = BTreeMap::new();
+        for fees in [
+            &self.swap,
+            &self.liquidity,
+            &self.open_leverage,
+            &self.close_leverage,
+        ] {
+            for (asset, fee) in fees {
+                *total.entry(asset.clone()).or_default() += fee;
+            }
+        }
+        total
+    }
+}
+
+fn format_fees(fees: &BTreeMap<Asset, u64>) -> String {
+    if fees.is_empty() {
+        return "0".to_string();
+    }
+    fees.iter()
+        .map(|(asset, fee)| format!("{} {:?}", fee, asset))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The first event the state failed to replay or to check.
+struct Failure {
+    index: u64,
+    event: Event,
+    reason: String,
+}
+
+struct Report {
+    event_count: u64,
+    first_failure: Option<Failure>,
+    failure_count: u64,
+    timeline: Vec<(u64, Balances)>,
+    fees: FeeTotals,
+    /// The replayed state, unless an event failed to replay.
+    state: Option<CoreState>,
+}
+
+impl Report {
+    fn record_failure(&mut self, index: u64, event: &Event, reason: String) {
+        self.failure_count += 1;
+        if self.first_failure.is_none() {
+            self.first_failure = Some(Failure {
+                index,
+                event: event.clone(),
+                reason,
+            });
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|msg| msg.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn check_invariants(state: &CoreState) -> Result<(), String> {
+    catch_unwind(AssertUnwindSafe(|| state.check_invariants()))
+        .unwrap_or_else(|payload| Err(format!("panicked: {}", panic_message(payload))))
+}
+
+fn replay(events: Vec<Event>, step: u64) -> Result<Report, String> {
+    let mut events = events.into_iter();
+    let init = events
+        .next()
+        .ok_or_else(|| "there are no events to replay".to_string())?;
+    let mut state = match &init {
+        Event::Init(args) => CoreState::from(args.clone()),
+        event => return Err(format!("the first event is not Init: {:?}", event)),
+    };
+
+    let mut report = Report {
+        event_count: 1,
+        first_failure: None,
+        failure_count: 0,
+        timeline: vec![(0, Balances::from(&state))],
+        fees: FeeTotals::default(),
+        state: None,
+    };
+    if let Err(reason) = check_invariants(&state) {
+        report.record_failure(0, &init, reason);
+    }
+
+    for (index, event) in (1..).zip(events) {
+        report.event_count += 1;
+        report.fees.record(&event);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            apply_events(state, std::iter::once(event.clone()))
+        }));
+        state = match result {
+            Ok(Ok(state)) => state,
+            Ok(Err(e)) => {
+                report.record_failure(index, &event, format!("{:?}", e));
+                return Ok(report);
+            }
+            Err(payload) => {
+                let reason = format!("panicked: {}", panic_message(payload));
+                report.record_failure(index, &event, reason);
+                return Ok(report);
+            }
+        };
+        if let Err(reason) = check_invariants(&state) {
+            report.record_failure(index, &event, reason);
+        }
+        if index % step == 0 {
+            report.timeline.push((index, Balances::from(&state)));
+        }
+    }
+
+    let last_index = report.event_count - 1;
+    if report.timeline.last().map(|(index, _)| *index) != Some(last_index) {
+        report.timeline.push((last_index, Balances::from(&state)));
+    }
+    report.state = Some(state);
+    Ok(report)
+}
+
+/// Returns the fields of the replayed status which differ from the live one.
+fn diff_status(replayed: &ProtocolStatus, live: &ProtocolStatus) -> Vec<String> {
+    let mut diff = vec![];
+    macro_rules! diff_field {
+        ($($field:ident),*) => {
+            $(
+                if replayed.$field != live.$field {
+                    diff.push(format!(
+                        "{}: replayed {:?}, live {:?}",
+                        stringify!($field),
+                        replayed.$field,
+                        live.$field
+                    ));
+                }
+            )*
+        };
+    }
+    diff_field!(
+        collateral_ratio,
+        coverered_ratio,
+        icp_price,
+        tvl,
+        coverable_amount,
+        collaterals,
+        remaining_mint_capacity,
+        regime,
+        global_settlement
+    );
+    diff
+}
+
+fn print_report(report: &Report) {
+    println!("replayed {} events", report.event_count);
+    match &report.first_failure {
+        Some(failure) => {
+            println!(
+                "{} failing events, the first one is event {}: {}",
+                report.failure_count, failure.index, failure.reason
+            );
+            println!("  {:?}", failure.event);
+        }
+        None => println!("the invariants hold after every event"),
+    }
+
+    println!();
+    println!("balance timeline (e8s):");
+    println!(
+        "{:>10} {:>18} {:>18} {:>18} {:>18} {:>18} {:>18}",
+        "event", "collateral", "liquidity", "margin", "covered", "eusd supply", "fees"
+    );
+    for (index, balances) in report.timeline.iter() {
+        println!(
+            "{:>10} {:>18} {:>18} {:>18} {:>18} {:>18} {:>18}",
+            index,
+            balances.icp_collateral,
+            balances.icp_liquidity,
+            balances.icp_leverage_margin,
+            balances.icp_collateral_covered,
+            balances.eusd_supply,
+            balances.available_fees
+        );
+    }
+
+    println!();
+    println!("fee totals (e8s):");
+    println!("  swap:           {}", format_fees(&report.fees.swap));
+    println!("  liquidity:      {}", format_fees(&report.fees.liquidity));
+    println!(
+        "  open leverage:  {}",
+        format_fees(&report.fees.open_leverage)
+    );
+    println!(
+        "  close leverage: {}",
+        format_fees(&report.fees.close_leverage)
+    );
+    println!("  total:          {}", format_fees(&report.fees.total()));
+    if let Some(state) = &report.state {
+        println!("  insurance fund: {}", state.insurance_fund_amount);
+        println!("  treasury:       {}", state.treasury_amount);
+        println!("  reserve:        {}", state.reserve_amount);
+        println!("  bad debt:       {}", state.bad_debt);
+    }
+}
+
+fn run(args: Args) -> Result<bool, String> {
+    let mut events = vec![];
+    for path in args.events.iter() {
+        let dump = read_dump(path)?;
+        let decoded =
+            decode_events(&dump, events.len() as u64).map_err(|e| format!("{:?}: {}", path, e))?;
+        events.extend(decoded);
+    }
+
+    let report = replay(events, args.step)?;
+    print_report(&report);
+    let mut ok = report.first_failure.is_none();
+
+    if let Some(path) = args.status {
+        let dump = read_dump(&path)?;
+        let live = Decode!(&dump, ProtocolStatus)
+            .map_err(|e| format!("{:?}: bad protocol status: {}", path, e))?;
+        println!();
+        match &report.state {
+            Some(state) => {
+                // The status depends on the time, e.g. the mint capacity
+                // of the epoch, it is computed at the time of the live one.
+                let replayed = catch_unwind(AssertUnwindSafe(|| {
+                    state.get_protocol_status(live.timestamp)
+                }))
+                .map_err(|payload| {
+                    format!(
+                        "failed to compute the replayed status: {}",
+                        panic_message(payload)
+                    )
+                })?;
+                let diff = diff_status(&replayed, &live);
+                if diff.is_empty() {
+                    println!("the replayed state matches the live protocol status");
+                } else {
+                    println!("the replayed state differs from the live protocol status:");
+                    for line in diff.iter() {
+                        println!("  {}", line);
+                    }
+                    ok = false;
+                }
+            }
+            None => println!("the replay stopped early, skipping the protocol status diff"),
+        }
+    }
+    Ok(ok)
+}
+
+fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    match run(args) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[test]
+fn test_replay_golden_log() {
+    let events = decode_events(include_bytes!("../../test_data/events_v0.cbor"), 0)
+        .expect("failed to decode the golden log");
+    assert_eq!(events.len(), 8);
+
+    let report = replay(events, 3).expect("failed to replay the golden log");
+    assert_eq!(report.event_count, 8);
+    assert_eq!(report.fees.swap, BTreeMap::from([(Asset::ICP, 5_000_000)]));
+    assert_eq!(
+        report.fees.open_leverage,
+        BTreeMap::from([(Asset::ICP, 1_000_000)])
+    );
+    assert_eq!(
+        report.fees.total(),
+        BTreeMap::from([(Asset::ICP, 7_000_000)])
+    );
+    assert_eq!(
+        report
+            .timeline
+            .iter()
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>(),
+        vec![0, 3, 6, 7]
+    );
+    let state = report.state.expect("the replay stopped early");
+    assert_eq!(state.icp_liqudity_amount, 100_000_000);
+}
+
+#[test]
+fn test_parse_args() {
+    let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+
+    assert!(args(&[]).is_err());
+    assert!(args(&["--status", "status.bin"]).is_err());
+    assert!(args(&["--events", "events.bin", "--step", "0"]).is_err());
+
+    let parsed = args(&["--events", "a.bin", "--events", "b.cbor", "--step", "10"]).unwrap();
+    assert_eq!(
+        parsed.events,
+        vec![PathBuf::from("a.bin"), PathBuf::from("b.cbor")]
+    );
+    assert_eq!(parsed.status, None);
+    assert_eq!(parsed.step, 10);
+}
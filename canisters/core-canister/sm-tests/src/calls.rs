@@ -1,3 +1,4 @@
+pub mod archive;
 pub mod core_canister;
 pub mod ledger;
 pub mod xrc_canister;
@@ -0,0 +1,54 @@
+use candid::{Decode, Encode, Principal};
+use core_canister::state::eventlog::GetEventsArg;
+use ic_base_types::PrincipalId;
+use ic_state_machine_tests::{CanisterId, StateMachine};
+
+pub fn send_append_events(
+    env: &StateMachine,
+    archive_id: CanisterId,
+    from: Principal,
+    first_index: u64,
+    entries: Vec<Vec<u8>>,
+) -> Result<u64, String> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            archive_id,
+            "append_events",
+            Encode!(&first_index, &entries).unwrap()
+        )
+        .expect("failed to append events")
+        .bytes(),
+        Result<u64, String>
+    )
+    .expect("failed to decode append_events response")
+}
+
+pub fn get_raw_events(
+    env: &StateMachine,
+    archive_id: CanisterId,
+    start: u64,
+    length: u64,
+) -> Vec<Vec<u8>> {
+    Decode!(
+        &env.query(
+            archive_id,
+            "get_raw_events",
+            Encode!(&GetEventsArg { start, length }).unwrap()
+        )
+        .expect("failed to query raw events")
+        .bytes(),
+        Vec<Vec<u8>>
+    )
+    .expect("failed to decode get_raw_events response")
+}
+
+pub fn get_event_count(env: &StateMachine, archive_id: CanisterId) -> u64 {
+    Decode!(
+        &env.query(archive_id, "get_event_count", Encode!().unwrap())
+            .expect("failed to query the event count")
+            .bytes(),
+        u64
+    )
+    .expect("failed to decode get_event_count response")
+}
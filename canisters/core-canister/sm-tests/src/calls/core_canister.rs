@@ -1,8 +1,12 @@
 use candid::{Decode, Encode, Principal};
-use core_canister::state::{ProtocolStatus, UserData};
+use core_canister::checks::AuditReport;
+use core_canister::state::{Asset, ProtocolStatus, UserData};
+use core_canister::storage::history::{GetUserHistoryArg, HistoryItem};
+use core_canister::updates::balance::BalanceError;
+use core_canister::updates::deposit::DepositMethod;
 use core_canister::updates::leverage::{LeveragePositionError, OpenLeveragePositionArg};
 use core_canister::updates::liquidity::LiquidityError;
-use core_canister::updates::swap::{SwapArg, SwapError};
+use core_canister::updates::swap::{SwapArg, SwapError, SwapQuote};
 use ic_base_types::PrincipalId;
 use ic_canisters_http_types::{HttpRequest, HttpResponse};
 use ic_state_machine_tests::{CanisterId, StateMachine};
@@ -114,13 +118,62 @@ pub fn send_remove_liquidity(
     .expect("failed to decode transfer response")
 }
 
+pub fn send_request_liquidity_withdrawal(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    amount: u64,
+) -> Result<u64, LiquidityError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "request_liquidity_withdrawal",
+            Encode!(&amount, &None::<Account>).unwrap()
+        )
+        .expect("failed to request a liquidity withdrawal")
+        .bytes(),
+        Result<u64, LiquidityError>
+    )
+    .expect("failed to decode request_liquidity_withdrawal response")
+}
+
+pub fn send_cancel_liquidity_withdrawal(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    id: u64,
+) -> Result<(), LiquidityError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "cancel_liquidity_withdrawal",
+            Encode!(&id).unwrap()
+        )
+        .expect("failed to cancel a liquidity withdrawal")
+        .bytes(),
+        Result<(), LiquidityError>
+    )
+    .expect("failed to decode cancel_liquidity_withdrawal response")
+}
+
 pub fn get_deposit_account(env: &StateMachine, coreid: CanisterId, from: Principal) -> Account {
+    get_deposit_account_with_nonce(env, coreid, from, None)
+}
+
+pub fn get_deposit_account_with_nonce(
+    env: &StateMachine,
+    coreid: CanisterId,
+    from: Principal,
+    nonce: Option<u64>,
+) -> Account {
     Decode!(
         &env.execute_ingress_as(
             PrincipalId(from),
             coreid,
             "get_deposit_account",
-            Encode!().unwrap()
+            Encode!(&nonce).unwrap()
         )
         .expect("failed to transfer funds")
         .bytes(),
@@ -139,6 +192,26 @@ pub fn get_protocol_status(env: &StateMachine, core_id: CanisterId) -> ProtocolS
     .expect("failed to decode get_protocol_status response")
 }
 
+pub fn get_swap_quote(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from_asset: Asset,
+    to_asset: Asset,
+    amount: u64,
+) -> Result<SwapQuote, SwapError> {
+    Decode!(
+        &env.query(
+            core_id,
+            "quote_swap",
+            Encode!(&from_asset, &to_asset, &amount).unwrap()
+        )
+        .expect("failed to query swap quote")
+        .bytes(),
+        Result<SwapQuote, SwapError>
+    )
+    .expect("failed to decode quote_swap response")
+}
+
 pub fn get_user_data(env: &StateMachine, core_id: CanisterId, target: &Principal) -> UserData {
     Decode!(
         &env.query(core_id, "get_user_data", Encode!(target).unwrap())
@@ -149,6 +222,30 @@ pub fn get_user_data(env: &StateMachine, core_id: CanisterId, target: &Principal
     .expect("failed to decode get_user_data response")
 }
 
+pub fn get_user_history(
+    env: &StateMachine,
+    core_id: CanisterId,
+    arg: &GetUserHistoryArg,
+) -> Vec<HistoryItem> {
+    Decode!(
+        &env.query(core_id, "get_user_history", Encode!(arg).unwrap())
+            .expect("failed to query user history")
+            .bytes(),
+        Vec<HistoryItem>
+    )
+    .expect("failed to decode get_user_history response")
+}
+
+pub fn run_audit(env: &StateMachine, core_id: CanisterId) -> AuditReport {
+    Decode!(
+        &env.query(core_id, "run_audit", Encode!().unwrap())
+            .expect("failed to run the audit")
+            .bytes(),
+        AuditReport
+    )
+    .expect("failed to decode run_audit response")
+}
+
 pub fn get_logs(env: &StateMachine, core_id: CanisterId) -> Vec<String> {
     Decode!(
         &env.query(core_id, "get_logs", Encode!().unwrap())
@@ -219,6 +316,110 @@ pub fn get_known_protocol_balance(env: &StateMachine, core_id: CanisterId) -> u6
     let core_rewards_amount = metrics
         .get(&"core_total_claimable_rewards".to_string())
         .unwrap();
+    let core_icp_internal_balances = metrics
+        .get(&"core_icp_internal_balances".to_string())
+        .unwrap();
+    let core_insurance_fund_amount = metrics
+        .get(&"core_insurance_fund_amount".to_string())
+        .unwrap();
+    let core_treasury_amount = metrics.get(&"core_treasury_amount".to_string()).unwrap();
+    let core_reserve_amount = metrics.get(&"core_reserve_amount".to_string()).unwrap();
+
+    collateral_amount
+        + core_liquidity_amount
+        + core_leverage_margin_amount
+        + core_rewards_amount
+        + core_icp_internal_balances
+        + core_insurance_fund_amount
+        + core_treasury_amount
+        + core_reserve_amount
+}
 
-    collateral_amount + core_liquidity_amount + core_leverage_margin_amount + core_rewards_amount
+pub fn send_deposit(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    asset: Asset,
+    amount: u64,
+    deposit_method: Option<DepositMethod>,
+) -> Result<u64, BalanceError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "deposit",
+            Encode!(&asset, &amount, &deposit_method)
+            .unwrap()
+        )
+        .expect("failed to deposit")
+        .bytes(),
+        Result<u64, BalanceError>
+    )
+    .expect("failed to decode deposit response")
+}
+
+pub fn send_withdraw(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    asset: Asset,
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, BalanceError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "withdraw",
+            Encode!(&asset, &amount, &to_account)
+            .unwrap()
+        )
+        .expect("failed to withdraw")
+        .bytes(),
+        Result<u64, BalanceError>
+    )
+    .expect("failed to decode withdraw response")
+}
+
+pub fn send_set_internal_balance_payouts(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    enabled: bool,
+) -> Result<(), BalanceError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "set_internal_balance_payouts",
+            Encode!(&enabled)
+            .unwrap()
+        )
+        .expect("failed to set internal balance payouts")
+        .bytes(),
+        Result<(), BalanceError>
+    )
+    .expect("failed to decode set_internal_balance_payouts response")
+}
+
+pub fn send_reclaim_deposit(
+    env: &StateMachine,
+    core_id: CanisterId,
+    from: Principal,
+    asset: Asset,
+    nonce: Option<u64>,
+) -> Result<u64, BalanceError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            core_id,
+            "reclaim_deposit",
+            Encode!(&asset, &nonce)
+            .unwrap()
+        )
+        .expect("failed to reclaim deposit")
+        .bytes(),
+        Result<u64, BalanceError>
+    )
+    .expect("failed to decode reclaim_deposit response")
 }
@@ -4,6 +4,7 @@ use ic_ledger_core::block::BlockIndex;
 use ic_state_machine_tests::{CanisterId, StateMachine};
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
 use num_traits::ToPrimitive;
 
 pub fn send_transfer(
@@ -28,6 +29,28 @@ pub fn send_transfer(
     .map(|n| n.0.to_u64().unwrap())
 }
 
+pub fn send_approve(
+    env: &StateMachine,
+    ledger: CanisterId,
+    from: Principal,
+    arg: &ApproveArgs,
+) -> Result<BlockIndex, ApproveError> {
+    Decode!(
+        &env.execute_ingress_as(
+            PrincipalId(from),
+            ledger,
+            "icrc2_approve",
+            Encode!(arg)
+            .unwrap()
+        )
+        .expect("failed to approve")
+        .bytes(),
+        Result<Nat, ApproveError>
+    )
+    .expect("failed to decode approve response")
+    .map(|n| n.0.to_u64().unwrap())
+}
+
 pub fn get_balance_of(env: &StateMachine, ledger: CanisterId, arg: &Account) -> Nat {
     Decode!(
         &env.query(ledger, "icrc1_balance_of", Encode!(arg).unwrap())
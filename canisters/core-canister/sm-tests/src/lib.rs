@@ -1,7 +1,8 @@
 use crate::calls::core_canister::{
-    get_deposit_account, get_known_protocol_balance, get_metrics, get_protocol_status,
-    get_user_data, send_add_liquidity, send_close_leverage, send_open_leverage,
-    send_remove_liquidity, send_swap,
+    get_deposit_account, get_deposit_account_with_nonce, get_known_protocol_balance, get_metrics,
+    get_protocol_status, get_user_data, send_add_liquidity, send_close_leverage, send_deposit,
+    send_open_leverage, send_reclaim_deposit, send_remove_liquidity,
+    send_set_internal_balance_payouts, send_swap, send_withdraw,
 };
 use crate::calls::{
     ledger::{get_balance_of, send_transfer},
@@ -11,18 +12,25 @@ use crate::setup::LedgerArgument;
 use assert_matches::assert_matches;
 use candid::{Encode, Principal};
 use core_canister::state::Asset;
+use core_canister::updates::balance::BalanceError;
+use core_canister::updates::deposit::DepositMethod;
 use core_canister::updates::leverage::{LeveragePositionError, OpenLeveragePositionArg};
 use core_canister::updates::liquidity::LiquidityError;
-use core_canister::updates::swap::SwapArg;
+use core_canister::updates::swap::{SwapArg, SwapError};
 use ic_base_types::CanisterId;
 use ic_base_types::PrincipalId;
 use ic_state_machine_tests::StateMachine;
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferArg;
+use num_traits::ToPrimitive;
 use std::time::Duration;
 
 pub mod calls;
 pub mod setup;
+pub mod test_archive;
+pub mod test_collateral;
+pub mod test_liquidity;
+pub mod test_settlement;
 pub mod test_swap;
 
 const ONE_THOUSAND_E8S: u64 = 10_000_000_000;
@@ -114,6 +122,8 @@ pub fn test_core(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
         from_asset: Asset::ICP,
         to_asset: Asset::EUSD,
         amount: TEN_E8S - ICP_TRANSFER_FEE,
+        to_account: None,
+        deposit_method: None,
     };
     let swap_result = send_swap(&env, canister_ids.core_id, users[0], &swap_arg);
     assert_matches!(swap_result, Ok(_));
@@ -154,6 +164,7 @@ pub fn test_core(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
             amount: TEN_E8S,
             take_profit: 1_500_000_000,
             covered_amount: TEN_E8S,
+            deposit_method: None,
         },
     );
     assert_matches!(
@@ -168,6 +179,7 @@ pub fn test_core(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
             amount: FIVE_E8S - ICP_TRANSFER_FEE,
             take_profit: 1_500_000_000,
             covered_amount: FIVE_E8S,
+            deposit_method: None,
         },
     );
     assert_matches!(open_leverage_result, Ok(_));
@@ -299,4 +311,183 @@ pub fn test_core(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
             + core_rewards_amount
             == protocol_balance
     );
+
+    // Deposit once, then swap from and get paid to the internal balance.
+    let deposit_account_user_3 = get_deposit_account(&env, canister_ids.core_id, users[3]);
+    let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: deposit_account_user_3,
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: TEN_E8S.into(),
+    };
+    let transfer_result = send_transfer(&env, canister_ids.icp_ledger_id, users[3], &transfer_arg);
+    assert_matches!(transfer_result, Ok(_));
+
+    let deposit_result = send_deposit(
+        &env,
+        canister_ids.core_id,
+        users[3],
+        Asset::ICP,
+        FIVE_E8S,
+        None,
+    );
+    assert_matches!(deposit_result, Ok(_));
+    let set_payouts_result =
+        send_set_internal_balance_payouts(&env, canister_ids.core_id, users[3], true);
+    assert_matches!(set_payouts_result, Ok(()));
+
+    let swap_arg = SwapArg {
+        from_asset: Asset::ICP,
+        to_asset: Asset::EUSD,
+        amount: ONE_E8S,
+        to_account: None,
+        deposit_method: Some(DepositMethod::InternalBalance),
+    };
+    let swap_result = send_swap(&env, canister_ids.core_id, users[3], &swap_arg);
+    assert_matches!(swap_result, Ok(_));
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+
+    let user3_data = get_user_data(&env, canister_ids.core_id, &users[3]);
+    assert_eq!(user3_data.icp_balance, FIVE_E8S - ONE_E8S);
+    assert!(user3_data.eusd_balance > 0);
+    assert!(user3_data.internal_balance_payouts);
+    dbg!("assert balances 5");
+    assert_balances_consistency(&env, canister_ids.core_id, canister_ids.icp_ledger_id);
+
+    let withdraw_result = send_withdraw(
+        &env,
+        canister_ids.core_id,
+        users[3],
+        Asset::EUSD,
+        user3_data.eusd_balance + 1,
+        None,
+    );
+    assert_eq!(
+        withdraw_result,
+        Err(BalanceError::InsufficientBalance(user3_data.eusd_balance))
+    );
+    let withdraw_result = send_withdraw(
+        &env,
+        canister_ids.core_id,
+        users[3],
+        Asset::EUSD,
+        user3_data.eusd_balance,
+        None,
+    );
+    assert_matches!(withdraw_result, Ok(_));
+    let eusd_balance = get_balance_of(
+        &env,
+        canister_ids.eusd_ledger_id,
+        &Account {
+            owner: users[3],
+            subaccount: None,
+        },
+    );
+    assert_eq!(eusd_balance, user3_data.eusd_balance);
+
+    let withdraw_result = send_withdraw(
+        &env,
+        canister_ids.core_id,
+        users[3],
+        Asset::ICP,
+        user3_data.icp_balance,
+        None,
+    );
+    assert_matches!(withdraw_result, Ok(_));
+    let user3_data = get_user_data(&env, canister_ids.core_id, &users[3]);
+    assert_eq!(user3_data.icp_balance, 0);
+    assert_eq!(user3_data.eusd_balance, 0);
+    dbg!("assert balances 6");
+    assert_balances_consistency(&env, canister_ids.core_id, canister_ids.icp_ledger_id);
+
+    // A deposit rejected by the swap can be reclaimed.
+    let deposit_account_user_4 = get_deposit_account(&env, canister_ids.core_id, users[4]);
+    let user4_account = Account {
+        owner: users[4],
+        subaccount: None,
+    };
+    let balance_before_deposit = get_balance_of(&env, canister_ids.icp_ledger_id, &user4_account)
+        .0
+        .to_u64()
+        .unwrap();
+    let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: deposit_account_user_4,
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: ONE_E8S.into(),
+    };
+    let transfer_result = send_transfer(&env, canister_ids.icp_ledger_id, users[4], &transfer_arg);
+    assert_matches!(transfer_result, Ok(_));
+    let swap_arg = SwapArg {
+        from_asset: Asset::ICP,
+        to_asset: Asset::EUSD,
+        amount: ONE_E8S / 2,
+        to_account: None,
+        deposit_method: None,
+    };
+    let swap_result = send_swap(&env, canister_ids.core_id, users[4], &swap_arg);
+    assert_matches!(swap_result, Err(SwapError::AmountTooSmall));
+
+    let reclaim_result =
+        send_reclaim_deposit(&env, canister_ids.core_id, users[4], Asset::ICP, None);
+    assert_matches!(reclaim_result, Ok(_));
+    let deposit_balance = get_balance_of(&env, canister_ids.icp_ledger_id, &deposit_account_user_4);
+    assert_eq!(deposit_balance, 0_u64);
+    let balance_after_reclaim = get_balance_of(&env, canister_ids.icp_ledger_id, &user4_account)
+        .0
+        .to_u64()
+        .unwrap();
+    assert_eq!(
+        balance_after_reclaim,
+        balance_before_deposit - 2 * ICP_TRANSFER_FEE
+    );
+    let reclaim_result =
+        send_reclaim_deposit(&env, canister_ids.core_id, users[4], Asset::ICP, None);
+    assert_eq!(reclaim_result, Err(BalanceError::AmountTooSmall));
+    dbg!("assert balances 7");
+    assert_balances_consistency(&env, canister_ids.core_id, canister_ids.icp_ledger_id);
+
+    // Each nonce gets its own deposit subaccount.
+    let deposit_account_user_5 =
+        get_deposit_account_with_nonce(&env, canister_ids.core_id, users[5], Some(1));
+    assert_ne!(
+        deposit_account_user_5,
+        get_deposit_account(&env, canister_ids.core_id, users[5])
+    );
+    let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: deposit_account_user_5,
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: TEN_E8S.into(),
+    };
+    let transfer_result = send_transfer(&env, canister_ids.icp_ledger_id, users[5], &transfer_arg);
+    assert_matches!(transfer_result, Ok(_));
+    let deposit_result = send_deposit(
+        &env,
+        canister_ids.core_id,
+        users[5],
+        Asset::ICP,
+        FIVE_E8S,
+        Some(DepositMethod::Subaccount(Some(1))),
+    );
+    assert_matches!(deposit_result, Ok(_));
+    let user5_data = get_user_data(&env, canister_ids.core_id, &users[5]);
+    assert_eq!(user5_data.icp_balance, FIVE_E8S);
+    let reclaim_result =
+        send_reclaim_deposit(&env, canister_ids.core_id, users[5], Asset::ICP, None);
+    assert_eq!(reclaim_result, Err(BalanceError::AmountTooSmall));
+    let reclaim_result =
+        send_reclaim_deposit(&env, canister_ids.core_id, users[5], Asset::ICP, Some(1));
+    assert_matches!(reclaim_result, Ok(_));
+    let deposit_balance = get_balance_of(&env, canister_ids.icp_ledger_id, &deposit_account_user_5);
+    assert_eq!(deposit_balance, 0_u64);
+    dbg!("assert balances 8");
+    assert_balances_consistency(&env, canister_ids.core_id, canister_ids.icp_ledger_id);
 }
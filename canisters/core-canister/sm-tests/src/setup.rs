@@ -1,4 +1,5 @@
 use candid::{CandidType, Encode, Principal};
+use core_canister::collateral::CollateralConfig;
 use core_canister::lifecycle::init::{CoreArgs, InitArgs as CoreInitArgs};
 use core_canister::state::Mode;
 use ic_base_types::PrincipalId;
@@ -58,6 +59,16 @@ pub struct CanisterPrincipals {
     pub icp_ledger_id: CanisterId,
     pub eusd_ledger_id: CanisterId,
     pub xrc_id: CanisterId,
+    // The ledgers of the collaterals, in setup order.
+    pub collateral_ledger_ids: Vec<CanisterId>,
+}
+
+/// A local ICRC-1 ledger standing in for a collateral such as ckBTC.
+pub struct TestCollateral {
+    pub symbol: String,
+    pub decimals: u32,
+    pub transfer_fee: u64,
+    pub initial_balances: Vec<(Account, u64)>,
 }
 
 pub fn setup(
@@ -66,6 +77,26 @@ pub fn setup(
     core_canister_wasm: Vec<u8>,
     initial_balances: Vec<(Account, u64)>,
     initial_icp_rate: u64,
+) -> (StateMachine, CanisterPrincipals) {
+    setup_with_collaterals(
+        xrc_wasm,
+        icrc1_ledger_wasm,
+        core_canister_wasm,
+        initial_balances,
+        initial_icp_rate,
+        vec![],
+    )
+}
+
+/// Same as [setup] with the given collaterals registered in the core canister.
+/// The XRC mock returns the same rate for every asset.
+pub fn setup_with_collaterals(
+    xrc_wasm: Vec<u8>,
+    icrc1_ledger_wasm: Vec<u8>,
+    core_canister_wasm: Vec<u8>,
+    initial_balances: Vec<(Account, u64)>,
+    initial_icp_rate: u64,
+    collaterals: Vec<TestCollateral>,
 ) -> (StateMachine, CanisterPrincipals) {
     let env = StateMachine::new();
 
@@ -97,12 +128,35 @@ pub fn setup(
         initial_balances,
     );
 
+    let mut collateral_ledger_ids = vec![];
+    let mut collateral_configs = vec![];
+    for collateral in collaterals {
+        let ledger_id = env.create_canister(None);
+        install_ledger(
+            &env,
+            icrc1_ledger_wasm.clone(),
+            ledger_id,
+            collateral.initial_balances,
+            collateral.transfer_fee,
+            &collateral.symbol,
+        );
+        collateral_configs.push(CollateralConfig {
+            symbol: collateral.symbol.clone(),
+            xrc_symbol: collateral.symbol,
+            ledger_principal: ledger_id.into(),
+            transfer_fee: collateral.transfer_fee,
+            decimals: collateral.decimals,
+        });
+        collateral_ledger_ids.push(ledger_id);
+    }
+
     let core_id = install_core_canister(
         &env,
         core_canister_wasm,
         Some(eusd_ledger_id.into()),
         Some(xrc_id.into()),
         Some(icp_ledger_id.into()),
+        Some(collateral_configs),
     );
 
     install_eusd_ledger(&env, icrc1_ledger_wasm, eusd_ledger_id, core_id);
@@ -112,6 +166,7 @@ pub fn setup(
         icp_ledger_id,
         eusd_ledger_id,
         xrc_id,
+        collateral_ledger_ids,
     };
     (env, cp)
 }
@@ -122,6 +177,7 @@ fn install_core_canister(
     eusd_ledger_principal: Option<Principal>,
     xrc_principal: Option<Principal>,
     icp_ledger_principal: Option<Principal>,
+    collaterals: Option<Vec<CollateralConfig>>,
 ) -> CanisterId {
     let init_args = CoreInitArgs {
         mode: Mode::NoHttpOutCalls,
@@ -132,6 +188,19 @@ fn install_core_canister(
         min_amount_from_stable: None,
         min_amount_leverage: None,
         min_amount_liquidity: None,
+        liquidity_haircut_curve: None,
+        collaterals,
+        debt_ceiling: None,
+        mint_cap_per_epoch: None,
+        mint_epoch_nanos: None,
+        min_collateral_ratio: None,
+        recovery_collateral_ratio: None,
+        recovery_fee_surcharge: None,
+        swap_fee_curves: None,
+        insurance_fund_fee_share: None,
+        treasury_fee_share: None,
+        reserve_fee_share: None,
+        archive_principal: None,
     };
     let core_args = CoreArgs::Init(init_args);
     let args = Encode!(&core_args).unwrap();
@@ -144,6 +213,24 @@ fn install_icp_ledger(
     icrc1_ledger_wasm: Vec<u8>,
     icp_ledger_id: CanisterId,
     initial_balances: Vec<(Account, u64)>,
+) {
+    install_ledger(
+        env,
+        icrc1_ledger_wasm,
+        icp_ledger_id,
+        initial_balances,
+        FEE,
+        "ICP",
+    );
+}
+
+fn install_ledger(
+    env: &StateMachine,
+    icrc1_ledger_wasm: Vec<u8>,
+    ledger_id: CanisterId,
+    initial_balances: Vec<(Account, u64)>,
+    transfer_fee: u64,
+    token_symbol: &str,
 ) {
     let init_args = InitArgs {
         minting_account: Account {
@@ -152,9 +239,9 @@ fn install_icp_ledger(
         },
         fee_collector_account: None,
         initial_balances,
-        transfer_fee: FEE,
-        token_name: "ICP".into(),
-        token_symbol: "ICP".into(),
+        transfer_fee,
+        token_name: token_symbol.into(),
+        token_symbol: token_symbol.into(),
         metadata: vec![],
         archive_options: ArchiveOptions {
             trigger_threshold: ARCHIVE_TRIGGER_THRESHOLD as usize,
@@ -169,7 +256,7 @@ fn install_icp_ledger(
     let ledger_arg = LedgerArgument::Init(init_args);
     let args = Encode!(&ledger_arg).unwrap();
     env.install_wasm_in_mode(
-        icp_ledger_id,
+        ledger_id,
         CanisterInstallMode::Install,
         icrc1_ledger_wasm,
         args,
@@ -0,0 +1,58 @@
+use crate::calls::archive::{get_event_count, get_raw_events, send_append_events};
+use assert_matches::assert_matches;
+use candid::Encode;
+use ic_base_types::PrincipalId;
+use ic_state_machine_tests::StateMachine;
+
+pub fn test_archive(archive_wasm: Vec<u8>) {
+    let env = StateMachine::new();
+    let users = crate::get_users(2);
+    // The archive only checks the caller, a user stands for the core canister.
+    let core_id = users[0];
+    let archive_id = env
+        .install_canister(archive_wasm, Encode!(&core_id).unwrap(), None)
+        .unwrap();
+    let entries: Vec<Vec<u8>> = (0..4u8).map(|k| vec![k; 8]).collect();
+
+    assert_matches!(
+        send_append_events(&env, archive_id, users[1], 0, entries[..2].to_vec()),
+        Err(_)
+    );
+    assert_matches!(
+        send_append_events(
+            &env,
+            archive_id,
+            PrincipalId::new_anonymous().0,
+            0,
+            entries[..2].to_vec()
+        ),
+        Err(_)
+    );
+    assert_eq!(get_event_count(&env, archive_id), 0);
+
+    assert_eq!(
+        send_append_events(&env, archive_id, core_id, 0, entries[..2].to_vec()),
+        Ok(2)
+    );
+    // A retry of a partially stored batch skips the stored entries.
+    assert_eq!(
+        send_append_events(&env, archive_id, core_id, 1, entries[1..3].to_vec()),
+        Ok(3)
+    );
+    // The entries must follow the stored ones.
+    assert_matches!(
+        send_append_events(&env, archive_id, core_id, 4, entries[3..].to_vec()),
+        Err(_)
+    );
+    assert_eq!(get_event_count(&env, archive_id), 3);
+
+    assert_eq!(
+        get_raw_events(&env, archive_id, 0, 10),
+        entries[..3].to_vec()
+    );
+    assert_eq!(
+        get_raw_events(&env, archive_id, 1, 1),
+        entries[1..2].to_vec()
+    );
+    assert!(get_raw_events(&env, archive_id, 3, 1).is_empty());
+}
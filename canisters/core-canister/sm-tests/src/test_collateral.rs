@@ -0,0 +1,249 @@
+use crate::calls::core_canister::{
+    get_deposit_account, get_protocol_status, get_swap_quote, send_deposit, send_reclaim_deposit,
+    send_swap,
+};
+use crate::calls::ledger::{get_balance_of, send_transfer};
+use crate::setup::TestCollateral;
+use crate::{ONE_E8S, TEN_E8S};
+use assert_matches::assert_matches;
+use core_canister::state::Asset;
+use core_canister::updates::balance::BalanceError;
+use core_canister::updates::swap::{SwapArg, SwapError};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use std::time::Duration;
+
+const CKBTC_FEE: u64 = 10;
+const CKUSDC_FEE: u64 = 10_000;
+const ONE_USDC: u64 = 1_000_000;
+
+fn swap_arg(from_asset: Asset, to_asset: Asset, amount: u64) -> SwapArg {
+    SwapArg {
+        from_asset,
+        to_asset,
+        amount,
+        to_account: None,
+        deposit_method: None,
+    }
+}
+
+pub fn test_multi_collateral(
+    core_canister_wasm: Vec<u8>,
+    xrc_wasm: Vec<u8>,
+    icrc1_ledger_wasm: Vec<u8>,
+) {
+    let users = crate::get_users(2);
+    let account = |owner| Account {
+        owner,
+        subaccount: None,
+    };
+    let ckbtc = Asset::Collateral("ckBTC".to_string());
+    let ckusdc = Asset::Collateral("ckUSDC".to_string());
+    let collaterals = vec![
+        TestCollateral {
+            symbol: "ckBTC".to_string(),
+            decimals: 8,
+            transfer_fee: CKBTC_FEE,
+            initial_balances: vec![(account(users[0]), TEN_E8S)],
+        },
+        TestCollateral {
+            symbol: "ckUSDC".to_string(),
+            decimals: 6,
+            transfer_fee: CKUSDC_FEE,
+            initial_balances: vec![(account(users[1]), 10 * ONE_USDC)],
+        },
+    ];
+    // Every asset is worth 5$ as the XRC mock returns a single rate.
+    let rate: u64 = 500_000_000;
+    let (env, canister_ids) = crate::setup::setup_with_collaterals(
+        xrc_wasm,
+        icrc1_ledger_wasm,
+        core_canister_wasm,
+        vec![],
+        rate,
+        collaterals,
+    );
+    let ckbtc_ledger_id = canister_ids.collateral_ledger_ids[0];
+    let ckusdc_ledger_id = canister_ids.collateral_ledger_ids[1];
+
+    env.advance_time(Duration::from_secs(60));
+    env.run_until_completion(1000);
+
+    let protocol_status = get_protocol_status(&env, canister_ids.core_id);
+    assert_eq!(protocol_status.collaterals.len(), 2);
+    assert_eq!(protocol_status.collaterals[0].price, rate);
+
+    // 1 ckBTC to eUSD.
+    let deposit_account_user_0 = get_deposit_account(&env, canister_ids.core_id, users[0]);
+    let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: deposit_account_user_0,
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        // The swap pays the ledger fee of the pull.
+        amount: (ONE_E8S + CKBTC_FEE).into(),
+    };
+    assert_matches!(
+        send_transfer(&env, ckbtc_ledger_id, users[0], &transfer_arg),
+        Ok(_)
+    );
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[0],
+        &swap_arg(ckbtc.clone(), Asset::EUSD, ONE_E8S),
+    );
+    assert_matches!(swap_result, Ok(_));
+
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+
+    // 0.25% fee: (1 - 0.0025) * 5 eUSD.
+    assert_eq!(
+        get_balance_of(&env, canister_ids.eusd_ledger_id, &account(users[0])),
+        498_750_000
+    );
+
+    // 2 ckUSDC to eUSD.
+    let deposit_account_user_1 = get_deposit_account(&env, canister_ids.core_id, users[1]);
+    let transfer_arg = TransferArg {
+        amount: (2 * ONE_USDC + CKUSDC_FEE).into(),
+        to: deposit_account_user_1,
+        ..transfer_arg
+    };
+    assert_matches!(
+        send_transfer(&env, ckusdc_ledger_id, users[1], &transfer_arg),
+        Ok(_)
+    );
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[1],
+        &swap_arg(ckusdc.clone(), Asset::EUSD, 2 * ONE_USDC),
+    );
+    assert_matches!(swap_result, Ok(_));
+
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+
+    assert_eq!(
+        get_balance_of(&env, canister_ids.eusd_ledger_id, &account(users[1])),
+        997_500_000
+    );
+
+    // The collateral ratio accounts for both collaterals, fees included.
+    let protocol_status = get_protocol_status(&env, canister_ids.core_id);
+    assert_eq!(protocol_status.collaterals[0].amount, ONE_E8S);
+    assert_eq!(protocol_status.collaterals[1].amount, 2 * ONE_USDC);
+    assert_eq!(protocol_status.tvl, 1_500_000_000);
+    assert!(protocol_status.collateral_ratio > ONE_E8S);
+
+    // eUSD back to ckBTC.
+    let quote = get_swap_quote(
+        &env,
+        canister_ids.core_id,
+        Asset::EUSD,
+        ckbtc.clone(),
+        400_000_000,
+    )
+    .unwrap();
+    assert_eq!(quote.fee_rate, 250_000);
+    assert_eq!(quote.fee, 1_000_000);
+    assert_eq!(quote.amount_out, 79_800_000);
+    let transfer_arg = TransferArg {
+        amount: 400_000_000_u64.into(),
+        to: deposit_account_user_0,
+        ..transfer_arg
+    };
+    assert_matches!(
+        send_transfer(&env, canister_ids.eusd_ledger_id, users[0], &transfer_arg),
+        Ok(_)
+    );
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[0],
+        &swap_arg(Asset::EUSD, ckbtc.clone(), 400_000_000),
+    );
+    assert_matches!(swap_result, Ok(_));
+
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+
+    // (4 - 0.01) eUSD / 5$ minus the ledger fee.
+    assert_eq!(
+        get_balance_of(&env, ckbtc_ledger_id, &account(users[0])),
+        TEN_E8S - (ONE_E8S + CKBTC_FEE) - CKBTC_FEE + 79_800_000 - CKBTC_FEE
+    );
+    let protocol_status = get_protocol_status(&env, canister_ids.core_id);
+    assert_eq!(protocol_status.collaterals[0].amount, ONE_E8S - 79_800_000);
+
+    // The ckBTC bucket cannot cover the swap.
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[1],
+        &swap_arg(Asset::EUSD, ckbtc.clone(), 500_000_000),
+    );
+    assert_eq!(
+        swap_result,
+        Err(SwapError::InsufficientCollateral(ONE_E8S - 79_800_000))
+    );
+
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[0],
+        &swap_arg(
+            Asset::Collateral("ckDOGE".to_string()),
+            Asset::EUSD,
+            ONE_E8S,
+        ),
+    );
+    assert_eq!(
+        swap_result,
+        Err(SwapError::UnknownCollateral("ckDOGE".to_string()))
+    );
+    let swap_result = send_swap(
+        &env,
+        canister_ids.core_id,
+        users[0],
+        &swap_arg(Asset::ICP, ckbtc.clone(), ONE_E8S),
+    );
+    assert_eq!(swap_result, Err(SwapError::UnsupportedSwap));
+
+    // Collaterals cannot be held in internal balances.
+    let deposit_result = send_deposit(
+        &env,
+        canister_ids.core_id,
+        users[0],
+        ckbtc.clone(),
+        ONE_E8S,
+        None,
+    );
+    assert_eq!(deposit_result, Err(BalanceError::UnsupportedAsset));
+
+    // Collateral left in a deposit account can be reclaimed.
+    let transfer_arg = TransferArg {
+        amount: ONE_E8S.into(),
+        to: deposit_account_user_0,
+        ..transfer_arg
+    };
+    assert_matches!(
+        send_transfer(&env, ckbtc_ledger_id, users[0], &transfer_arg),
+        Ok(_)
+    );
+    let balance_before = get_balance_of(&env, ckbtc_ledger_id, &account(users[0]));
+    let reclaim_result = send_reclaim_deposit(&env, canister_ids.core_id, users[0], ckbtc, None);
+    assert_matches!(reclaim_result, Ok(_));
+    assert_eq!(
+        get_balance_of(&env, ckbtc_ledger_id, &account(users[0])),
+        balance_before + ONE_E8S - CKBTC_FEE
+    );
+
+    assert_eq!(
+        crate::calls::core_canister::self_check(&env, canister_ids.core_id),
+        Ok(())
+    );
+}
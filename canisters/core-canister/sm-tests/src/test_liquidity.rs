@@ -0,0 +1,168 @@
+use crate::calls::core_canister::{
+    get_protocol_status, get_user_data, send_add_liquidity, send_cancel_liquidity_withdrawal,
+    send_request_liquidity_withdrawal, send_swap,
+};
+use crate::calls::{
+    ledger::{get_balance_of, send_transfer},
+    xrc_canister::{assert_xrc_is_running, upgrade_icp_price},
+};
+use crate::{FIVE_E8S, ICP_TRANSFER_FEE, ONE_E8S, TEN_E8S};
+use assert_matches::assert_matches;
+use core_canister::state::Asset;
+use core_canister::updates::liquidity::LiquidityError;
+use core_canister::updates::swap::SwapArg;
+use ic_base_types::PrincipalId;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use num_traits::ToPrimitive;
+use std::time::Duration;
+
+pub fn test_liquidity_withdrawals(
+    core_canister_wasm: Vec<u8>,
+    xrc_wasm: Vec<u8>,
+    icrc1_ledger_wasm: Vec<u8>,
+) {
+    let mut initial_balances: Vec<(Account, u64)> = vec![];
+    let number_of_users = 2;
+    let users = crate::get_users(number_of_users);
+    let icp_minter = PrincipalId::new(0, [0u8; 29]).0;
+    for user in &users {
+        initial_balances.push((
+            Account {
+                owner: *user,
+                subaccount: None,
+            },
+            crate::ONE_THOUSAND_E8S,
+        ));
+    }
+    let initial_icp_rate: u64 = 1_000_000_000; // 10$
+    let (env, canister_ids) = crate::setup::setup(
+        xrc_wasm.clone(),
+        icrc1_ledger_wasm,
+        core_canister_wasm,
+        initial_balances,
+        initial_icp_rate,
+    );
+    let users_deposit_accounts =
+        crate::get_user_deposit_account(&env, number_of_users, canister_ids.core_id, users.clone());
+
+    env.advance_time(Duration::from_secs(60));
+    env.run_until_completion(1000);
+    assert_xrc_is_running(&env, canister_ids.xrc_id, icp_minter);
+
+    for (user, deposit_account) in users.iter().zip(users_deposit_accounts.iter()) {
+        let transfer_arg = TransferArg {
+            from_subaccount: None,
+            to: *deposit_account,
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: TEN_E8S.into(),
+        };
+        let transfer_result = send_transfer(&env, canister_ids.icp_ledger_id, *user, &transfer_arg);
+        assert_matches!(transfer_result, Ok(_));
+    }
+
+    let add_liquidity_result = send_add_liquidity(&env, canister_ids.core_id, users[1], &FIVE_E8S);
+    assert_matches!(add_liquidity_result, Ok(_));
+    let liquidity_provided =
+        get_user_data(&env, canister_ids.core_id, &users[1]).liquidity_provided;
+
+    let swap_arg = SwapArg {
+        from_asset: Asset::ICP,
+        to_asset: Asset::EUSD,
+        amount: TEN_E8S - ICP_TRANSFER_FEE,
+        to_account: None,
+        deposit_method: None,
+    };
+    let swap_result = send_swap(&env, canister_ids.core_id, users[0], &swap_arg);
+    assert_matches!(swap_result, Ok(_));
+
+    // The liquidity counts in the collateral, a 7$ price brings the
+    // collateral ratio to about 105% which puts the withdrawals behind
+    // a haircut.
+    upgrade_icp_price(&env, canister_ids.xrc_id, xrc_wasm.clone(), 700_000_000);
+    env.advance_time(Duration::from_secs(60 * 5));
+    env.run_until_completion(1000);
+    let protocol_status = get_protocol_status(&env, canister_ids.core_id);
+    assert_eq!(protocol_status.icp_price, 700_000_000);
+    assert!(protocol_status.collateral_ratio < 120_000_000);
+
+    let withdrawal_result =
+        send_request_liquidity_withdrawal(&env, canister_ids.core_id, users[1], 1);
+    assert_matches!(withdrawal_result, Err(LiquidityError::AmountTooSmall));
+    let withdrawal_result = send_request_liquidity_withdrawal(
+        &env,
+        canister_ids.core_id,
+        users[1],
+        liquidity_provided + 1,
+    );
+    assert_matches!(
+        withdrawal_result,
+        Err(LiquidityError::NotEnoughLiquidity(balance)) if balance == liquidity_provided
+    );
+
+    let cancelled_id =
+        send_request_liquidity_withdrawal(&env, canister_ids.core_id, users[1], ONE_E8S)
+            .expect("failed to request a liquidity withdrawal");
+    let served_id =
+        send_request_liquidity_withdrawal(&env, canister_ids.core_id, users[1], ONE_E8S)
+            .expect("failed to request a liquidity withdrawal");
+    // The queued withdrawals reserve the liquidity.
+    let withdrawal_result =
+        send_request_liquidity_withdrawal(&env, canister_ids.core_id, users[1], liquidity_provided);
+    assert_matches!(
+        withdrawal_result,
+        Err(LiquidityError::NotEnoughLiquidity(balance))
+            if balance == liquidity_provided - 2 * ONE_E8S
+    );
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+    let user1_data = get_user_data(&env, canister_ids.core_id, &users[1]);
+    assert_eq!(user1_data.pending_liquidity_withdrawals.len(), 2);
+    assert_eq!(user1_data.liquidity_provided, liquidity_provided);
+
+    assert_matches!(
+        send_cancel_liquidity_withdrawal(&env, canister_ids.core_id, users[0], cancelled_id),
+        Err(LiquidityError::CallerNotOwner)
+    );
+    assert_matches!(
+        send_cancel_liquidity_withdrawal(&env, canister_ids.core_id, users[1], cancelled_id),
+        Ok(())
+    );
+    assert_matches!(
+        send_cancel_liquidity_withdrawal(&env, canister_ids.core_id, users[1], cancelled_id),
+        Err(LiquidityError::WithdrawalNotFound)
+    );
+
+    let user1_account = Account {
+        owner: users[1],
+        subaccount: None,
+    };
+    let balance_before_withdrawal =
+        get_balance_of(&env, canister_ids.icp_ledger_id, &user1_account)
+            .0
+            .to_u64()
+            .unwrap();
+
+    // The collateral ratio of about 300% serves the queued withdrawal in full.
+    upgrade_icp_price(&env, canister_ids.xrc_id, xrc_wasm, 2 * initial_icp_rate);
+    env.advance_time(Duration::from_secs(60 * 5));
+    env.run_until_completion(1000);
+
+    let user1_data = get_user_data(&env, canister_ids.core_id, &users[1]);
+    assert!(user1_data.pending_liquidity_withdrawals.is_empty());
+    assert_eq!(user1_data.liquidity_provided, liquidity_provided - ONE_E8S);
+    let balance_after_withdrawal = get_balance_of(&env, canister_ids.icp_ledger_id, &user1_account)
+        .0
+        .to_u64()
+        .unwrap();
+    assert!(balance_after_withdrawal > balance_before_withdrawal);
+    assert!(balance_after_withdrawal < balance_before_withdrawal + ONE_E8S);
+    assert_matches!(
+        send_cancel_liquidity_withdrawal(&env, canister_ids.core_id, users[1], served_id),
+        Err(LiquidityError::WithdrawalNotFound)
+    );
+
+    crate::assert_balances_consistency(&env, canister_ids.core_id, canister_ids.icp_ledger_id);
+}
@@ -0,0 +1,229 @@
+use crate::calls::core_canister::{
+    get_deposit_account, get_protocol_status, get_user_data, self_check, send_add_liquidity,
+    send_open_leverage, send_remove_liquidity, send_swap,
+};
+use crate::calls::ledger::{get_balance_of, send_transfer};
+use crate::{FIVE_E8S, ICP_TRANSFER_FEE, ONE_E8S, ONE_THOUSAND_E8S, TEN_E8S};
+use assert_matches::assert_matches;
+use candid::Encode;
+use core_canister::lifecycle::init::CoreArgs;
+use core_canister::lifecycle::upgrade::UpgradeArgs;
+use core_canister::state::Asset;
+use core_canister::updates::leverage::{LeveragePositionError, OpenLeveragePositionArg};
+use core_canister::updates::liquidity::LiquidityError;
+use core_canister::updates::swap::{SwapArg, SwapError};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use std::time::Duration;
+
+pub fn test_global_settlement(
+    core_canister_wasm: Vec<u8>,
+    xrc_wasm: Vec<u8>,
+    icrc1_ledger_wasm: Vec<u8>,
+) {
+    let users = crate::get_users(3);
+    let account = |owner| Account {
+        owner,
+        subaccount: None,
+    };
+    let initial_balances = users
+        .iter()
+        .map(|user| (account(*user), ONE_THOUSAND_E8S))
+        .collect();
+    let icp_rate: u64 = 1_000_000_000;
+    let (env, canister_ids) = crate::setup::setup(
+        xrc_wasm,
+        icrc1_ledger_wasm,
+        core_canister_wasm.clone(),
+        initial_balances,
+        icp_rate,
+    );
+
+    env.advance_time(Duration::from_secs(60));
+    env.run_until_completion(1000);
+
+    let deposit = |user, amount: u64| {
+        let transfer_arg = TransferArg {
+            from_subaccount: None,
+            to: get_deposit_account(&env, canister_ids.core_id, user),
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: amount.into(),
+        };
+        assert_matches!(
+            send_transfer(&env, canister_ids.icp_ledger_id, user, &transfer_arg),
+            Ok(_)
+        );
+    };
+    let swap_arg = |from_asset, to_asset, amount| SwapArg {
+        from_asset,
+        to_asset,
+        amount,
+        to_account: None,
+        deposit_method: None,
+    };
+
+    // user 0 mints eUSD, user 1 opens a position and user 2 provides liquidity.
+    deposit(users[0], TEN_E8S);
+    assert_matches!(
+        send_swap(
+            &env,
+            canister_ids.core_id,
+            users[0],
+            &swap_arg(Asset::ICP, Asset::EUSD, TEN_E8S - ICP_TRANSFER_FEE),
+        ),
+        Ok(_)
+    );
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+    let eusd_balance = get_balance_of(&env, canister_ids.eusd_ledger_id, &account(users[0]));
+    assert_eq!(eusd_balance, 9_974_900_250);
+
+    deposit(users[1], FIVE_E8S);
+    assert_matches!(
+        send_open_leverage(
+            &env,
+            canister_ids.core_id,
+            users[1],
+            &OpenLeveragePositionArg {
+                amount: FIVE_E8S - ICP_TRANSFER_FEE,
+                take_profit: 1_500_000_000,
+                covered_amount: FIVE_E8S,
+                deposit_method: None,
+            },
+        ),
+        Ok(_)
+    );
+
+    deposit(users[2], TEN_E8S);
+    assert_matches!(
+        send_add_liquidity(&env, canister_ids.core_id, users[2], &FIVE_E8S),
+        Ok(_)
+    );
+
+    let icp_balance_user_1 = get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[1]));
+
+    // Only the controllers can upgrade the canister to start the settlement.
+    let upgrade_args = CoreArgs::Upgrade(Some(UpgradeArgs {
+        start_global_settlement: Some(true),
+        ..Default::default()
+    }));
+    env.upgrade_canister(
+        canister_ids.core_id,
+        core_canister_wasm.clone(),
+        Encode!(&upgrade_args).unwrap(),
+    )
+    .expect("failed to upgrade the core canister");
+
+    let settlement = get_protocol_status(&env, canister_ids.core_id)
+        .global_settlement
+        .expect("the global settlement did not start");
+    assert_eq!(settlement.icp_price.rate, icp_rate);
+
+    // Minting and new positions are refused.
+    deposit(users[0], ONE_E8S);
+    assert_eq!(
+        send_swap(
+            &env,
+            canister_ids.core_id,
+            users[0],
+            &swap_arg(Asset::ICP, Asset::EUSD, ONE_E8S - ICP_TRANSFER_FEE),
+        ),
+        Err(SwapError::GlobalSettlement)
+    );
+    assert_matches!(
+        send_open_leverage(
+            &env,
+            canister_ids.core_id,
+            users[0],
+            &OpenLeveragePositionArg {
+                amount: ONE_E8S - ICP_TRANSFER_FEE,
+                take_profit: 1_500_000_000,
+                covered_amount: ONE_E8S,
+                deposit_method: None,
+            },
+        ),
+        Err(LeveragePositionError::GlobalSettlement)
+    );
+    assert_matches!(
+        send_add_liquidity(&env, canister_ids.core_id, users[0], &ONE_E8S),
+        Err(LiquidityError::GlobalSettlement)
+    );
+
+    // The position is closed at the frozen price, then the redemptions open.
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+    env.tick();
+    env.tick();
+
+    let user_data = get_user_data(&env, canister_ids.core_id, &users[1]);
+    assert_eq!(user_data.leverage_positions.unwrap_or_default(), vec![]);
+    // (5 - 0.0001) * 0.9975 ICP minus the ledger fee.
+    assert_eq!(
+        get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[1])),
+        icp_balance_user_1 + 498_740_025 - ICP_TRANSFER_FEE
+    );
+    let settlement = get_protocol_status(&env, canister_ids.core_id)
+        .global_settlement
+        .unwrap();
+    // The collateral exactly covers the eUSD at the frozen price.
+    assert_eq!(settlement.redemption_rate, Some(icp_rate));
+
+    // 10 eUSD redeemed for 1 ICP, without fee.
+    let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: get_deposit_account(&env, canister_ids.core_id, users[0]),
+        fee: None,
+        created_at_time: None,
+        memo: None,
+        amount: TEN_E8S.into(),
+    };
+    assert_matches!(
+        send_transfer(&env, canister_ids.eusd_ledger_id, users[0], &transfer_arg),
+        Ok(_)
+    );
+    let icp_balance_user_0 = get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[0]));
+    assert_matches!(
+        send_swap(
+            &env,
+            canister_ids.core_id,
+            users[0],
+            &swap_arg(Asset::EUSD, Asset::ICP, TEN_E8S),
+        ),
+        Ok(_)
+    );
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+    assert_eq!(
+        get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[0])),
+        icp_balance_user_0 + ONE_E8S
+    );
+    // Liquidity is withdrawn without haircut nor fee.
+    let liquidity_provided =
+        get_user_data(&env, canister_ids.core_id, &users[2]).liquidity_provided;
+    let icp_balance_user_2 = get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[2]));
+    assert_matches!(
+        send_remove_liquidity(&env, canister_ids.core_id, users[2], &liquidity_provided),
+        Ok(_)
+    );
+    assert_eq!(
+        get_balance_of(&env, canister_ids.icp_ledger_id, &account(users[2])),
+        icp_balance_user_2 + liquidity_provided - ICP_TRANSFER_FEE
+    );
+
+    assert_eq!(self_check(&env, canister_ids.core_id), Ok(()));
+
+    // The settlement is replayed from the event log.
+    let status_before_upgrade = get_protocol_status(&env, canister_ids.core_id);
+    env.upgrade_canister(
+        canister_ids.core_id,
+        core_canister_wasm,
+        Encode!(&CoreArgs::Upgrade(None)).unwrap(),
+    )
+    .expect("failed to upgrade the core canister");
+    assert_eq!(
+        get_protocol_status(&env, canister_ids.core_id),
+        status_before_upgrade
+    );
+}
@@ -1,15 +1,19 @@
-use crate::calls::core_canister::send_swap;
+use crate::calls::core_canister::{get_user_history, run_audit, send_swap};
 use crate::calls::{
-    ledger::{get_balance_of, send_transfer},
+    ledger::{get_balance_of, send_approve, send_transfer},
     xrc_canister::{assert_xrc_is_running, upgrade_icp_price},
 };
 use crate::{ONE_E8S, TEN_E8S};
 use assert_matches::assert_matches;
+use core_canister::checks::AuditCheck;
 use core_canister::state::Asset;
+use core_canister::storage::history::{GetUserHistoryArg, HistoryKind};
+use core_canister::updates::deposit::DepositMethod;
 use core_canister::updates::swap::SwapArg;
 use ic_base_types::PrincipalId;
 use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc2::approve::ApproveArgs;
 use num_traits::ToPrimitive;
 use std::time::Duration;
 
@@ -59,6 +63,8 @@ pub fn test_swap(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
         from_asset: Asset::ICP,
         to_asset: Asset::EUSD,
         amount: ONE_E8S,
+        to_account: None,
+        deposit_method: None,
     };
     let swap_result = send_swap(&env, canister_ids.core_id, users[0], &swap_arg);
     assert_matches!(swap_result, Ok(_));
@@ -84,6 +90,8 @@ pub fn test_swap(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
         from_asset: Asset::ICP,
         to_asset: Asset::EUSD,
         amount: ONE_E8S,
+        to_account: None,
+        deposit_method: None,
     };
     let swap_result = send_swap(&env, canister_ids.core_id, users[0], &swap_arg);
     assert_matches!(swap_result, Ok(_));
@@ -102,6 +110,61 @@ pub fn test_swap(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
     let balance_second_swap = 997_500_000;
     assert_eq!(balance_of_result, balance_first_swap + balance_second_swap);
 
+    let history = get_user_history(
+        &env,
+        canister_ids.core_id,
+        &GetUserHistoryArg {
+            owner: users[0],
+            start: 0,
+            length: 10,
+            kinds: Some(vec![HistoryKind::Swap]),
+        },
+    );
+    assert_eq!(history.len(), 2);
+    assert!(history.iter().all(|item| item.kind == HistoryKind::Swap));
+
+    // Swap with funds pulled from the default account through an ICRC-2 allowance.
+    let approve_arg = ApproveArgs {
+        from_subaccount: None,
+        spender: Account {
+            owner: canister_ids.core_id.into(),
+            subaccount: None,
+        },
+        amount: (ONE_E8S + core_canister::ICP_TRANSFER_FEE).into(),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let approve_result = send_approve(&env, canister_ids.icp_ledger_id, users[0], &approve_arg);
+    assert_matches!(approve_result, Ok(_));
+    let icrc2_swap_arg = SwapArg {
+        from_asset: Asset::ICP,
+        to_asset: Asset::EUSD,
+        amount: ONE_E8S,
+        to_account: None,
+        deposit_method: Some(DepositMethod::Icrc2),
+    };
+    let swap_result = send_swap(&env, canister_ids.core_id, users[0], &icrc2_swap_arg);
+    assert_matches!(swap_result, Ok(_));
+
+    env.advance_time(Duration::from_secs(60));
+    env.tick();
+
+    let balance_of_result = get_balance_of(
+        &env,
+        canister_ids.eusd_ledger_id,
+        &Account {
+            owner: users[0],
+            subaccount: None,
+        },
+    );
+    assert_eq!(
+        balance_of_result,
+        balance_first_swap + 2 * balance_second_swap
+    );
+
     upgrade_icp_price(&env, canister_ids.xrc_id, xrc_wasm, initial_icp_rate);
 
     for k in 1..10 {
@@ -179,6 +242,8 @@ pub fn test_swap(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
             from_asset: Asset::EUSD,
             to_asset: Asset::ICP,
             amount: amount_to_swap,
+            to_account: None,
+            deposit_method: None,
         };
         let swap_result = send_swap(&env, canister_ids.core_id, users[k], &swap_arg);
         assert_matches!(swap_result, Ok(_));
@@ -214,4 +279,9 @@ pub fn test_swap(core_canister_wasm: Vec<u8>, xrc_wasm: Vec<u8>, icrc1_ledger_wa
             amount_minted
         );
     }
+
+    let report = run_audit(&env, canister_ids.core_id);
+    assert_eq!(report.invariants, AuditCheck::Passed);
+    assert_eq!(report.event_chain, AuditCheck::Passed);
+    assert_matches!(report.replay, AuditCheck::Passed | AuditCheck::Skipped(_));
 }
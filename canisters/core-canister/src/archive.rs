@@ -0,0 +1,95 @@
+//! Moves the old events to the archive canister, keeping only the events
+//! recorded after the latest snapshot in the core canister.
+
+use crate::logs::P0;
+use crate::state::read_state;
+use crate::storage::{
+    archive_id, drop_archived_events, first_event_index, latest_snapshot, read_raw_events,
+    SNAPSHOT_INTERVAL_EVENTS,
+};
+use ic_canister_log::log;
+
+/// The events are archived once the latest snapshot covers that many
+/// events of the log.
+pub const ARCHIVE_TRIGGER_EVENTS: u64 = 2 * SNAPSHOT_INTERVAL_EVENTS;
+
+/// The number of events sent to the archive per call.
+const ARCHIVE_BATCH_EVENTS: u64 = 1_000;
+
+/// Sends the events covered by the latest snapshot to the archive, then
+/// drops them from the log once the archive stores all of them.
+pub async fn archive_events() {
+    let archive_principal = match read_state(|s| s.archive_principal) {
+        Some(archive_principal) => archive_principal,
+        None => return,
+    };
+    if let Some(archive_id) = archive_id() {
+        if archive_id != archive_principal {
+            log!(
+                P0,
+                "[archive_events]: the events are archived by {}, not {}",
+                archive_id,
+                archive_principal
+            );
+            return;
+        }
+    }
+
+    let first_index = first_event_index();
+    // The replay starts from the latest snapshot, the events
+    // it covers are no longer needed.
+    let end = match latest_snapshot() {
+        Some(snapshot) => snapshot.event_count,
+        None => return,
+    };
+    if end < first_index + ARCHIVE_TRIGGER_EVENTS {
+        return;
+    }
+
+    let mut archived = first_index;
+    while archived < end {
+        let entries = read_raw_events(archived, ARCHIVE_BATCH_EVENTS.min(end - archived));
+        let sent = entries.len() as u64;
+        let result: Result<(Result<u64, String>,), _> =
+            ic_cdk::call(archive_principal, "append_events", (archived, entries)).await;
+        match result {
+            Ok((Ok(archive_length),)) if archive_length >= archived + sent => {
+                archived = archive_length.min(end);
+            }
+            Ok((Ok(archive_length),)) => {
+                log!(
+                    P0,
+                    "[archive_events]: the archive stores {} events, expected at least {}",
+                    archive_length,
+                    archived + sent
+                );
+                return;
+            }
+            Ok((Err(e),)) => {
+                log!(
+                    P0,
+                    "[archive_events]: the archive rejected the events: {}",
+                    e
+                );
+                return;
+            }
+            Err((code, msg)) => {
+                log!(
+                    P0,
+                    "[archive_events]: failed to call the archive: {:?} {}",
+                    code,
+                    msg
+                );
+                return;
+            }
+        }
+    }
+
+    drop_archived_events(end, archive_principal);
+    log!(
+        P0,
+        "[archive_events]: archived the events in [{}, {})",
+        first_index,
+        end
+    );
+}
@@ -0,0 +1,244 @@
+//! Audits the state of the canister, reporting the violations instead of
+//! trapping so that the checks can run in production.
+//!
+//! Only the ICP ledger is reconciled with the state, the eUSD supply and
+//! the collateral ledgers are not.
+
+use crate::logs::P0;
+use crate::state::eventlog::{replay, replay_from_snapshot, without_replay_logs};
+use crate::state::CoreState;
+use crate::storage::{
+    check_event_chain, count_events, events, events_from, first_event_index,
+    last_snapshot_event_count, latest_snapshot,
+};
+use candid::CandidType;
+use ic_canister_log::log;
+use serde::Deserialize;
+use std::cell::Cell;
+
+/// The audit skips the replays of more events, which would not fit in
+/// the instruction limit of a query.
+pub const MAX_AUDIT_REPLAY_EVENTS: u64 = 20_000;
+
+thread_local! {
+    static AUDIT_VIOLATION_COUNT: Cell<u64> = Cell::default();
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub enum AuditCheck {
+    Passed,
+    Failed(String),
+    /// The check could not run.
+    Skipped(String),
+}
+
+impl From<Result<(), String>> for AuditCheck {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self::Passed,
+            Err(e) => Self::Failed(e),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct AuditReport {
+    pub timestamp: u64,
+    /// The number of events, archived ones included.
+    pub event_count: u64,
+    /// The invariants of the current state.
+    pub invariants: AuditCheck,
+    /// The hash chain of the events recorded since the latest snapshot.
+    pub event_chain: AuditCheck,
+    /// The state replayed from the latest snapshot and, if the log is not
+    /// archived, from the first event matches the current state.
+    pub replay: AuditCheck,
+    /// The ICP tracked by the state e8s.
+    pub known_icp_balance: u64,
+    /// The last ICP balance of the protocol read from the ledger e8s.
+    pub ledger_icp_balance: u64,
+    /// The ledger holds at least the ICP tracked by the state. The eUSD
+    /// supply and the collateral ledgers are not reconciled.
+    pub balance: AuditCheck,
+}
+
+impl AuditReport {
+    /// Returns the failures of the checks.
+    pub fn violations(&self) -> Vec<&str> {
+        [
+            &self.invariants,
+            &self.event_chain,
+            &self.replay,
+            &self.balance,
+        ]
+        .into_iter()
+        .filter_map(|check| match check {
+            AuditCheck::Failed(e) => Some(e.as_str()),
+            _ => None,
+        })
+        .collect()
+    }
+}
+
+/// Returns the number of violations found by the periodic audits since
+/// the last upgrade.
+pub fn audit_violation_count() -> u64 {
+    AUDIT_VIOLATION_COUNT.with(|count| count.get())
+}
+
+/// Replays the log from the latest snapshot and from the first event,
+/// skipping the replays of more than [MAX_AUDIT_REPLAY_EVENTS] events.
+fn replay_bounded() -> Result<Option<CoreState>, String> {
+    let event_count = count_events();
+    let snapshot = latest_snapshot();
+    if first_event_index() > 0 && snapshot.is_none() {
+        return Err("the archived events require a snapshot".to_string());
+    }
+
+    let restored_state = match snapshot {
+        Some(snapshot) if event_count - snapshot.event_count <= MAX_AUDIT_REPLAY_EVENTS => Some(
+            replay_from_snapshot(snapshot.state, events_from(snapshot.event_count))
+                .map_err(|e| format!("failed to replay the log from the snapshot: {:?}", e))?,
+        ),
+        _ => None,
+    };
+
+    // The archived events can only be replayed from a snapshot.
+    if first_event_index() > 0 || event_count > MAX_AUDIT_REPLAY_EVENTS {
+        return Ok(restored_state);
+    }
+    let recovered_state =
+        replay(events()).map_err(|e| format!("failed to replay the log: {:?}", e))?;
+    if let Some(restored_state) = &restored_state {
+        restored_state
+            .check_semantically_eq(&recovered_state)
+            .map_err(|e| format!("the snapshot replay differs from the full replay: {}", e))?;
+    }
+    Ok(Some(recovered_state))
+}
+
+fn check_replay(state: &CoreState) -> AuditCheck {
+    let replayed_state = match without_replay_logs(replay_bounded) {
+        Ok(Some(replayed_state)) => replayed_state,
+        Ok(None) => {
+            return AuditCheck::Skipped(format!(
+                "the replay exceeds {} events",
+                MAX_AUDIT_REPLAY_EVENTS
+            ))
+        }
+        Err(e) => return AuditCheck::Failed(e),
+    };
+    if let Err(e) = replayed_state.check_invariants() {
+        return AuditCheck::Failed(format!("the replayed state violates an invariant: {}", e));
+    }
+    state
+        .check_semantically_eq(&replayed_state)
+        .map_err(|e| format!("the replayed state differs from the state: {}", e))
+        .into()
+}
+
+/// Checks the chain from the checkpoint before the latest snapshot, the
+/// events it covers were checked by the previous audits.
+fn check_chain() -> AuditCheck {
+    let start = last_snapshot_event_count();
+    if count_events() - start > MAX_AUDIT_REPLAY_EVENTS {
+        return AuditCheck::Skipped(format!(
+            "the chain exceeds {} events",
+            MAX_AUDIT_REPLAY_EVENTS
+        ));
+    }
+    check_event_chain(start).into()
+}
+
+fn check_balance(state: &CoreState) -> AuditCheck {
+    let known_balance = state.get_known_icp_balance();
+    if state.protocol_balance == 0 {
+        AuditCheck::Skipped("the ledger balance was not fetched yet".to_string())
+    } else if known_balance > state.protocol_balance {
+        AuditCheck::Failed(format!(
+            "the state tracks {} ICP e8s, the ledger holds {}",
+            known_balance, state.protocol_balance
+        ))
+    } else {
+        AuditCheck::Passed
+    }
+}
+
+/// Checks the invariants of `state`, the event log and the ICP balance of
+/// the protocol.
+pub fn run_audit(state: &CoreState, now: u64) -> AuditReport {
+    AuditReport {
+        timestamp: now,
+        event_count: count_events(),
+        invariants: state.check_invariants().into(),
+        event_chain: check_chain(),
+        replay: check_replay(state),
+        known_icp_balance: state.get_known_icp_balance(),
+        ledger_icp_balance: state.protocol_balance,
+        balance: check_balance(state),
+    }
+}
+
+/// Runs the audit from the timer, counting and logging the violations.
+pub fn record_audit(state: &CoreState, now: u64) {
+    let report = run_audit(state, now);
+    let violations = report.violations();
+    AUDIT_VIOLATION_COUNT.with(|count| count.set(count.get() + violations.len() as u64));
+    for violation in violations {
+        log!(P0, "[audit]: {}", violation);
+    }
+}
+
+#[test]
+fn test_run_audit() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::state::eventlog::Event;
+    use crate::storage::record_event;
+    use crate::updates::liquidity::{Liquidity, LiquidityType};
+    use candid::Principal;
+
+    record_event(&Event::Init(default_init_args()));
+    record_event(&Event::Liquidity(Liquidity {
+        caller: Principal::from_slice(&[1]),
+        operation_type: LiquidityType::Add,
+        amount: 100_000_000,
+        block_index: 0,
+        timestamp: 0,
+        fee: 0,
+        to_account: None,
+    }));
+    let mut state = replay(events()).unwrap();
+
+    let logged = ic_canister_log::export(&crate::logs::P0).len();
+    let report = run_audit(&state, 0);
+    assert_eq!(ic_canister_log::export(&crate::logs::P0).len(), logged);
+    assert_eq!(report.event_count, 2);
+    assert!(report.violations().is_empty(), "{:?}", report);
+    assert_eq!(report.replay, AuditCheck::Passed);
+    assert!(matches!(report.balance, AuditCheck::Skipped(_)));
+
+    // The guards of the requests in flight are transient, they are not
+    // compared, the rest of the state still is.
+    state
+        .leverage_principals_lock
+        .insert(Principal::from_slice(&[2]));
+    state.is_timer_running = true;
+    assert_eq!(run_audit(&state, 0).replay, AuditCheck::Passed);
+    let mut replayed_state = replay(events()).unwrap();
+    replayed_state
+        .block_index_to_owner
+        .insert(0, Principal::from_slice(&[2]));
+    assert!(state.check_semantically_eq(&replayed_state).is_err());
+    state.release_guards();
+
+    state.protocol_balance = 50_000_000;
+    state.icp_liqudity_amount += 1;
+    let report = run_audit(&state, 0);
+    assert!(matches!(report.invariants, AuditCheck::Failed(_)));
+    assert!(matches!(report.replay, AuditCheck::Failed(_)));
+    assert!(matches!(report.balance, AuditCheck::Failed(_)));
+    assert_eq!(report.violations().len(), 3);
+
+    record_audit(&state, 0);
+    assert_eq!(audit_violation_count(), 3);
+}
@@ -0,0 +1,110 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The maximum number of decimals of a collateral token. The amounts are
+/// u64 like the ICP e8s ones, with 18 decimals they would overflow past
+/// about 18.4 tokens.
+const MAX_DECIMALS: u32 = 8;
+
+/// An ICRC-1 token accepted as collateral besides ICP, e.g. ckBTC or ckUSDC.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CollateralConfig {
+    /// The symbol identifying the collateral in `Asset::Collateral`.
+    pub symbol: String,
+    /// The symbol of the base asset queried to the XRC canister, e.g. "BTC".
+    pub xrc_symbol: String,
+    pub ledger_principal: Principal,
+    pub transfer_fee: u64,
+    pub decimals: u32,
+}
+
+/// Checks that `collaterals` can be registered.
+pub fn validate_collaterals(collaterals: &[CollateralConfig]) -> Result<(), String> {
+    let mut symbols = BTreeSet::new();
+    for collateral in collaterals {
+        if collateral.symbol.is_empty() || collateral.xrc_symbol.is_empty() {
+            return Err("collateral symbols must not be empty".to_string());
+        }
+        if collateral.symbol == "ICP" || collateral.symbol == "EUSD" {
+            return Err(format!(
+                "{} cannot be registered as a collateral",
+                collateral.symbol
+            ));
+        }
+        if collateral.decimals > MAX_DECIMALS {
+            return Err(format!(
+                "collateral {} has more than {} decimals",
+                collateral.symbol, MAX_DECIMALS
+            ));
+        }
+        if !symbols.insert(&collateral.symbol) {
+            return Err(format!("collateral {} is duplicated", collateral.symbol));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the eUSD value (e8s) of `amount` tokens with `decimals` at the e8s `rate`,
+/// saturating at `u64::MAX`.
+pub fn to_value_e8s(amount: u64, rate: u64, decimals: u32) -> u64 {
+    saturate(amount as u128 * rate as u128 / 10_u128.pow(decimals))
+}
+
+/// Returns the amount of tokens with `decimals` worth `value` eUSD (e8s) at the e8s `rate`,
+/// saturating at `u64::MAX`.
+pub fn from_value_e8s(value: u64, rate: u64, decimals: u32) -> u64 {
+    saturate(value as u128 * 10_u128.pow(decimals) / rate as u128)
+}
+
+fn saturate(amount: u128) -> u64 {
+    u64::try_from(amount).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(symbol: &str, decimals: u32) -> CollateralConfig {
+        CollateralConfig {
+            symbol: symbol.to_string(),
+            xrc_symbol: symbol.to_string(),
+            ledger_principal: Principal::anonymous(),
+            transfer_fee: 10,
+            decimals,
+        }
+    }
+
+    #[test]
+    fn test_value_conversions() {
+        // 0.5 ckBTC at 30_000 $.
+        assert_eq!(
+            to_value_e8s(50_000_000, 3_000_000_000_000, 8),
+            1_500_000_000_000
+        );
+        assert_eq!(
+            from_value_e8s(1_500_000_000_000, 3_000_000_000_000, 8),
+            50_000_000
+        );
+        // 2 ckUSDC at 1 $.
+        assert_eq!(to_value_e8s(2_000_000, 100_000_000, 6), 200_000_000);
+        assert_eq!(from_value_e8s(200_000_000, 100_000_000, 6), 2_000_000);
+        // The values past u64 saturate instead of wrapping.
+        assert_eq!(to_value_e8s(u64::MAX, 2 * crate::E8S, 8), u64::MAX);
+        assert_eq!(from_value_e8s(u64::MAX, 1, 8), u64::MAX);
+        // ICP amounts match the e8s helpers.
+        assert_eq!(
+            to_value_e8s(150_000_001, 520_000_000, 8),
+            crate::multiply_e8s(150_000_001, 520_000_000)
+        );
+    }
+
+    #[test]
+    fn test_validate_collaterals() {
+        assert!(validate_collaterals(&[config("ckBTC", 8), config("ckUSDC", 6)]).is_ok());
+        assert!(validate_collaterals(&[config("ckBTC", 8), config("ckBTC", 8)]).is_err());
+        assert!(validate_collaterals(&[config("ICP", 8)]).is_err());
+        assert!(validate_collaterals(&[config("", 8)]).is_err());
+        assert!(validate_collaterals(&[config("ckETH", 18)]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// A point of a [PiecewiseLinearCurve], both coordinates are e8s.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub x: u64,
+    pub y: u64,
+}
+
+/// A function defined by linear interpolation between points sorted by `x`.
+/// The curve is flat before its first point and after its last point.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PiecewiseLinearCurve {
+    pub points: Vec<CurvePoint>,
+}
+
+impl PiecewiseLinearCurve {
+    pub fn new(points: Vec<(u64, u64)>) -> Self {
+        Self {
+            points: points
+                .into_iter()
+                .map(|(x, y)| CurvePoint { x, y })
+                .collect(),
+        }
+    }
+
+    /// Checks that the curve has at least one point and
+    /// that the points are sorted by strictly increasing `x`.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.points.is_empty() {
+            return Err("the curve has no point".to_string());
+        }
+        for window in self.points.windows(2) {
+            if window[0].x >= window[1].x {
+                return Err(format!(
+                    "the curve points are not sorted by strictly increasing x: {} >= {}",
+                    window[0].x, window[1].x
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates the curve at `x`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the curve has no point.
+    pub fn evaluate(&self, x: u64) -> u64 {
+        let first = self.points.first().expect("bug: empty curve");
+        let last = self.points.last().expect("bug: empty curve");
+        if x <= first.x {
+            return first.y;
+        }
+        if x >= last.x {
+            return last.y;
+        }
+        let end = self
+            .points
+            .iter()
+            .position(|p| p.x >= x)
+            .expect("bug: x is within the curve bounds");
+        let (p0, p1) = (self.points[end - 1], self.points[end]);
+        let dx = (x - p0.x) as i128;
+        let width = (p1.x - p0.x) as i128;
+        let height = p1.y as i128 - p0.y as i128;
+        (p0.y as i128 + height * dx / width) as u64
+    }
+}
+
+#[test]
+fn test_curve_validation() {
+    assert!(PiecewiseLinearCurve::new(vec![]).validate().is_err());
+    assert!(PiecewiseLinearCurve::new(vec![(1, 0), (1, 1)])
+        .validate()
+        .is_err());
+    assert!(PiecewiseLinearCurve::new(vec![(2, 0), (1, 1)])
+        .validate()
+        .is_err());
+    assert!(PiecewiseLinearCurve::new(vec![(0, 5)]).validate().is_ok());
+    assert!(PiecewiseLinearCurve::new(vec![(0, 5), (10, 0)])
+        .validate()
+        .is_ok());
+}
+
+#[test]
+fn test_curve_evaluation() {
+    let curve = PiecewiseLinearCurve::new(vec![(100, 0), (200, 1_000), (300, 500)]);
+    assert_eq!(curve.evaluate(0), 0);
+    assert_eq!(curve.evaluate(100), 0);
+    assert_eq!(curve.evaluate(150), 500);
+    assert_eq!(curve.evaluate(200), 1_000);
+    assert_eq!(curve.evaluate(250), 750);
+    assert_eq!(curve.evaluate(300), 500);
+    assert_eq!(curve.evaluate(u64::MAX), 500);
+
+    let flat = PiecewiseLinearCurve::new(vec![(42, 7)]);
+    assert_eq!(flat.evaluate(0), 7);
+    assert_eq!(flat.evaluate(u64::MAX), 7);
+}
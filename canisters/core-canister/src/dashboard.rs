@@ -1,5 +1,8 @@
+use crate::state::Asset;
 use crate::tasks::get_task_vec;
+use crate::updates::swap::SwapDirection;
 use crate::{read_state, E8S_FLOAT};
+use std::collections::BTreeSet;
 use std::io::Write;
 
 pub fn build_dashboard() -> Vec<u8> {
@@ -32,6 +35,21 @@ pub fn build_dashboard() -> Vec<u8> {
                 <h3>Metadata</h3>
                 {}
             </div>
+            <div>
+                <h3>Collaterals</h3>
+                <table>
+                    <thead>
+                        <tr>
+                            <th>Symbol</th>
+                            <th>Ledger</th>
+                            <th>Amount</th>
+                            <th>Price</th>
+                            <th>Value (eUSD)</th>
+                        </tr>
+                    </thead>
+                    <tbody>{}</tbody>
+                </table>
+            </div>
             <div>
                 <h3>Liquidity Table</h3>
                 <table>
@@ -39,6 +57,7 @@ pub fn build_dashboard() -> Vec<u8> {
                         <tr>
                             <th>Owner</th>
                             <th>Amount</th>
+                            <th>Auto Compound</th>
                         </tr>
                     </thead>
                     <tbody>{}</tbody>
@@ -56,6 +75,34 @@ pub fn build_dashboard() -> Vec<u8> {
                     <tbody>{}</tbody>
                 </table>
             </div>
+            <div>
+                <h3>Internal Balances</h3>
+                <table>
+                    <thead>
+                        <tr>
+                            <th>Owner</th>
+                            <th>ICP</th>
+                            <th>eUSD</th>
+                            <th>Payouts</th>
+                        </tr>
+                    </thead>
+                    <tbody>{}</tbody>
+                </table>
+            </div>
+            <div>
+                <h3>Liquidity Withdrawal Queue</h3>
+                <table>
+                    <thead>
+                        <tr>
+                            <th>Id</th>
+                            <th>Owner</th>
+                            <th>Amount</th>
+                            <th>Timestamp</th>
+                        </tr>
+                    </thead>
+                    <tbody>{}</tbody>
+                </table>
+            </div>
             <div>
                 <h3>Leverage Table</h3>
                 <table>
@@ -118,8 +165,11 @@ pub fn build_dashboard() -> Vec<u8> {
     </html>
     ",
         construct_metadata_table(),
+        construct_collaterals_table(),
         construct_liquidity_table(),
         construct_liquidity_rewards(),
+        construct_internal_balances(),
+        construct_withdrawal_queue(),
         construct_leverage_table(),
         construct_convert_table(),
         construct_task_queue(),
@@ -156,14 +206,84 @@ fn construct_metadata_table() -> String {
                         <th>xrc principal</th>
                         <td>{}</td>
                     </tr>
-                    
+                    <tr>
+                        <th>Debt ceiling (eUSD)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Mint cap per epoch (eUSD)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Remaining mint capacity (eUSD)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Regime</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Global settlement</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Insurance fund (ICP)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Bad debt (ICP)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Treasury (ICP)</th>
+                        <td>{}</td>
+                    </tr>
+                    <tr>
+                        <th>Reserve (ICP)</th>
+                        <td>{}</td>
+                    </tr>
                 </tbody>
             </table>",
-            s.mode, s.eusd_ledger_principal, s.icp_ledger_principal, s.xrc_principal,
+            s.mode,
+            s.eusd_ledger_principal,
+            s.icp_ledger_principal,
+            s.xrc_principal,
+            s.debt_ceiling as f64 / E8S_FLOAT,
+            s.mint_cap_per_epoch as f64 / E8S_FLOAT,
+            s.get_remaining_mint_capacity(ic_cdk::api::time()) as f64 / E8S_FLOAT,
+            s.get_regime(),
+            s.global_settlement
+                .as_ref()
+                .map(|settlement| settlement.to_string())
+                .unwrap_or_else(|| "None".to_string()),
+            s.insurance_fund_amount as f64 / E8S_FLOAT,
+            s.bad_debt as f64 / E8S_FLOAT,
+            s.treasury_amount as f64 / E8S_FLOAT,
+            s.reserve_amount as f64 / E8S_FLOAT,
         )
     })
 }
 
+fn construct_collaterals_table() -> String {
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            for status in s.get_collaterals_status() {
+                let decimals = s.get_decimals(&Asset::Collateral(status.symbol.clone()));
+                write!(
+                    buf,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    status.symbol,
+                    status.ledger_principal,
+                    status.amount as f64 / 10_f64.powi(decimals as i32),
+                    status.price as f64 / E8S_FLOAT,
+                    status.value as f64 / E8S_FLOAT
+                )
+                .unwrap();
+            }
+        })
+    })
+}
+
 fn construct_liquidity_table() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
@@ -174,9 +294,12 @@ fn construct_liquidity_table() -> String {
                 <tr>
                     <td>{}</td>
                     <td>{}</td>
+                    <td>{}</td>
                 </tr>
                 ",
-                    principal, amount
+                    principal,
+                    amount,
+                    s.liquidity_auto_compound.contains(principal)
                 )
                 .unwrap();
             }
@@ -233,6 +356,51 @@ fn construct_liquidity_rewards() -> String {
     })
 }
 
+fn construct_internal_balances() -> String {
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            let owners: BTreeSet<_> = s
+                .icp_balances
+                .keys()
+                .chain(s.eusd_balances.keys())
+                .collect();
+            for owner in owners {
+                write!(
+                    buf,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    owner,
+                    s.get_balance(owner, &Asset::ICP) as f64 / E8S_FLOAT,
+                    s.get_balance(owner, &Asset::EUSD) as f64 / E8S_FLOAT,
+                    if s.internal_balance_payouts.contains(owner) {
+                        "internal"
+                    } else {
+                        "ledger"
+                    }
+                )
+                .unwrap();
+            }
+        })
+    })
+}
+
+fn construct_withdrawal_queue() -> String {
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            for (id, withdrawal) in s.liquidity_withdrawal_queue.iter() {
+                write!(
+                    buf,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    id,
+                    withdrawal.owner,
+                    withdrawal.amount as f64 / E8S_FLOAT,
+                    withdrawal.timestamp
+                )
+                .unwrap();
+            }
+        })
+    })
+}
+
 fn construct_convert_table() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
@@ -260,10 +428,37 @@ fn construct_fee_table() -> String {
                 "<tr><td>Base Fee</td><td>{}%</td></tr>
                 <tr><td>Liquidation Fee</td><td>{}%</td></tr>
                 <tr><td>Stability Fee</td><td>{}%</td></tr>
+                <tr><td>Recovery Fee Surcharge</td><td>{}%</td></tr>
+                <tr><td>Insurance Fund Fee Share</td><td>{}%</td></tr>
+                <tr><td>Treasury Fee Share</td><td>{}%</td></tr>
+                <tr><td>Reserve Fee Share</td><td>{}%</td></tr>
+                <tr><td>Current Mint Fee Rate</td><td>{}%</td></tr>
+                <tr><td>Current Redeem Fee Rate</td><td>{}%</td></tr>
+                <tr><td>Minimum Collateral Ratio</td><td>{}%</td></tr>
+                <tr><td>Recovery Collateral Ratio</td><td>{}%</td></tr>
+                <tr><td>Liquidity Haircut Curve</td><td>{}</td></tr>
                 ",
                 s.fees.base_fee as f64 / 100_000_000.0,
                 s.fees.liquidation_fee as f64 / 100_000_000.0,
-                s.fees.stability_fee as f64 / 100_000_000.0
+                s.fees.stability_fee as f64 / 100_000_000.0,
+                s.recovery_fee_surcharge as f64 / 100_000_000.0,
+                s.insurance_fund_fee_share as f64 / 1_000_000.0,
+                s.treasury_fee_share as f64 / 1_000_000.0,
+                s.reserve_fee_share as f64 / 1_000_000.0,
+                s.get_swap_fee_rate(SwapDirection::Mint) as f64 / 1_000_000.0,
+                s.get_swap_fee_rate(SwapDirection::Redeem) as f64 / 1_000_000.0,
+                s.min_collateral_ratio as f64 / 1_000_000.0,
+                s.recovery_collateral_ratio as f64 / 1_000_000.0,
+                s.liquidity_haircut_curve
+                    .points
+                    .iter()
+                    .map(|p| format!(
+                        "CR {}% &rarr; {}%",
+                        p.x as f64 / 1_000_000.0,
+                        p.y as f64 / 1_000_000.0
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
             )
             .unwrap();
         })
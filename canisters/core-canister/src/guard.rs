@@ -29,6 +29,27 @@ impl Drop for TimerLogicGuard {
     }
 }
 
+/// Reserves eUSD of the mint capacity for the swap of `principal` until
+/// it is dropped, the swap is recorded by then.
+#[must_use]
+pub struct MintReservation {
+    principal: Principal,
+}
+
+impl MintReservation {
+    pub fn new(state: &mut CoreState, principal: Principal, eusd_amount: u64) -> Self {
+        let previous = state.mint_reservations.insert(principal, eusd_amount);
+        assert_eq!(previous, None, "bug: {} already reserved a mint", principal);
+        Self { principal }
+    }
+}
+
+impl Drop for MintReservation {
+    fn drop(&mut self) {
+        mutate_state(|s| s.mint_reservations.remove(&self.principal));
+    }
+}
+
 /// Guards a block from executing twice when called by the same user and from being
 /// executed [MAX_CONCURRENT] or more times in parallel.
 #[must_use]
@@ -80,6 +101,10 @@ pub struct PendingLeverageUpdates;
 
 pub struct PendingConvertUpdates;
 
+pub struct PendingBalanceUpdates;
+
+pub struct PendingTreasuryUpdates;
+
 impl PendingRequests for PendingLiquidityUpdates {
     fn pending_requests(state: &mut CoreState) -> &mut BTreeSet<Principal> {
         &mut state.liquidity_principals_lock
@@ -98,6 +123,18 @@ impl PendingRequests for PendingConvertUpdates {
     }
 }
 
+impl PendingRequests for PendingBalanceUpdates {
+    fn pending_requests(state: &mut CoreState) -> &mut BTreeSet<Principal> {
+        &mut state.balance_principals_lock
+    }
+}
+
+impl PendingRequests for PendingTreasuryUpdates {
+    fn pending_requests(state: &mut CoreState) -> &mut BTreeSet<Principal> {
+        &mut state.treasury_principals_lock
+    }
+}
+
 pub fn leverage_update_guard(p: Principal) -> Result<Guard<PendingLeverageUpdates>, GuardError> {
     Guard::new(p)
 }
@@ -109,3 +146,11 @@ pub fn liquidity_update_guard(p: Principal) -> Result<Guard<PendingLiquidityUpda
 pub fn convert_update_guard(p: Principal) -> Result<Guard<PendingConvertUpdates>, GuardError> {
     Guard::new(p)
 }
+
+pub fn balance_update_guard(p: Principal) -> Result<Guard<PendingBalanceUpdates>, GuardError> {
+    Guard::new(p)
+}
+
+pub fn treasury_update_guard(p: Principal) -> Result<Guard<PendingTreasuryUpdates>, GuardError> {
+    Guard::new(p)
+}
@@ -1,20 +1,25 @@
 use crate::logs::P1;
-use crate::management::transfer_icp;
 use crate::state::audit::record_swap_success;
 use crate::state::mutate_state;
 use crate::state::read_state;
 use crate::state::Asset;
 use crate::tasks::{schedule_now, TaskType};
+use crate::updates::balance::{pay_collateral, pay_eusd, pay_icp};
 use ic_base_types::PrincipalId;
 use ic_canister_log::log;
 use ic_crypto_sha::Sha256;
 
+pub mod archive;
+pub mod checks;
+pub mod collateral;
+pub mod curve;
 pub mod dashboard;
 pub mod guard;
 pub mod lifecycle;
 pub mod logs;
 pub mod management;
 pub mod metrics;
+pub mod price;
 pub mod state;
 pub mod storage;
 pub mod tasks;
@@ -59,60 +64,36 @@ pub fn divide_e8s(amount: u64, divisor: u64) -> u64 {
 pub async fn process_pending_swaps() {
     let open_swaps = mutate_state(|s| s.open_swaps.clone());
     for (_index, swap) in open_swaps {
-        match swap.from {
-            Asset::ICP => {
-                let amount_to_swap = swap.from_amount - swap.fee;
-                let eusd_to_mint = crate::multiply_e8s(amount_to_swap, swap.rate);
-                match crate::management::mint_eusd(eusd_to_mint, swap.caller).await {
-                    Ok(block_index) => {
-                        log!(
-                            P1,
-                            "[swap]: Success swap from {} {:?} to {:?} ",
-                            swap.from_amount,
-                            swap.from,
-                            swap.to,
-                        );
-                        mutate_state(|s| {
-                            record_swap_success(s, swap.from_block_index, block_index);
-                        });
-                    }
-                    Err(e) => {
-                        log!(
-                            P1,
-                            "[swap]: failed to swap from {:?} to {:?}, error: {:?}",
-                            swap.from,
-                            swap.to,
-                            e
-                        );
-                    }
-                }
+        let amount_out = read_state(|s| s.get_swap_output_amount(&swap));
+        let result = match &swap.to {
+            Asset::EUSD => pay_eusd(swap.caller, swap.to_account, amount_out).await,
+            Asset::ICP => pay_icp(swap.caller, swap.to_account, amount_out).await,
+            Asset::Collateral(symbol) => {
+                pay_collateral(symbol, swap.caller, swap.to_account, amount_out).await
             }
-            Asset::EUSD => {
-                let amount_to_swap = swap.from_amount - swap.fee;
-                let icp_to_transfer = divide_e8s(amount_to_swap, swap.rate);
-                match crate::management::transfer_icp(None, swap.caller, icp_to_transfer).await {
-                    Ok(block_index) => {
-                        log!(
-                            P1,
-                            "[swap]: Success swap from {} {:?} to {} {:?} ",
-                            swap.from_amount,
-                            swap.from,
-                            icp_to_transfer,
-                            swap.to,
-                        );
-                        mutate_state(|s| {
-                            record_swap_success(s, swap.from_block_index, block_index);
-                        });
-                    }
-                    Err(_) => {
-                        log!(
-                            P1,
-                            "[swap]: failed to swap from {:?} to {:?}",
-                            swap.from,
-                            swap.to
-                        );
-                    }
-                }
+        };
+        match result {
+            Ok(block_index) => {
+                log!(
+                    P1,
+                    "[swap]: Success swap from {} {:?} to {} {:?} ",
+                    swap.from_amount,
+                    swap.from,
+                    amount_out,
+                    swap.to,
+                );
+                mutate_state(|s| {
+                    record_swap_success(s, swap.from.clone(), swap.from_block_index, block_index);
+                });
+            }
+            Err(e) => {
+                log!(
+                    P1,
+                    "[swap]: failed to swap from {:?} to {:?}, error: {:?}",
+                    swap.from,
+                    swap.to,
+                    e
+                );
             }
         }
     }
@@ -1,6 +1,9 @@
+use crate::collateral::CollateralConfig;
+use crate::curve::PiecewiseLinearCurve;
 use crate::lifecycle::upgrade::UpgradeArgs;
 use crate::state::Mode;
 use crate::state::{replace_state, CoreState};
+use crate::updates::swap::SwapFeeCurves;
 use candid::Principal;
 use candid::{CandidType, Deserialize};
 use serde::Serialize;
@@ -11,7 +14,7 @@ pub enum CoreArgs {
     Upgrade(Option<UpgradeArgs>),
 }
 
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct InitArgs {
     pub mode: Mode,
     pub eusd_ledger_principal: Option<Principal>,
@@ -22,8 +25,66 @@ pub struct InitArgs {
     pub min_amount_from_stable: Option<u64>,
     pub min_amount_leverage: Option<u64>,
     pub min_amount_liquidity: Option<u64>,
+
+    /// Maps the collateral ratio to the fraction of liquidity paid out on removal.
+    pub liquidity_haircut_curve: Option<PiecewiseLinearCurve>,
+
+    /// Collaterals accepted besides ICP.
+    pub collaterals: Option<Vec<CollateralConfig>>,
+
+    /// The maximum amount of outstanding eUSD.
+    pub debt_ceiling: Option<u64>,
+    /// The maximum amount of eUSD minted over a rolling epoch.
+    pub mint_cap_per_epoch: Option<u64>,
+    pub mint_epoch_nanos: Option<u64>,
+
+    /// The collateral ratio (e8s) below which minting eUSD is refused.
+    pub min_collateral_ratio: Option<u64>,
+    /// The collateral ratio (e8s) below which the protocol enters recovery mode.
+    pub recovery_collateral_ratio: Option<u64>,
+    /// The fee rate (e8s) added to the swap fee in recovery mode.
+    pub recovery_fee_surcharge: Option<u64>,
+
+    /// The curves setting the fee rate of swaps.
+    pub swap_fee_curves: Option<SwapFeeCurves>,
+
+    /// The fraction (e8s) of the protocol fees paid into the insurance fund.
+    pub insurance_fund_fee_share: Option<u64>,
+    /// The fraction (e8s) of the protocol fees paid into the treasury.
+    pub treasury_fee_share: Option<u64>,
+    /// The fraction (e8s) of the protocol fees kept as a reserve buffer.
+    pub reserve_fee_share: Option<u64>,
+
+    /// The canister storing the archived events.
+    pub archive_principal: Option<Principal>,
+}
+
+/// The arguments leaving every setting to its default, to build test
+/// fixtures from.
+#[cfg(test)]
+pub fn default_init_args() -> InitArgs {
+    InitArgs::default()
+}
+
+impl InitArgs {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(curve) = &self.liquidity_haircut_curve {
+            crate::updates::liquidity::validate_haircut_curve(curve)?;
+        }
+        if let Some(collaterals) = &self.collaterals {
+            crate::collateral::validate_collaterals(collaterals)?;
+        }
+        if let Some(curves) = &self.swap_fee_curves {
+            crate::updates::swap::validate_swap_fee_curves(curves)?;
+        }
+        Ok(())
+    }
 }
 
 pub fn init(args: InitArgs) {
-    replace_state(CoreState::from(args));
+    let state = CoreState::from(args);
+    if let Err(e) = state.check_settings() {
+        ic_cdk::trap(&format!("[init]: invalid init args: {}", e));
+    }
+    replace_state(state);
 }
@@ -1,34 +1,130 @@
+use crate::collateral::CollateralConfig;
+use crate::curve::PiecewiseLinearCurve;
 use crate::logs::P0;
-use crate::state::eventlog::replay;
 use crate::state::eventlog::Event;
+use crate::state::eventlog::{replay, replay_from_snapshot};
 use crate::state::replace_state;
 use crate::storage::count_events;
-use crate::storage::events;
 use crate::storage::record_event;
-use candid::{CandidType, Deserialize};
+use crate::storage::{archive_id, events, events_from, first_event_index, latest_snapshot};
+use crate::updates::swap::SwapFeeCurves;
+use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
 use serde::Serialize;
 
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct UpgradeArgs {}
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UpgradeArgs {
+    /// Maps the collateral ratio to the fraction of liquidity paid out on removal.
+    pub liquidity_haircut_curve: Option<PiecewiseLinearCurve>,
+
+    /// Collaterals accepted besides ICP.
+    pub collaterals: Option<Vec<CollateralConfig>>,
+
+    /// The maximum amount of outstanding eUSD.
+    pub debt_ceiling: Option<u64>,
+    /// The maximum amount of eUSD minted over a rolling epoch.
+    pub mint_cap_per_epoch: Option<u64>,
+    pub mint_epoch_nanos: Option<u64>,
+
+    /// The collateral ratio (e8s) below which minting eUSD is refused.
+    pub min_collateral_ratio: Option<u64>,
+    /// The collateral ratio (e8s) below which the protocol enters recovery mode.
+    pub recovery_collateral_ratio: Option<u64>,
+    /// The fee rate (e8s) added to the swap fee in recovery mode.
+    pub recovery_fee_surcharge: Option<u64>,
+
+    /// The curves setting the fee rate of swaps.
+    pub swap_fee_curves: Option<SwapFeeCurves>,
+
+    /// The fraction (e8s) of the protocol fees paid into the insurance fund.
+    pub insurance_fund_fee_share: Option<u64>,
+    /// The fraction (e8s) of the protocol fees paid into the treasury.
+    pub treasury_fee_share: Option<u64>,
+    /// The fraction (e8s) of the protocol fees kept as a reserve buffer.
+    pub reserve_fee_share: Option<u64>,
+
+    /// The canister storing the archived events, which cannot change
+    /// once events are archived.
+    pub archive_principal: Option<Principal>,
+
+    /// Starts the global settlement winding down the protocol, which
+    /// cannot be undone.
+    pub start_global_settlement: Option<bool>,
+}
+
+impl UpgradeArgs {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(curve) = &self.liquidity_haircut_curve {
+            crate::updates::liquidity::validate_haircut_curve(curve)?;
+        }
+        if let Some(collaterals) = &self.collaterals {
+            crate::collateral::validate_collaterals(collaterals)?;
+        }
+        if let Some(curves) = &self.swap_fee_curves {
+            crate::updates::swap::validate_swap_fee_curves(curves)?;
+        }
+        Ok(())
+    }
+}
 
 pub fn post_upgrade(upgrade_args: Option<UpgradeArgs>) {
+    let start_global_settlement = upgrade_args
+        .as_ref()
+        .and_then(|args| args.start_global_settlement)
+        .unwrap_or(false);
     if let Some(upgrade_args) = upgrade_args {
+        if let Err(e) = upgrade_args.validate() {
+            ic_cdk::trap(&format!("[upgrade]: invalid upgrade args: {}", e));
+        }
         record_event(&Event::Upgrade(upgrade_args));
     };
 
     let start = ic_cdk::api::instruction_counter();
 
-    log!(P0, "[upgrade]: replaying {} events", count_events());
-
-    let state = replay(events()).unwrap_or_else(|e| {
+    let replayed = match latest_snapshot() {
+        Some(snapshot) => {
+            log!(
+                P0,
+                "[upgrade]: replaying {} events from the snapshot at event {}",
+                count_events() - snapshot.event_count,
+                snapshot.event_count
+            );
+            replay_from_snapshot(snapshot.state, events_from(snapshot.event_count))
+        }
+        // The snapshots of the older versions are upgraded, only a
+        // snapshot which does not decode leaves no way to replay.
+        None if first_event_index() > 0 => ic_cdk::trap(&format!(
+            "[upgrade]: the events before {} are archived, replaying requires a snapshot",
+            first_event_index()
+        )),
+        None => {
+            log!(P0, "[upgrade]: replaying {} events", count_events());
+            replay(events())
+        }
+    };
+    let state = replayed.unwrap_or_else(|e| {
         ic_cdk::trap(&format!(
             "[upgrade]: failed to replay the event log: {:?}",
             e
         ))
     });
 
+    if let Err(e) = state.check_settings() {
+        ic_cdk::trap(&format!("[upgrade]: invalid upgrade args: {}", e));
+    }
+    if let Some(archive_id) = archive_id() {
+        if state.archive_principal != Some(archive_id) {
+            ic_cdk::trap(&format!(
+                "[upgrade]: invalid upgrade args: the events are archived by {}",
+                archive_id
+            ));
+        }
+    }
+
     replace_state(state);
+    crate::storage::record_chain_checkpoints();
+    crate::storage::certify_chain_tip();
+    crate::storage::history::index_new_events();
 
     let end = ic_cdk::api::instruction_counter();
     log!(
@@ -36,4 +132,8 @@ pub fn post_upgrade(upgrade_args: Option<UpgradeArgs>) {
         "[upgrade]: replaying events consumed {} instructions",
         end - start
     );
+
+    if start_global_settlement {
+        crate::updates::settlement::start_global_settlement();
+    }
 }
@@ -1,18 +1,24 @@
 use crate::liquidity::LiquidityError;
 use candid::candid_method;
+use core_canister::checks::AuditReport;
 use core_canister::dashboard::build_dashboard;
 use core_canister::lifecycle::{init::CoreArgs, upgrade::UpgradeArgs};
 use core_canister::logs::P1;
 use core_canister::metrics::encode_metrics;
+use core_canister::price::Candle;
 use core_canister::state::{
-    eventlog::{Event, GetEventsArg},
-    read_state, Asset, IcpPrice, ProtocolStatus, UserData,
+    eventlog::{Event, GetEventsArg, GetEventsResponse},
+    read_state, Asset, ProtocolStatus, UserData,
 };
-use core_canister::tasks::schedule_now;
-use core_canister::tasks::TaskType;
+use core_canister::storage::history::{GetUserHistoryArg, HistoryItem};
+use core_canister::timer::schedule_startup_tasks;
+use core_canister::updates::balance::BalanceError;
+use core_canister::updates::deposit::DepositMethod;
 use core_canister::updates::leverage::{LeveragePositionError, OpenLeveragePositionArg};
 use core_canister::updates::liquidity;
-use core_canister::updates::swap::{SwapArg, SwapError};
+use core_canister::updates::liquidity::RemoveLiquidityQuote;
+use core_canister::updates::swap::{SwapArg, SwapError, SwapQuote};
+use core_canister::updates::treasury::TreasuryError;
 use ic_canister_log::export;
 use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
 use ic_cdk_macros::{init, post_upgrade, query, update};
@@ -31,24 +37,16 @@ fn ok_or_die(result: Result<(), String>) {
 /// Checks that Elliptic Core Canister state is internally consistent.
 #[cfg(feature = "self_check")]
 fn check_invariants() -> Result<(), String> {
-    use core_canister::state::eventlog::replay;
+    use core_canister::checks::{run_audit, AuditCheck};
 
-    read_state(|s| {
-        s.check_invariants()?;
-
-        let events: Vec<_> = core_canister::storage::events().collect();
-        let recovered_state = replay(events.clone().into_iter())
-            .unwrap_or_else(|e| panic!("failed to replay log {:?}: {:?}", events, e));
-
-        recovered_state.check_invariants()?;
-
-        // A running timer can temporarily violate invariants.
-        if !s.is_timer_running {
-            s.check_semantically_eq(&recovered_state)?;
+    let report = read_state(|s| run_audit(s, ic_cdk::api::time()));
+    // The ledger balance can lag behind the state in tests.
+    for check in [report.invariants, report.event_chain, report.replay] {
+        if let AuditCheck::Failed(e) = check {
+            return Err(e);
         }
-
-        Ok(())
-    })
+    }
+    Ok(())
 }
 
 fn check_postcondition<T>(t: T) -> T {
@@ -61,15 +59,16 @@ fn check_postcondition<T>(t: T) -> T {
 fn init(args: CoreArgs) {
     match args {
         CoreArgs::Init(init_args) => {
+            if let Err(e) = init_args.validate() {
+                ic_cdk::trap(&format!("[init]: invalid init args: {}", e));
+            }
             core_canister::storage::record_event(&Event::Init(init_args.clone()));
             core_canister::lifecycle::init::init(init_args);
         }
         CoreArgs::Upgrade(_) => panic!("Expected Init args got Upgrade args."),
     }
 
-    schedule_now(TaskType::FetchPrice);
-    // schedule_now(TaskType::ProtocolBalanceUpdate);
-    schedule_now(TaskType::CheckLeveragePositions);
+    schedule_startup_tasks();
 }
 
 #[post_upgrade]
@@ -83,19 +82,17 @@ fn post_upgrade(core_arg: Option<CoreArgs>) {
     }
     core_canister::lifecycle::upgrade::post_upgrade(upgrade_args);
 
-    schedule_now(TaskType::FetchPrice);
-    // schedule_now(TaskType::ProtocolBalanceUpdate);
-    schedule_now(TaskType::CheckLeveragePositions);
+    schedule_startup_tasks();
 }
 
 #[candid_method(update)]
 #[update]
-async fn get_deposit_account() -> Account {
+async fn get_deposit_account(nonce: Option<u64>) -> Account {
     Account {
         owner: ic_cdk::id(),
         subaccount: Some(core_canister::compute_subaccount(
             ic_cdk::caller().into(),
-            0,
+            nonce.unwrap_or(0),
         )),
     }
 }
@@ -110,43 +107,162 @@ async fn open_leverage_position(
 
 #[candid_method(update)]
 #[update]
-async fn close_leverage_position(position_block_index: u64) -> Result<u64, LeveragePositionError> {
+async fn close_leverage_position(
+    position_block_index: u64,
+    to_account: Option<Account>,
+) -> Result<u64, LeveragePositionError> {
     check_postcondition(
-        core_canister::updates::leverage::close_leverage_position(position_block_index).await,
+        core_canister::updates::leverage::close_leverage_position(position_block_index, to_account)
+            .await,
     )
 }
 
 #[candid_method(update)]
 #[update]
 async fn swap(swap_arg: SwapArg) -> Result<u64, SwapError> {
-    match swap_arg.from_asset {
-        Asset::ICP => check_postcondition(
-            core_canister::updates::swap::convert_icp_to_eusd(swap_arg.amount).await,
+    let deposit_method = swap_arg.deposit_method.unwrap_or_default();
+    match (swap_arg.from_asset, swap_arg.to_asset) {
+        (Asset::ICP, Asset::EUSD) => check_postcondition(
+            core_canister::updates::swap::convert_icp_to_eusd(
+                swap_arg.amount,
+                swap_arg.to_account,
+                deposit_method,
+            )
+            .await,
+        ),
+        (Asset::EUSD, Asset::ICP) => check_postcondition(
+            core_canister::updates::swap::convert_eusd_to_icp(
+                swap_arg.amount,
+                swap_arg.to_account,
+                deposit_method,
+            )
+            .await,
+        ),
+        (Asset::Collateral(symbol), Asset::EUSD) => check_postcondition(
+            core_canister::updates::swap::convert_collateral_to_eusd(
+                symbol,
+                swap_arg.amount,
+                swap_arg.to_account,
+                deposit_method,
+            )
+            .await,
         ),
-        Asset::EUSD => check_postcondition(
-            core_canister::updates::swap::convert_eusd_to_icp(swap_arg.amount).await,
+        (Asset::EUSD, Asset::Collateral(symbol)) => check_postcondition(
+            core_canister::updates::swap::convert_eusd_to_collateral(
+                symbol,
+                swap_arg.amount,
+                swap_arg.to_account,
+                deposit_method,
+            )
+            .await,
         ),
+        _ => Err(SwapError::UnsupportedSwap),
     }
 }
 
 #[candid_method(update)]
 #[update]
-async fn add_liquidity(amount: u64) -> Result<u64, LiquidityError> {
-    check_postcondition(core_canister::updates::liquidity::add_liquidity(amount).await)
+async fn add_liquidity(
+    amount: u64,
+    deposit_method: Option<DepositMethod>,
+) -> Result<u64, LiquidityError> {
+    check_postcondition(
+        core_canister::updates::liquidity::add_liquidity(
+            amount,
+            deposit_method.unwrap_or_default(),
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn remove_liquidity(amount: u64, to_account: Option<Account>) -> Result<u64, LiquidityError> {
+    check_postcondition(
+        core_canister::updates::liquidity::remove_liquidity(amount, to_account).await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+fn request_liquidity_withdrawal(
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, LiquidityError> {
+    check_postcondition(
+        core_canister::updates::liquidity::request_liquidity_withdrawal(amount, to_account),
+    )
 }
 
 #[candid_method(update)]
 #[update]
-async fn remove_liquidity(amount: u64) -> Result<u64, LiquidityError> {
+fn cancel_liquidity_withdrawal(id: u64) -> Result<(), LiquidityError> {
+    check_postcondition(core_canister::updates::liquidity::cancel_liquidity_withdrawal(id))
+}
+
+#[candid_method(update)]
+#[update]
+async fn claim_liquidity_rewards(to_account: Option<Account>) -> Result<u64, LiquidityError> {
     check_postcondition(
-        core_canister::updates::liquidity::remove_liquidity(amount, ic_cdk::caller()).await,
+        core_canister::updates::liquidity::claim_liquidity_rewards(to_account).await,
     )
 }
 
 #[candid_method(update)]
 #[update]
-async fn claim_liquidity_rewards() -> Result<u64, LiquidityError> {
-    check_postcondition(core_canister::updates::liquidity::claim_liquidity_rewards().await)
+async fn withdraw_treasury(amount: u64, to: Account) -> Result<u64, TreasuryError> {
+    check_postcondition(core_canister::updates::treasury::withdraw_treasury(amount, to).await)
+}
+
+#[candid_method(update)]
+#[update]
+fn compound_liquidity_rewards() -> Result<u64, LiquidityError> {
+    check_postcondition(core_canister::updates::liquidity::compound_liquidity_rewards())
+}
+
+#[candid_method(update)]
+#[update]
+fn set_auto_compound(enabled: bool) -> Result<(), LiquidityError> {
+    check_postcondition(core_canister::updates::liquidity::set_auto_compound(
+        enabled,
+    ))
+}
+
+#[candid_method(update)]
+#[update]
+async fn deposit(
+    asset: Asset,
+    amount: u64,
+    deposit_method: Option<DepositMethod>,
+) -> Result<u64, BalanceError> {
+    check_postcondition(
+        core_canister::updates::balance::deposit(asset, amount, deposit_method.unwrap_or_default())
+            .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn withdraw(
+    asset: Asset,
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, BalanceError> {
+    check_postcondition(core_canister::updates::balance::withdraw(asset, amount, to_account).await)
+}
+
+#[candid_method(update)]
+#[update]
+async fn reclaim_deposit(asset: Asset, nonce: Option<u64>) -> Result<u64, BalanceError> {
+    check_postcondition(
+        core_canister::updates::balance::reclaim_deposit(asset, nonce.unwrap_or(0)).await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+fn set_internal_balance_payouts(enabled: bool) -> Result<(), BalanceError> {
+    check_postcondition(core_canister::updates::balance::set_internal_balance_payouts(enabled))
 }
 
 #[candid_method(query)]
@@ -156,30 +272,103 @@ fn get_user_data(principal: candid::Principal) -> UserData {
         claimable_liquidity_rewards: s.liquidity_rewards.get(&principal).cloned().unwrap_or(0),
         liquidity_provided: *s.liquidity_provided.get(&principal).unwrap_or(&0),
         leverage_positions: s.get_leverage_position_of(principal),
+        auto_compound: s.liquidity_auto_compound.contains(&principal),
+        pending_liquidity_withdrawals: s.get_liquidity_withdrawals_of(&principal),
+        icp_balance: s.get_balance(&principal, &Asset::ICP),
+        eusd_balance: s.get_balance(&principal, &Asset::EUSD),
+        internal_balance_payouts: s.internal_balance_payouts.contains(&principal),
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn quote_remove_liquidity(amount: u64) -> Result<RemoveLiquidityQuote, LiquidityError> {
+    read_state(|s| {
+        core_canister::updates::liquidity::quote_remove_liquidity(s, ic_cdk::caller(), amount)
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn quote_swap(from_asset: Asset, to_asset: Asset, amount: u64) -> Result<SwapQuote, SwapError> {
+    read_state(|s| {
+        core_canister::updates::swap::quote_swap(
+            s,
+            &from_asset,
+            &to_asset,
+            amount,
+            ic_cdk::api::time(),
+        )
     })
 }
 
 #[candid_method(query)]
 #[query]
 fn get_protocol_status() -> ProtocolStatus {
-    read_state(|s| ProtocolStatus {
-        collateral_ratio: s.get_collateral_ratio(),
-        coverered_ratio: s.get_coverered_ratio(),
-        icp_price: s.get_last_icp_price().unwrap_or(IcpPrice { rate: 0 }).rate,
-        tvl: s.get_tvl(),
-        coverable_amount: s.get_leverage_coverable_amount(),
+    read_state(|s| s.get_protocol_status(ic_cdk::api::time()))
+}
+
+/// Returns the candles of the price of `asset` in `[from, to)`, each
+/// spanning `resolution` nanoseconds.
+#[candid_method(query)]
+#[query]
+fn get_price_history(asset: Asset, from: u64, to: u64, resolution: u64) -> Vec<Candle> {
+    const MAX_CANDLES_PER_QUERY: u64 = 1_000;
+
+    if resolution == 0 {
+        ic_cdk::trap("the resolution must be positive");
+    }
+    let to = to.min(from.saturating_add(MAX_CANDLES_PER_QUERY.saturating_mul(resolution)));
+    read_state(|s| {
+        s.get_price_history(&asset)
+            .map(|prices| core_canister::price::candles(prices, from, to, resolution))
+            .unwrap_or_default()
     })
 }
 
+/// Audits the state without trapping, only the controllers can run it.
 #[candid_method(query)]
 #[query]
-fn get_events(args: GetEventsArg) -> Vec<Event> {
-    const MAX_EVENTS_PER_QUERY: usize = 2000;
+fn run_audit() -> AuditReport {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("only the controllers can run the audit");
+    }
+    read_state(|s| core_canister::checks::run_audit(s, ic_cdk::api::time()))
+}
+
+#[candid_method(query)]
+#[query]
+fn get_events(args: GetEventsArg) -> GetEventsResponse {
+    use core_canister::storage::{
+        chain_hash_at, chain_tip, count_events, decode_event, read_raw_events, split_archived_range,
+    };
+
+    // Each event is sent twice, decoded and raw.
+    const MAX_EVENTS_PER_QUERY: u64 = 1000;
+
+    let (archived, live) = split_archived_range(args.start, MAX_EVENTS_PER_QUERY.min(args.length));
+    let entries = read_raw_events(live.start, live.end - live.start);
+    GetEventsResponse {
+        total_event_count: count_events(),
+        first_index: live.start,
+        events: entries.iter().map(|entry| decode_event(entry)).collect(),
+        entries,
+        prev_hash: chain_hash_at(live.start).to_vec(),
+        tip_hash: chain_tip().to_vec(),
+        certificate: ic_cdk::api::data_certificate(),
+        archived_events: archived.into_iter().collect(),
+    }
+}
 
-    core_canister::storage::events()
-        .skip(args.start as usize)
-        .take(MAX_EVENTS_PER_QUERY.min(args.length as usize))
-        .collect()
+#[candid_method(query)]
+#[query]
+fn get_user_history(args: GetUserHistoryArg) -> Vec<HistoryItem> {
+    const MAX_ITEMS_PER_QUERY: u64 = 500;
+
+    core_canister::storage::history::get_user_history(&GetUserHistoryArg {
+        length: MAX_ITEMS_PER_QUERY.min(args.length),
+        ..args
+    })
 }
 
 #[candid_method(query)]
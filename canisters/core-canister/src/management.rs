@@ -8,15 +8,16 @@ use ic_xrc_types::{Asset, AssetClass, GetExchangeRateRequest, GetExchangeRateRes
 use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::TransferArg;
 use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 
 const XRC_MARGIN_SEC: u64 = 5 * 60;
 // The payment required for querying the XRC canister.
 const XRC_CALL_COST_CYCLES: u64 = 10_000_000_000;
 
-/// Query the XRC canister to retrieve the last ICP/USD price.
-pub async fn get_exchange_rate() -> Result<GetExchangeRateResult, String> {
-    let icp = Asset {
-        symbol: "ICP".to_string(),
+/// Query the XRC canister to retrieve the last `symbol`/USD price.
+pub async fn get_exchange_rate(symbol: &str) -> Result<GetExchangeRateResult, String> {
+    let base_asset = Asset {
+        symbol: symbol.to_string(),
         class: AssetClass::Cryptocurrency,
     };
     let usd = Asset {
@@ -27,16 +28,16 @@ pub async fn get_exchange_rate() -> Result<GetExchangeRateResult, String> {
     // Take few minutes back to be sure to have data.
     let timestamp_sec = ic_cdk::api::time() / crate::SEC_NANOS - XRC_MARGIN_SEC;
 
-    // Retrieve last ICP/USD value.
+    // Retrieve last symbol/USD value.
     let args = GetExchangeRateRequest {
-        base_asset: icp,
+        base_asset,
         quote_asset: usd,
         timestamp: Some(timestamp_sec),
     };
 
     let xrc_principal = read_state(|s| s.xrc_principal);
 
-    ic_cdk::println!("Calling XRC canister ({})", xrc_principal);
+    ic_cdk::println!("Calling XRC canister ({}) for {}", xrc_principal, symbol);
     let res_xrc: Result<(GetExchangeRateResult,), (i32, String)> =
         match read_state(|s| s.mode.clone()) {
             Mode::NoHttpOutCalls => call(xrc_principal, "get_exchange_rate", (args,)).await,
@@ -59,7 +60,15 @@ pub async fn get_exchange_rate() -> Result<GetExchangeRateResult, String> {
     }
 }
 
-pub async fn mint_eusd(amount: u64, to: Principal) -> Result<u64, TransferError> {
+/// The main account of the core canister, holding the protocol funds.
+pub fn main_account() -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    }
+}
+
+pub async fn mint_eusd(amount: u64, to: Account) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
         ledger_canister_id: crate::state::read_state(|s| s.eusd_ledger_principal),
@@ -67,10 +76,7 @@ pub async fn mint_eusd(amount: u64, to: Principal) -> Result<u64, TransferError>
     let block_index = client
         .transfer(TransferArg {
             from_subaccount: None,
-            to: Account {
-                owner: to,
-                subaccount: None,
-            },
+            to,
             fee: None,
             created_at_time: None,
             memo: None,
@@ -84,13 +90,14 @@ pub async fn mint_eusd(amount: u64, to: Principal) -> Result<u64, TransferError>
     Ok(block_index)
 }
 
-pub async fn burn_eusd(user: Principal, amount: u64) -> Result<u64, TransferError> {
+/// Burns `amount` eUSD from the deposit subaccount of `user` for `nonce`.
+pub async fn burn_eusd(user: Principal, nonce: u64, amount: u64) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
         ledger_canister_id: read_state(|s| s.eusd_ledger_principal),
     };
     let core_id = ic_cdk::id();
-    let from_subaccount = crate::compute_subaccount(PrincipalId(user), 0);
+    let from_subaccount = crate::compute_subaccount(PrincipalId(user), nonce);
     let block_index = client
         .transfer(TransferArg {
             from_subaccount: Some(from_subaccount),
@@ -113,20 +120,29 @@ pub async fn burn_eusd(user: Principal, amount: u64) -> Result<u64, TransferErro
 
 pub async fn transfer_icp(
     from_subaccount: Option<Subaccount>,
-    to: Principal,
+    to: Account,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    let icp_ledger = read_state(|s| s.icp_ledger_principal);
+    transfer(icp_ledger, from_subaccount, to, amount).await
+}
+
+/// Transfers `amount` from a subaccount of the core canister on the given ledger.
+/// Transfers to the minting account burn the funds.
+pub async fn transfer(
+    ledger_canister_id: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
     amount: u64,
 ) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: read_state(|s| s.icp_ledger_principal),
+        ledger_canister_id,
     };
     let block_index = client
         .transfer(TransferArg {
             from_subaccount,
-            to: Account {
-                owner: to,
-                subaccount: None,
-            },
+            to,
             fee: None,
             created_at_time: None,
             memo: None,
@@ -140,16 +156,81 @@ pub async fn transfer_icp(
     Ok(block_index)
 }
 
-pub async fn balance_of(owner: Principal) -> Result<u64, TransferError> {
+/// Pulls `amount` from `from` into the main account of the core canister
+/// using an ICRC-2 allowance granted to the core canister.
+/// When the ledger is the eUSD ledger, the funds are burned.
+pub async fn transfer_from(
+    ledger_canister_id: Principal,
+    from: Account,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to: main_account(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let res: Result<(Result<Nat, TransferFromError>,), (i32, String)> =
+        call(ledger_canister_id, "icrc2_transfer_from", (args,)).await;
+    match res {
+        Ok((Ok(block_index),)) => Ok(nat_to_u64(block_index)),
+        Ok((Err(e),)) => Err(transfer_from_error_to_transfer_error(e)),
+        Err((code, msg)) => Err(TransferError::GenericError {
+            error_code: Nat::from(code),
+            message: msg,
+        }),
+    }
+}
+
+fn nat_to_u64(n: Nat) -> u64 {
+    use num_traits::ToPrimitive;
+    n.0.to_u64().expect("bug: block index does not fit in u64")
+}
+
+/// Maps an ICRC-2 error to the ICRC-1 error returned by our endpoints.
+/// `InsufficientAllowance` has no ICRC-1 counterpart and is reported as a generic error.
+fn transfer_from_error_to_transfer_error(e: TransferFromError) -> TransferError {
+    match e {
+        TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+        TransferFromError::BadBurn { min_burn_amount } => {
+            TransferError::BadBurn { min_burn_amount }
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            TransferError::InsufficientFunds { balance }
+        }
+        TransferFromError::InsufficientAllowance { allowance } => TransferError::GenericError {
+            error_code: Nat::from(0),
+            message: format!("insufficient allowance: {}", allowance),
+        },
+        TransferFromError::TooOld => TransferError::TooOld,
+        TransferFromError::CreatedInFuture { ledger_time } => {
+            TransferError::CreatedInFuture { ledger_time }
+        }
+        TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+        TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+        TransferFromError::GenericError {
+            error_code,
+            message,
+        } => TransferError::GenericError {
+            error_code,
+            message,
+        },
+    }
+}
+
+pub async fn balance_of(
+    ledger_canister_id: Principal,
+    account: Account,
+) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: read_state(|s| s.icp_ledger_principal),
+        ledger_canister_id,
     };
     let balance = client
-        .balance_of(Account {
-            owner,
-            subaccount: None,
-        })
+        .balance_of(account)
         .await
         .map_err(|e| TransferError::GenericError {
             error_code: (Nat::from(e.0)),
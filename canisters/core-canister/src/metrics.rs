@@ -16,6 +16,29 @@ pub fn encode_metrics(
         "Cycle balance on this canister.",
     )?;
 
+    metrics.encode_gauge(
+        "core_event_count",
+        crate::storage::count_events() as f64,
+        "The number of recorded events, archived ones included.",
+    )?;
+    metrics.encode_gauge(
+        "core_archived_event_count",
+        crate::storage::first_event_index() as f64,
+        "The number of events moved to the archive.",
+    )?;
+
+    metrics.encode_counter(
+        "core_audit_violation_count",
+        crate::checks::audit_violation_count() as f64,
+        "The number of violations found by the audits since the last upgrade.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_icp_price_count",
+        state::read_state(|s| s.icp_prices.len() as f64),
+        "The number of ICP prices in the retention window.",
+    )?;
+
     metrics.encode_gauge(
         "core_leverage_total_amount",
         state::read_state(|s| s.get_total_leverage_amount() as f64),
@@ -78,5 +101,71 @@ pub fn encode_metrics(
         "The total reward amount claimable by liquidity providers.",
     )?;
 
+    metrics.encode_gauge(
+        "core_pending_liquidity_withdrawals",
+        state::read_state(|s| s.liquidity_withdrawal_queue.len() as f64),
+        "The number of liquidity withdrawals waiting in the queue.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_icp_internal_balances",
+        state::read_state(|s| s.icp_balances.values().sum::<u64>() as f64),
+        "The total ICP held in the internal balances of users.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_eusd_internal_balances",
+        state::read_state(|s| s.eusd_balances.values().sum::<u64>() as f64),
+        "The total eUSD held in the internal balances of users.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_collaterals_value",
+        state::read_state(|s| s.get_collaterals_value() as f64),
+        "The eUSD value of the collaterals besides ICP.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_insurance_fund_amount",
+        state::read_state(|s| s.insurance_fund_amount as f64),
+        "The ICP in the insurance fund covering the shortfalls.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_insurance_fund_fee_share",
+        state::read_state(|s| s.insurance_fund_fee_share as f64 / 100_000_000.0),
+        "The share of the protocol fees paid into the insurance fund.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_treasury_amount",
+        state::read_state(|s| s.treasury_amount as f64),
+        "The ICP in the protocol treasury.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_treasury_fee_share",
+        state::read_state(|s| s.treasury_fee_share as f64 / 100_000_000.0),
+        "The share of the protocol fees paid into the treasury.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_reserve_amount",
+        state::read_state(|s| s.reserve_amount as f64),
+        "The ICP kept in the reserve buffer.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_reserve_fee_share",
+        state::read_state(|s| s.reserve_fee_share as f64 / 100_000_000.0),
+        "The share of the protocol fees kept in the reserve buffer.",
+    )?;
+
+    metrics.encode_gauge(
+        "core_bad_debt",
+        state::read_state(|s| s.bad_debt as f64),
+        "The ICP shortfall covered neither by the insurance fund nor by the liquidity.",
+    )?;
+
     Ok(())
 }
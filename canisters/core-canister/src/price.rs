@@ -0,0 +1,323 @@
+//! The prices fetched from the exchange rate canister, recorded in the
+//! event log as the only source of prices of the state.
+
+use crate::state::{Asset, IcpPrice};
+use candid::{CandidType, Principal};
+use ic_ledger_types::Timestamp;
+use ic_xrc_types::ExchangeRateMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// The prices older than that before the latest price are dropped.
+pub const PRICE_RETENTION_NANOS: u64 = 30 * 24 * crate::ONE_HOUR_NANOS;
+
+/// The window of the average prices the swaps and the liquidations use,
+/// so that a short spike does not move them.
+pub const TWAP_WINDOW_NANOS: u64 = crate::ONE_HOUR_NANOS;
+
+/// A price fetched by the `FetchPrice` task.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceUpdate {
+    pub asset: Asset,
+    /// The USD price of the asset e8s.
+    pub rate: u64,
+    /// The time of the rate in nanoseconds.
+    pub timestamp: u64,
+    /// The exchange rate canister serving the rate.
+    pub source: Principal,
+    pub metadata: PriceMetadata,
+}
+
+/// The metadata of the exchange rate canister for a rate.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceMetadata {
+    /// The decimals of the rate before its conversion to e8s.
+    pub decimals: u32,
+    pub base_asset_num_queried_sources: u64,
+    pub base_asset_num_received_rates: u64,
+    pub quote_asset_num_queried_sources: u64,
+    pub quote_asset_num_received_rates: u64,
+    /// The standard deviation of the received rates, with the
+    /// decimals of the rate.
+    pub standard_deviation: u64,
+    /// The timestamp of the forex rates in seconds, if used.
+    pub forex_timestamp: Option<u64>,
+}
+
+impl From<&ExchangeRateMetadata> for PriceMetadata {
+    fn from(metadata: &ExchangeRateMetadata) -> Self {
+        Self {
+            decimals: metadata.decimals,
+            base_asset_num_queried_sources: metadata.base_asset_num_queried_sources as u64,
+            base_asset_num_received_rates: metadata.base_asset_num_received_rates as u64,
+            quote_asset_num_queried_sources: metadata.quote_asset_num_queried_sources as u64,
+            quote_asset_num_received_rates: metadata.quote_asset_num_received_rates as u64,
+            standard_deviation: metadata.standard_deviation,
+            forex_timestamp: metadata.forex_timestamp,
+        }
+    }
+}
+
+/// The prices of a time range.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Candle {
+    /// The start of the range in nanoseconds.
+    pub start: u64,
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+}
+
+/// Drops the prices older than [PRICE_RETENTION_NANOS] before the latest
+/// one, which only depends on the prices so that the replay prunes them
+/// the same way.
+pub fn prune_prices(prices: &mut BTreeMap<Timestamp, IcpPrice>) {
+    if let Some(latest) = prices.keys().next_back() {
+        let cutoff = latest.timestamp_nanos.saturating_sub(PRICE_RETENTION_NANOS);
+        prices.retain(|timestamp, _| timestamp.timestamp_nanos >= cutoff);
+    }
+}
+
+/// Returns the time-weighted average rate in `[from, to)`, each price
+/// holding until the next one. The time before the first known price
+/// is not weighted.
+pub fn time_weighted_average(
+    prices: &BTreeMap<Timestamp, IcpPrice>,
+    from: u64,
+    to: u64,
+) -> Option<u64> {
+    if from >= to {
+        return None;
+    }
+    let mut rate = prices
+        .range(
+            ..=Timestamp {
+                timestamp_nanos: from,
+            },
+        )
+        .next_back()
+        .map(|(_, price)| price.rate);
+    let mut cursor = from;
+    let mut weighted_sum: u128 = 0;
+    let mut duration: u64 = 0;
+    let mut weigh = |rate: Option<u64>, until: u64, cursor: u64| {
+        if let Some(rate) = rate {
+            weighted_sum += rate as u128 * (until - cursor) as u128;
+            duration += until - cursor;
+        }
+    };
+    for (timestamp, price) in prices.range((
+        Bound::Excluded(Timestamp {
+            timestamp_nanos: from,
+        }),
+        Bound::Excluded(Timestamp {
+            timestamp_nanos: to,
+        }),
+    )) {
+        weigh(rate, timestamp.timestamp_nanos, cursor);
+        cursor = timestamp.timestamp_nanos;
+        rate = Some(price.rate);
+    }
+    weigh(rate, to, cursor);
+    if duration == 0 {
+        return None;
+    }
+    Some((weighted_sum / duration as u128) as u64)
+}
+
+/// Returns the candles of the prices in `[from, to)`, each spanning
+/// `resolution` nanoseconds from `from`. The ranges without prices
+/// have no candle.
+pub fn candles(
+    prices: &BTreeMap<Timestamp, IcpPrice>,
+    from: u64,
+    to: u64,
+    resolution: u64,
+) -> Vec<Candle> {
+    assert!(resolution > 0, "bug: the resolution must be positive");
+    let mut candles: Vec<Candle> = vec![];
+    if from >= to {
+        return candles;
+    }
+    for (timestamp, price) in prices.range(
+        Timestamp {
+            timestamp_nanos: from,
+        }..Timestamp {
+            timestamp_nanos: to,
+        },
+    ) {
+        let start = from + (timestamp.timestamp_nanos - from) / resolution * resolution;
+        match candles.last_mut() {
+            Some(candle) if candle.start == start => {
+                candle.high = candle.high.max(price.rate);
+                candle.low = candle.low.min(price.rate);
+                candle.close = price.rate;
+            }
+            _ => candles.push(Candle {
+                start,
+                open: price.rate,
+                high: price.rate,
+                low: price.rate,
+                close: price.rate,
+            }),
+        }
+    }
+    candles
+}
+
+#[test]
+fn test_replay_price_updates() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::state::audit::record_price_update;
+    use crate::state::eventlog::{replay, Event};
+    use crate::state::IcpPrice;
+    use crate::storage::{events, record_event};
+
+    record_event(&Event::Init(default_init_args()));
+    let mut state = replay(events()).unwrap();
+    assert_eq!(state.get_last_icp_price(), None);
+
+    let metadata = PriceMetadata {
+        decimals: 9,
+        base_asset_num_queried_sources: 3,
+        base_asset_num_received_rates: 3,
+        quote_asset_num_queried_sources: 1,
+        quote_asset_num_received_rates: 1,
+        standard_deviation: 0,
+        forex_timestamp: None,
+    };
+    for (timestamp, rate) in [(2, 500_000_000), (1, 400_000_000)] {
+        record_price_update(
+            &mut state,
+            PriceUpdate {
+                asset: Asset::ICP,
+                rate,
+                timestamp,
+                source: Principal::anonymous(),
+                metadata: metadata.clone(),
+            },
+        );
+    }
+    assert_eq!(
+        state.get_last_icp_price(),
+        Some(IcpPrice { rate: 500_000_000 })
+    );
+
+    let replayed_state = replay(events()).unwrap();
+    assert_eq!(replayed_state.icp_prices.len(), 2);
+    assert_eq!(state.check_semantically_eq(&replayed_state), Ok(()));
+
+    // Once a price update is recorded, the prices of the swaps are no
+    // longer taken.
+    record_event(&Event::Swap(crate::updates::swap::Swap {
+        caller: Principal::from_slice(&[1]),
+        from: Asset::ICP,
+        from_block_index: 0,
+        from_amount: 100_000_000,
+        to: Asset::EUSD,
+        rate: 700_000_000,
+        fee: 0,
+        timestamp: 3,
+        to_account: None,
+        fee_rate: None,
+    }));
+    let replayed_state = replay(events()).unwrap();
+    assert_eq!(
+        replayed_state.get_last_icp_price(),
+        Some(IcpPrice { rate: 500_000_000 })
+    );
+}
+
+#[cfg(test)]
+fn price_map(prices: &[(u64, u64)]) -> BTreeMap<Timestamp, IcpPrice> {
+    prices
+        .iter()
+        .map(|(timestamp_nanos, rate)| {
+            (
+                Timestamp {
+                    timestamp_nanos: *timestamp_nanos,
+                },
+                IcpPrice { rate: *rate },
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_prune_prices() {
+    let mut prices = price_map(&[(0, 1), (10, 2), (PRICE_RETENTION_NANOS + 10, 3)]);
+    prune_prices(&mut prices);
+    assert_eq!(
+        prices,
+        price_map(&[(10, 2), (PRICE_RETENTION_NANOS + 10, 3)])
+    );
+}
+
+#[test]
+fn test_time_weighted_average() {
+    let prices = price_map(&[(10, 100), (20, 200), (40, 400)]);
+
+    assert_eq!(time_weighted_average(&prices, 0, 10), None);
+    assert_eq!(time_weighted_average(&prices, 10, 10), None);
+    assert_eq!(time_weighted_average(&prices, 10, 20), Some(100));
+    // 10 x 100 + 20 x 200 over 30.
+    assert_eq!(time_weighted_average(&prices, 10, 40), Some(166));
+    // The time before the first price is not weighted.
+    assert_eq!(time_weighted_average(&prices, 0, 30), Some(150));
+    assert_eq!(time_weighted_average(&prices, 50, 60), Some(400));
+}
+
+#[test]
+fn test_candles() {
+    let prices = price_map(&[(10, 100), (15, 300), (18, 200), (35, 400)]);
+
+    assert_eq!(
+        candles(&prices, 10, 40, 10),
+        vec![
+            Candle {
+                start: 10,
+                open: 100,
+                high: 300,
+                low: 100,
+                close: 200,
+            },
+            Candle {
+                start: 30,
+                open: 400,
+                high: 400,
+                low: 400,
+                close: 400,
+            },
+        ]
+    );
+    assert_eq!(candles(&prices, 11, 35, 100).len(), 1);
+    assert!(candles(&prices, 40, 10, 10).is_empty());
+}
+
+#[test]
+fn test_get_average_price() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::state::CoreState;
+
+    let mut state = CoreState::from(default_init_args());
+    assert_eq!(
+        state.get_average_price(&Asset::ICP, TWAP_WINDOW_NANOS),
+        None
+    );
+
+    state.icp_prices = price_map(&[(0, 100), (TWAP_WINDOW_NANOS / 2, 300)]);
+    // A spike to 300 over half the window moves the average to 200.
+    assert_eq!(
+        state.get_average_price(&Asset::ICP, TWAP_WINDOW_NANOS),
+        Some(IcpPrice { rate: 200 })
+    );
+
+    // Without any price in the window, the latest price is used.
+    state.icp_prices = price_map(&[(TWAP_WINDOW_NANOS, 500)]);
+    assert_eq!(
+        state.get_average_price(&Asset::ICP, TWAP_WINDOW_NANOS),
+        Some(IcpPrice { rate: 500 })
+    );
+}
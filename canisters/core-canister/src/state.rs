@@ -1,9 +1,12 @@
+use crate::collateral::{from_value_e8s, to_value_e8s, CollateralConfig};
+use crate::curve::PiecewiseLinearCurve;
 use crate::divide_e8s;
 use crate::lifecycle::init::InitArgs;
 use crate::lifecycle::upgrade::UpgradeArgs;
 use crate::multiply_e8s;
-use crate::updates::liquidity::Liquidity;
-use crate::updates::swap::Swap;
+use crate::price::PriceUpdate;
+use crate::updates::liquidity::{Liquidity, LiquidityWithdrawal};
+use crate::updates::swap::{Swap, SwapDirection, SwapFeeCurves};
 use crate::E8S_FLOAT;
 use candid::CandidType;
 use candid::Principal;
@@ -22,6 +25,17 @@ const DEFAULT_MIN_AMOUNT_FROM_STABLE: u64 = 100_000_000;
 const DEFAULT_MIN_AMOUNT_TO_STABLE: u64 = 100_000_000;
 const DEFAULT_MIN_AMOUNT_LEVERAGE: u64 = 100_000_000;
 const DEFAULT_MIN_AMOUNT_LIQUIDITY: u64 = 100_000_000;
+const DEFAULT_MINT_EPOCH_NANOS: u64 = 24 * crate::ONE_HOUR_NANOS;
+pub const DEFAULT_RECOVERY_FEE_SURCHARGE: u64 = 250_000;
+/// The fee rate (e8s) of the protocol, swaps charge it by default.
+pub const DEFAULT_BASE_FEE: u64 = 250_000;
+
+/// No haircut above 120% of collateral ratio, linear down to 0 below.
+const DEFAULT_LIQUIDITY_HAIRCUT_CURVE: [(u64, u64); 2] = [(0, 0), (120_000_000, 100_000_000)];
+
+/// Operations funded from or paid to internal balances have no ledger block,
+/// they are identified by synthetic block indexes starting at this offset.
+pub const INTERNAL_BLOCK_INDEX_OFFSET: u64 = 1 << 63;
 
 const DEFAULT_XRC_PRINCIPAL: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
 const DEFAULT_ICP_LEDGER_PRINCIPAL: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
@@ -74,6 +88,79 @@ pub struct ProtocolStatus {
     // The amount of ICP that can be covered
     // by leverage positions e8s.
     pub coverable_amount: u64,
+    // The registered collaterals besides ICP.
+    pub collaterals: Vec<CollateralStatus>,
+    // The amount of eUSD that can still be minted given the
+    // debt ceiling and the mint cap of the current epoch e8s.
+    pub remaining_mint_capacity: u64,
+    // The regime given by the collateral ratio.
+    pub regime: ProtocolRegime,
+    // The global settlement winding down the protocol, if started.
+    pub global_settlement: Option<GlobalSettlement>,
+    // The time the status was computed at, ns.
+    pub timestamp: u64,
+}
+
+/// The operating regime of the protocol, given by its collateral ratio.
+#[derive(candid::CandidType, Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+pub enum ProtocolRegime {
+    Normal,
+    /// Swap fees are raised and no leverage position can be opened.
+    Recovery,
+    /// Recovery mode where minting eUSD is refused as well.
+    Critical,
+}
+
+impl fmt::Display for ProtocolRegime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolRegime::Normal => write!(f, "Normal"),
+            ProtocolRegime::Recovery => write!(f, "Recovery"),
+            ProtocolRegime::Critical => write!(f, "Critical"),
+        }
+    }
+}
+
+/// A global settlement winds the protocol down: the ICP price is frozen, the
+/// leverage positions are closed at that price and eUSD can only be redeemed
+/// against the ICP collateral.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub struct GlobalSettlement {
+    /// The ICP price frozen when the settlement started.
+    pub icp_price: IcpPrice,
+    pub timestamp: u64,
+    /// The ICP price (e8s) at which eUSD is redeemed, set once all the
+    /// leverage positions are closed.
+    pub redemption_rate: Option<u64>,
+    /// The prices (e8s) at which eUSD is redeemed against the other
+    /// collaterals, set with the ICP one.
+    #[serde(default)]
+    pub collateral_redemption_rates: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for GlobalSettlement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.redemption_rate {
+            Some(rate) => write!(f, "Redeeming eUSD at {} $/ICP", rate as f64 / E8S_FLOAT),
+            None => write!(
+                f,
+                "Closing leverage positions at {} $/ICP",
+                self.icp_price.rate as f64 / E8S_FLOAT
+            ),
+        }
+    }
+}
+
+#[derive(candid::CandidType, serde::Deserialize, Debug, Eq, PartialEq)]
+pub struct CollateralStatus {
+    pub symbol: String,
+    pub ledger_principal: Principal,
+    // The amount of collateral backing eUSD.
+    pub amount: u64,
+    // The last price entry e8s, 0 if none.
+    pub price: u64,
+    // The value of the collateral in eUSD e8s.
+    pub value: u64,
 }
 
 #[derive(candid::CandidType, serde::Deserialize, Debug, Eq, PartialEq)]
@@ -83,6 +170,16 @@ pub struct UserData {
     pub liquidity_provided: u64,
 
     pub leverage_positions: Option<Vec<LeveragePosition>>,
+
+    pub auto_compound: bool,
+
+    pub pending_liquidity_withdrawals: Vec<LiquidityWithdrawal>,
+
+    pub icp_balance: u64,
+
+    pub eusd_balance: u64,
+
+    pub internal_balance_payouts: bool,
 }
 
 #[derive(
@@ -147,13 +244,18 @@ pub struct ConversionEntry {
 pub enum Asset {
     ICP,
     EUSD,
+    /// A collateral of the registry, identified by its symbol.
+    Collateral(String),
 }
 
-#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize)]
+#[derive(
+    candid::CandidType, Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, Serialize,
+)]
 pub enum Mode {
     ReadOnly,
     RestrictedTo(Vec<Principal>),
     DepositsRestrictedTo(Vec<Principal>),
+    #[default]
     GeneralAvailability,
     NoHttpOutCalls,
 }
@@ -182,23 +284,42 @@ impl fmt::Display for Mode {
     }
 }
 
+/// The fields missing from an older snapshot decode to their defaults
+/// before its upgrade, see [crate::storage::migration].
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, Serialize)]
+#[serde(default)]
 pub struct CoreState {
     /// Canisters ids
     pub eusd_ledger_principal: Principal,
     pub icp_ledger_principal: Principal,
     pub xrc_principal: Principal,
+    pub archive_principal: Option<Principal>,
 
     pub liquidity_provided: BTreeMap<Principal, u64>,
     pub liquidity_rewards: BTreeMap<Principal, u64>,
+    // Liquidity providers whose rewards are added to their liquidity.
+    pub liquidity_auto_compound: BTreeSet<Principal>,
 
     pub leverage_positions: BTreeMap<Principal, BTreeSet<LeveragePosition>>,
     pub block_index_to_owner: BTreeMap<u64, Principal>,
 
+    // FIFO queue of liquidity withdrawals waiting for the
+    // collateral ratio to recover, keyed by request id.
+    pub liquidity_withdrawal_queue: BTreeMap<u64, LiquidityWithdrawal>,
+    pub next_liquidity_withdrawal_id: u64,
+
+    // Internal balances held by the protocol on behalf of users.
+    pub icp_balances: BTreeMap<Principal, u64>,
+    pub eusd_balances: BTreeMap<Principal, u64>,
+    // Principals whose payouts are credited to their internal balances.
+    pub internal_balance_payouts: BTreeSet<Principal>,
+    pub next_internal_block_index: u64,
+
     pub fees: FeesPerAction,
 
-    // Map from block index to swap
-    pub open_swaps: BTreeMap<u64, Swap>,
+    // Map from the asset and the block index of the swapped deposit to
+    // swap, the ledgers number their blocks independently.
+    pub open_swaps: BTreeMap<(Asset, u64), Swap>,
 
     pub icp_collateral_amount: u64,
     pub icp_liqudity_amount: u64,
@@ -206,10 +327,53 @@ pub struct CoreState {
     pub icp_collateral_covered_amount: u64,
     pub protocol_balance: u64,
 
+    // Collaterals accepted besides ICP keyed by symbol, with
+    // the amount of each backing eUSD.
+    pub collaterals: BTreeMap<String, CollateralConfig>,
+    pub collateral_amounts: BTreeMap<String, u64>,
+
     pub total_eusd_minted: u64,
     pub total_eusd_burned: u64,
     pub total_available_fees: u64,
 
+    // The fraction (e8s) of the fees paid into the insurance fund, which
+    // covers the shortfalls before they are socialized to liquidity providers.
+    pub insurance_fund_fee_share: u64,
+    pub insurance_fund_amount: u64,
+    // The ICP shortfall covered neither by the insurance fund nor
+    // by the liquidity providers, repaid first by the fund.
+    pub bad_debt: u64,
+
+    // The fractions (e8s) of the fees paid into the protocol treasury,
+    // withdrawn by the controllers, and kept as a reserve buffer drawn on
+    // after the insurance fund. Liquidity providers get the rest.
+    pub treasury_fee_share: u64,
+    pub treasury_amount: u64,
+    // The treasury fees ever paid and withdrawn, which reconcile
+    // the treasury amount.
+    pub total_treasury_fees: u64,
+    pub total_treasury_withdrawn: u64,
+    pub reserve_fee_share: u64,
+    pub reserve_amount: u64,
+
+    // Limits on the eUSD minted by swaps.
+    pub debt_ceiling: u64,
+    pub mint_cap_per_epoch: u64,
+    pub mint_epoch_nanos: u64,
+    // The eUSD minted by swaps keyed by swap timestamp, pruned
+    // to the last epoch.
+    pub recent_mints: BTreeMap<u64, u64>,
+
+    // Collateral ratio thresholds e8s, see `ProtocolRegime`.
+    pub min_collateral_ratio: u64,
+    pub recovery_collateral_ratio: u64,
+    pub recovery_fee_surcharge: u64,
+
+    /// Sets the fee rate of swaps from the collateral and covered ratios.
+    pub swap_fee_curves: SwapFeeCurves,
+
+    pub global_settlement: Option<GlobalSettlement>,
+
     pub mode: Mode,
 
     // Min Amounts
@@ -218,15 +382,30 @@ pub struct CoreState {
     pub min_amount_leverage: u64,
     pub min_amount_liquidity: u64,
 
-    // List of all the recorded icp prices.
-    //
+    /// Maps the collateral ratio to the fraction of
+    /// liquidity paid out on removal.
+    pub liquidity_haircut_curve: PiecewiseLinearCurve,
+
+    // The recorded icp prices of the retention window,
+    // see `price::PRICE_RETENTION_NANOS`.
     pub icp_prices: BTreeMap<Timestamp, IcpPrice>,
+    // The recorded prices of each registered collateral.
+    pub collateral_prices: BTreeMap<String, BTreeMap<Timestamp, IcpPrice>>,
+    // Whether a `PriceUpdate` was recorded, the prices are then only
+    // taken from these events. The older events carry the prices they
+    // were applied at, the fetched prices were not recorded.
+    pub has_price_updates: bool,
 
     /// Guards
     pub is_timer_running: bool,
     pub liquidity_principals_lock: BTreeSet<Principal>,
     pub leverage_principals_lock: BTreeSet<Principal>,
     pub convert_principals_lock: BTreeSet<Principal>,
+    pub balance_principals_lock: BTreeSet<Principal>,
+    pub treasury_principals_lock: BTreeSet<Principal>,
+    // The eUSD reserved by the swaps minting it while their deposit is
+    // pulled, keyed by caller.
+    pub mint_reservations: BTreeMap<Principal, u64>,
 }
 
 impl CoreState {
@@ -241,6 +420,19 @@ impl CoreState {
             min_amount_leverage,
             min_amount_liquidity,
             mode,
+            liquidity_haircut_curve,
+            collaterals,
+            debt_ceiling,
+            mint_cap_per_epoch,
+            mint_epoch_nanos,
+            min_collateral_ratio,
+            recovery_collateral_ratio,
+            recovery_fee_surcharge,
+            swap_fee_curves,
+            insurance_fund_fee_share,
+            treasury_fee_share,
+            reserve_fee_share,
+            archive_principal,
         }: InitArgs,
     ) {
         self.mode = mode;
@@ -255,29 +447,387 @@ impl CoreState {
             min_amount_from_stable.unwrap_or(DEFAULT_MIN_AMOUNT_FROM_STABLE);
         self.min_amount_leverage = min_amount_leverage.unwrap_or(DEFAULT_MIN_AMOUNT_LEVERAGE);
         self.min_amount_liquidity = min_amount_liquidity.unwrap_or(DEFAULT_MIN_AMOUNT_LIQUIDITY);
+        self.liquidity_haircut_curve = liquidity_haircut_curve.unwrap_or(
+            PiecewiseLinearCurve::new(DEFAULT_LIQUIDITY_HAIRCUT_CURVE.to_vec()),
+        );
+        self.register_collaterals(collaterals.unwrap_or_default());
+        self.debt_ceiling = debt_ceiling.unwrap_or(u64::MAX);
+        self.mint_cap_per_epoch = mint_cap_per_epoch.unwrap_or(u64::MAX);
+        self.mint_epoch_nanos = mint_epoch_nanos.unwrap_or(DEFAULT_MINT_EPOCH_NANOS);
+        self.min_collateral_ratio = min_collateral_ratio.unwrap_or(0);
+        self.recovery_collateral_ratio = recovery_collateral_ratio.unwrap_or(0);
+        self.recovery_fee_surcharge =
+            recovery_fee_surcharge.unwrap_or(DEFAULT_RECOVERY_FEE_SURCHARGE);
+        self.swap_fee_curves =
+            swap_fee_curves.unwrap_or_else(|| SwapFeeCurves::flat(self.fees.base_fee));
+        self.insurance_fund_fee_share = insurance_fund_fee_share.unwrap_or(0);
+        self.treasury_fee_share = treasury_fee_share.unwrap_or(0);
+        self.reserve_fee_share = reserve_fee_share.unwrap_or(0);
+        self.archive_principal = archive_principal;
     }
 
-    pub fn upgrade(&mut self, UpgradeArgs {}: UpgradeArgs) {}
+    pub fn upgrade(
+        &mut self,
+        UpgradeArgs {
+            liquidity_haircut_curve,
+            collaterals,
+            debt_ceiling,
+            mint_cap_per_epoch,
+            mint_epoch_nanos,
+            min_collateral_ratio,
+            recovery_collateral_ratio,
+            recovery_fee_surcharge,
+            swap_fee_curves,
+            insurance_fund_fee_share,
+            treasury_fee_share,
+            reserve_fee_share,
+            archive_principal,
+            // Recorded as a separate event once the state is replayed.
+            start_global_settlement: _,
+        }: UpgradeArgs,
+    ) {
+        if let Some(curve) = liquidity_haircut_curve {
+            self.liquidity_haircut_curve = curve;
+        }
+        self.register_collaterals(collaterals.unwrap_or_default());
+        if let Some(debt_ceiling) = debt_ceiling {
+            self.debt_ceiling = debt_ceiling;
+        }
+        if let Some(mint_cap_per_epoch) = mint_cap_per_epoch {
+            self.mint_cap_per_epoch = mint_cap_per_epoch;
+        }
+        if let Some(mint_epoch_nanos) = mint_epoch_nanos {
+            self.mint_epoch_nanos = mint_epoch_nanos;
+        }
+        if let Some(min_collateral_ratio) = min_collateral_ratio {
+            self.min_collateral_ratio = min_collateral_ratio;
+        }
+        if let Some(recovery_collateral_ratio) = recovery_collateral_ratio {
+            self.recovery_collateral_ratio = recovery_collateral_ratio;
+        }
+        if let Some(recovery_fee_surcharge) = recovery_fee_surcharge {
+            self.recovery_fee_surcharge = recovery_fee_surcharge;
+        }
+        if let Some(swap_fee_curves) = swap_fee_curves {
+            self.swap_fee_curves = swap_fee_curves;
+        }
+        if let Some(insurance_fund_fee_share) = insurance_fund_fee_share {
+            self.insurance_fund_fee_share = insurance_fund_fee_share;
+        }
+        if let Some(treasury_fee_share) = treasury_fee_share {
+            self.treasury_fee_share = treasury_fee_share;
+        }
+        if let Some(reserve_fee_share) = reserve_fee_share {
+            self.reserve_fee_share = reserve_fee_share;
+        }
+        if archive_principal.is_some() {
+            self.archive_principal = archive_principal;
+        }
+    }
+
+    /// Adds the given collaterals to the registry, replacing the
+    /// configuration of the already registered ones.
+    pub fn register_collaterals(&mut self, collaterals: Vec<CollateralConfig>) {
+        for collateral in collaterals {
+            self.collaterals
+                .insert(collateral.symbol.clone(), collateral);
+        }
+    }
+
+    pub fn get_collateral(&self, symbol: &str) -> Option<&CollateralConfig> {
+        self.collaterals.get(symbol)
+    }
+
+    /// Returns the number of decimals of a collateral asset, eUSD and ICP have 8.
+    pub fn get_decimals(&self, asset: &Asset) -> u32 {
+        match asset {
+            Asset::ICP | Asset::EUSD => 8,
+            Asset::Collateral(symbol) => {
+                self.get_collateral(symbol)
+                    .expect("bug: unknown collateral")
+                    .decimals
+            }
+        }
+    }
+
+    pub fn get_last_price(&self, asset: &Asset) -> Option<IcpPrice> {
+        match asset {
+            Asset::ICP => self.get_last_icp_price(),
+            Asset::EUSD => Some(IcpPrice { rate: crate::E8S }),
+            Asset::Collateral(symbol) => self
+                .collateral_prices
+                .get(symbol)
+                .and_then(|prices| prices.iter().next_back())
+                .map(|entry| entry.1.clone()),
+        }
+    }
+
+    pub fn apply_price_update(&mut self, update: &PriceUpdate) {
+        self.has_price_updates = true;
+        self.insert_price(
+            &update.asset,
+            update.timestamp,
+            IcpPrice { rate: update.rate },
+        );
+    }
+
+    pub fn insert_price(&mut self, asset: &Asset, timestamp_nanos: u64, price: IcpPrice) {
+        let prices = match asset {
+            Asset::ICP => &mut self.icp_prices,
+            Asset::EUSD => panic!("bug: eUSD has no price history"),
+            Asset::Collateral(symbol) => self.collateral_prices.entry(symbol.clone()).or_default(),
+        };
+        prices.insert(Timestamp { timestamp_nanos }, price);
+        crate::price::prune_prices(prices);
+    }
+
+    /// Returns the time-weighted average price of `asset` in `[from, to)`,
+    /// the frozen price during a global settlement.
+    pub fn get_twap(&self, asset: &Asset, from: u64, to: u64) -> Option<IcpPrice> {
+        use crate::price::time_weighted_average;
 
-    pub fn finish_swap(&mut self, from_block_index: u64) {
-        if let Some(swap_to_remove) = self.open_swaps.remove(&from_block_index) {
+        match asset {
+            Asset::ICP => {
+                if let Some(settlement) = &self.global_settlement {
+                    return Some(settlement.icp_price.clone());
+                }
+            }
+            Asset::EUSD => return Some(IcpPrice { rate: crate::E8S }),
+            Asset::Collateral(_) => {}
+        }
+        time_weighted_average(self.get_price_history(asset)?, from, to)
+            .map(|rate| IcpPrice { rate })
+    }
+
+    /// Returns the average price of `asset` over the [TWAP_WINDOW_NANOS]
+    /// before `now`, the latest price if none is that old.
+    ///
+    /// [TWAP_WINDOW_NANOS]: crate::price::TWAP_WINDOW_NANOS
+    pub fn get_average_price(&self, asset: &Asset, now: u64) -> Option<IcpPrice> {
+        self.get_twap(
+            asset,
+            now.saturating_sub(crate::price::TWAP_WINDOW_NANOS),
+            now,
+        )
+        .or_else(|| self.get_last_price(asset))
+    }
+
+    /// Returns the recorded prices of `asset`, eUSD has none.
+    pub fn get_price_history(&self, asset: &Asset) -> Option<&BTreeMap<Timestamp, IcpPrice>> {
+        match asset {
+            Asset::ICP => Some(&self.icp_prices),
+            Asset::EUSD => None,
+            Asset::Collateral(symbol) => self.collateral_prices.get(symbol),
+        }
+    }
+
+    pub fn get_collateral_amount(&self, symbol: &str) -> u64 {
+        self.collateral_amounts.get(symbol).cloned().unwrap_or(0)
+    }
+
+    /// Returns the amount of a collateral not reserved by open swaps to it.
+    pub fn get_available_collateral_amount(&self, symbol: &str) -> u64 {
+        let asset = Asset::Collateral(symbol.to_string());
+        let reserved: u64 = self
+            .open_swaps
+            .values()
+            .filter(|swap| swap.to == asset)
+            .map(|swap| self.get_swap_output_amount(swap))
+            .sum();
+        self.get_collateral_amount(symbol).saturating_sub(reserved)
+    }
+
+    /// Returns the value in eUSD (e8s) of a registered collateral,
+    /// 0 if no price was recorded yet.
+    pub fn get_collateral_value(&self, symbol: &str) -> u64 {
+        let asset = Asset::Collateral(symbol.to_string());
+        match self.get_last_price(&asset) {
+            Some(price) => to_value_e8s(
+                self.get_collateral_amount(symbol),
+                price.rate,
+                self.get_decimals(&asset),
+            ),
+            None => 0,
+        }
+    }
+
+    /// Returns the value in eUSD (e8s) of all the collaterals besides ICP.
+    pub fn get_collaterals_value(&self) -> u64 {
+        self.collaterals
+            .keys()
+            .map(|symbol| self.get_collateral_value(symbol))
+            .sum()
+    }
+
+    pub fn get_collaterals_status(&self) -> Vec<CollateralStatus> {
+        self.collaterals
+            .values()
+            .map(|collateral| CollateralStatus {
+                symbol: collateral.symbol.clone(),
+                ledger_principal: collateral.ledger_principal,
+                amount: self.get_collateral_amount(&collateral.symbol),
+                price: self
+                    .get_last_price(&Asset::Collateral(collateral.symbol.clone()))
+                    .map(|price| price.rate)
+                    .unwrap_or(0),
+                value: self.get_collateral_value(&collateral.symbol),
+            })
+            .collect()
+    }
+
+    fn credit_collateral(&mut self, asset: &Asset, amount: u64) {
+        match asset {
+            Asset::ICP => {
+                self.icp_collateral_amount = self
+                    .icp_collateral_amount
+                    .checked_add(amount)
+                    .expect("bug: crediting more than u64::MAX ICP");
+            }
+            Asset::EUSD => panic!("bug: eUSD is not a collateral"),
+            Asset::Collateral(symbol) => {
+                let collateral_amount = self.collateral_amounts.entry(symbol.clone()).or_insert(0);
+                *collateral_amount = collateral_amount
+                    .checked_add(amount)
+                    .expect("bug: crediting more than u64::MAX of the collateral");
+            }
+        }
+    }
+
+    fn debit_collateral(&mut self, asset: &Asset, amount: u64) {
+        match asset {
+            Asset::ICP => {
+                if amount > self.icp_collateral_amount {
+                    self.cover_collateral_shortfall(amount - self.icp_collateral_amount);
+                }
+                self.icp_collateral_amount = self.icp_collateral_amount.saturating_sub(amount);
+            }
+            Asset::EUSD => panic!("bug: eUSD is not a collateral"),
+            Asset::Collateral(symbol) => {
+                let collateral_amount = self
+                    .collateral_amounts
+                    .get_mut(symbol)
+                    .expect("bug: debiting an empty collateral");
+                assert!(
+                    *collateral_amount >= amount,
+                    "bug: debiting more than the collateral"
+                );
+                *collateral_amount -= amount;
+                if *collateral_amount == 0 {
+                    self.collateral_amounts.remove(symbol);
+                }
+            }
+        }
+    }
+
+    /// Returns the amount of the output asset of `swap`.
+    pub fn get_swap_output_amount(&self, swap: &Swap) -> u64 {
+        let amount = swap.from_amount - swap.fee;
+        if swap.from == Asset::EUSD {
+            from_value_e8s(amount, swap.rate, self.get_decimals(&swap.to))
+        } else {
+            to_value_e8s(amount, swap.rate, self.get_decimals(&swap.from))
+        }
+    }
+
+    /// Returns the fee of `swap` in units of its collateral, the fees of
+    /// redemptions being paid in eUSD.
+    pub fn get_swap_fee_in_collateral(&self, swap: &Swap) -> u64 {
+        if swap.from == Asset::EUSD {
+            from_value_e8s(swap.fee, swap.rate, self.get_decimals(&swap.to))
+        } else {
+            swap.fee
+        }
+    }
+
+    /// Fees of ICP swaps are distributed in ICP, the ICP kept by a
+    /// redemption is taken out of the collateral. The split pays out ICP
+    /// only, so the fees of other collaterals stay in their bucket, backing
+    /// the eUSD.
+    pub fn open_swap(&mut self, swap: Swap) {
+        if *swap.collateral() == Asset::ICP {
+            let fee = self.get_swap_fee_in_collateral(&swap);
+            if swap.from == Asset::EUSD {
+                self.debit_collateral(&Asset::ICP, fee);
+            }
+            self.distribute_fee(fee);
+        }
+        if swap.to == Asset::EUSD {
+            let minted_amount = self.get_swap_output_amount(&swap);
+            self.record_mint(swap.timestamp, minted_amount);
+        }
+        self.open_swaps
+            .insert((swap.from.clone(), swap.from_block_index), swap);
+    }
+
+    fn record_mint(&mut self, timestamp: u64, amount: u64) {
+        let epoch_start = timestamp.saturating_sub(self.mint_epoch_nanos);
+        self.recent_mints = self.recent_mints.split_off(&(epoch_start + 1));
+        *self.recent_mints.entry(timestamp).or_insert(0) += amount;
+    }
+
+    /// Returns the eUSD minted by swaps during the epoch ending at `now`.
+    pub fn get_epoch_minted_amount(&self, now: u64) -> u64 {
+        let epoch_start = now.saturating_sub(self.mint_epoch_nanos);
+        self.recent_mints
+            .range(epoch_start + 1..)
+            .map(|(_, amount)| amount)
+            .sum()
+    }
+
+    /// Returns the outstanding eUSD including the mints of open swaps.
+    pub fn get_outstanding_eusd(&self) -> u64 {
+        let pending_mints: u64 = self
+            .open_swaps
+            .values()
+            .filter(|swap| swap.to == Asset::EUSD)
+            .map(|swap| self.get_swap_output_amount(swap))
+            .sum();
+        self.total_eusd_minted
+            .saturating_sub(self.total_eusd_burned)
+            + pending_mints
+    }
+
+    /// Returns the eUSD reserved by the swaps waiting for their deposit.
+    pub fn get_reserved_mint_amount(&self) -> u64 {
+        self.mint_reservations.values().sum()
+    }
+
+    /// Returns the amount of eUSD that swaps can still mint at `now`,
+    /// the reserved mints included.
+    pub fn get_remaining_mint_capacity(&self, now: u64) -> u64 {
+        let reserved = self.get_reserved_mint_amount();
+        let debt_capacity = self
+            .debt_ceiling
+            .saturating_sub(self.get_outstanding_eusd().saturating_add(reserved));
+        let epoch_capacity = self
+            .mint_cap_per_epoch
+            .saturating_sub(self.get_epoch_minted_amount(now).saturating_add(reserved));
+        debt_capacity.min(epoch_capacity)
+    }
+
+    pub fn finish_swap(&mut self, from: &Asset, from_block_index: u64) {
+        if let Some(swap_to_remove) = self.open_swaps.remove(&(from.clone(), from_block_index)) {
+            let amount_out = self.get_swap_output_amount(&swap_to_remove);
             if swap_to_remove.from == Asset::EUSD {
                 self.total_eusd_burned += swap_to_remove.from_amount;
-                let deposited_amount = swap_to_remove.from_amount - swap_to_remove.fee;
-                let transfered_icp = crate::divide_e8s(deposited_amount, swap_to_remove.rate);
-                self.icp_collateral_amount -= transfered_icp;
+                self.debit_collateral(&swap_to_remove.to, amount_out);
             } else {
-                let minted_eusd = multiply_e8s(
-                    swap_to_remove.from_amount - swap_to_remove.fee,
-                    swap_to_remove.rate,
-                );
-                self.icp_collateral_amount += swap_to_remove.from_amount - swap_to_remove.fee;
-                self.total_eusd_minted += minted_eusd;
+                let credited_amount = if swap_to_remove.from == Asset::ICP {
+                    swap_to_remove.from_amount - swap_to_remove.fee
+                } else {
+                    swap_to_remove.from_amount
+                };
+                self.credit_collateral(&swap_to_remove.from, credited_amount);
+                self.total_eusd_minted += amount_out;
             }
         }
     }
 
+    /// Returns the frozen price during a global settlement.
     pub fn get_last_icp_price(&self) -> Option<IcpPrice> {
+        if let Some(settlement) = &self.global_settlement {
+            return Some(settlement.icp_price.clone());
+        }
         self.icp_prices
             .iter()
             .next_back()
@@ -295,17 +845,120 @@ impl CoreState {
     }
 
     pub fn get_tvl(&self) -> u64 {
+        // The ICP is worth nothing until its first price is fetched.
+        let icp_rate = self.get_last_icp_price().map_or(0, |price| price.rate);
         multiply_e8s(
             self.icp_collateral_amount + self.icp_leverage_margin_amount + self.icp_liqudity_amount,
-            self.get_last_icp_price().unwrap().rate,
-        )
+            icp_rate,
+        ) + self.get_collaterals_value()
     }
 
+    /// Returns the status served by `get_protocol_status` at `now`.
+    pub fn get_protocol_status(&self, now: u64) -> ProtocolStatus {
+        ProtocolStatus {
+            collateral_ratio: self.get_collateral_ratio(),
+            coverered_ratio: self.get_coverered_ratio(),
+            icp_price: self
+                .get_last_icp_price()
+                .unwrap_or(IcpPrice { rate: 0 })
+                .rate,
+            tvl: self.get_tvl(),
+            coverable_amount: self.get_leverage_coverable_amount(),
+            collaterals: self.get_collaterals_status(),
+            remaining_mint_capacity: self.get_remaining_mint_capacity(now),
+            regime: self.get_regime(),
+            global_settlement: self.global_settlement.clone(),
+            timestamp: now,
+        }
+    }
+
+    /// Splits `fee` between the insurance fund, which repays the bad debt
+    /// first, the treasury, the reserve and the liquidity providers.
     pub fn distribute_fee(&mut self, fee: u64) {
-        self.total_available_fees += fee;
+        let insurance_fee = multiply_e8s(fee, self.insurance_fund_fee_share);
+        let treasury_fee = multiply_e8s(fee, self.treasury_fee_share);
+        let reserve_fee = multiply_e8s(fee, self.reserve_fee_share);
+        let repaid_debt = insurance_fee.min(self.bad_debt);
+        self.bad_debt -= repaid_debt;
+        self.icp_collateral_amount += repaid_debt;
+        self.insurance_fund_amount += insurance_fee - repaid_debt;
+        self.treasury_amount += treasury_fee;
+        self.total_treasury_fees += treasury_fee;
+        self.reserve_amount += reserve_fee;
+        self.total_available_fees += fee - insurance_fee - treasury_fee - reserve_fee;
         crate::updates::liquidity::distribute_protocol_rewards(self);
     }
 
+    pub fn withdraw_treasury(&mut self, amount: u64) {
+        assert!(
+            amount <= self.treasury_amount,
+            "bug: withdrawing more than the treasury"
+        );
+        self.treasury_amount -= amount;
+        self.total_treasury_withdrawn += amount;
+    }
+
+    /// The shares of the fees can be upgraded separately, their sum is
+    /// checked once the upgrade is applied.
+    pub fn check_fee_shares(&self) -> Result<(), String> {
+        let total_share =
+            self.insurance_fund_fee_share + self.treasury_fee_share + self.reserve_fee_share;
+        if total_share > crate::E8S {
+            return Err(format!(
+                "the fee shares must not exceed 100%, got {}",
+                total_share
+            ));
+        }
+        Ok(())
+    }
+
+    /// The fee curves and the recovery surcharge can be upgraded
+    /// separately, their sum is checked once the upgrade is applied.
+    pub fn check_swap_fee_rate(&self) -> Result<(), String> {
+        crate::updates::swap::validate_swap_fee_rate(
+            &self.swap_fee_curves,
+            self.recovery_fee_surcharge,
+        )
+    }
+
+    /// The collateral ratios can be upgraded separately, their order is
+    /// checked once the upgrade is applied.
+    pub fn check_collateral_ratios(&self) -> Result<(), String> {
+        if self.recovery_collateral_ratio < self.min_collateral_ratio {
+            return Err(format!(
+                "the recovery collateral ratio must not be lower than the minimum one, got {} < {}",
+                self.recovery_collateral_ratio, self.min_collateral_ratio
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that depend on each other, once the init or
+    /// the upgrade args are applied.
+    pub fn check_settings(&self) -> Result<(), String> {
+        self.check_fee_shares()?;
+        self.check_swap_fee_rate()?;
+        self.check_collateral_ratios()?;
+        if self.mint_epoch_nanos == 0 {
+            return Err("the mint epoch must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the ICP the protocol accounts for, which its ledger
+    /// balance must cover.
+    pub fn get_known_icp_balance(&self) -> u64 {
+        self.icp_collateral_amount
+            + self.icp_liqudity_amount
+            + self.icp_leverage_margin_amount
+            + self.insurance_fund_amount
+            + self.treasury_amount
+            + self.reserve_amount
+            + self.total_available_fees
+            + self.liquidity_rewards.values().sum::<u64>()
+            + self.icp_balances.values().sum::<u64>()
+    }
+
     pub fn get_total_leverage_amount(&self) -> u64 {
         self.leverage_positions
             .values()
@@ -319,14 +972,6 @@ impl CoreState {
     }
 
     pub fn get_collateral_ratio(&self) -> u64 {
-        let core_tvl_e8s = read_state(|s| {
-            multiply_e8s(
-                self.icp_collateral_amount
-                    + self.icp_leverage_margin_amount
-                    + self.icp_liqudity_amount,
-                s.get_last_icp_price().unwrap().rate,
-            )
-        });
         let diff = self
             .total_eusd_minted
             .saturating_sub(self.total_eusd_burned);
@@ -335,11 +980,54 @@ impl CoreState {
             // The CR is inifinite.
             return u64::MAX;
         }
-        divide_e8s(
-            core_tvl_e8s,
-            self.total_eusd_minted
-                .saturating_sub(self.total_eusd_burned),
-        )
+        divide_e8s(self.get_tvl(), diff)
+    }
+
+    /// Returns the collateral ratio once the reserved mints land, their
+    /// eUSD being backed one for one by the deposits still being pulled.
+    pub fn get_collateral_ratio_with_reserved_mints(&self) -> u64 {
+        self.get_collateral_ratio_after_mint(0)
+    }
+
+    /// Returns the collateral ratio once the reserved mints and a mint
+    /// of `eusd_amount` land.
+    pub fn get_collateral_ratio_after_mint(&self, eusd_amount: u64) -> u64 {
+        let pending = self.get_reserved_mint_amount() + eusd_amount;
+        let diff = self
+            .total_eusd_minted
+            .saturating_sub(self.total_eusd_burned)
+            + pending;
+        if diff == 0 {
+            return u64::MAX;
+        }
+        divide_e8s(self.get_tvl() + pending, diff)
+    }
+
+    pub fn get_regime(&self) -> ProtocolRegime {
+        let collateral_ratio = self.get_collateral_ratio();
+        if collateral_ratio < self.min_collateral_ratio {
+            ProtocolRegime::Critical
+        } else if collateral_ratio < self.recovery_collateral_ratio {
+            ProtocolRegime::Recovery
+        } else {
+            ProtocolRegime::Normal
+        }
+    }
+
+    /// Returns the fee rate (e8s) of swaps in `direction` given by the fee
+    /// curves, raised outside of the normal regime.
+    pub fn get_swap_fee_rate(&self, direction: SwapDirection) -> u64 {
+        let fee_rate = self.swap_fee_curves.fee_rate(
+            direction,
+            self.get_collateral_ratio(),
+            self.get_coverered_ratio(),
+        );
+        match self.get_regime() {
+            ProtocolRegime::Normal => fee_rate,
+            ProtocolRegime::Recovery | ProtocolRegime::Critical => {
+                fee_rate + self.recovery_fee_surcharge
+            }
+        }
     }
 
     pub fn get_coverered_ratio(&self) -> u64 {
@@ -353,13 +1041,44 @@ impl CoreState {
     }
 
     pub fn add_liquidity(&mut self, liquidity: &Liquidity) {
-        let liquidity_to_add = liquidity.amount - liquidity.fee;
-        self.icp_liqudity_amount += liquidity.amount - liquidity.fee;
-        if let Some(entry_mut) = self.liquidity_provided.get_mut(&liquidity.caller) {
-            *entry_mut += liquidity_to_add;
+        self.credit_liquidity(liquidity.caller, liquidity.amount - liquidity.fee);
+    }
+
+    pub fn credit_liquidity(&mut self, owner: Principal, amount: u64) {
+        self.icp_liqudity_amount += amount;
+        if let Some(entry_mut) = self.liquidity_provided.get_mut(&owner) {
+            *entry_mut += amount;
         } else {
-            self.liquidity_provided
-                .insert(liquidity.caller, liquidity_to_add);
+            self.liquidity_provided.insert(owner, amount);
+        }
+    }
+
+    /// Moves `amount` of the rewards of `owner` into its provided liquidity.
+    pub fn compound_liquidity_rewards(&mut self, owner: Principal, amount: u64) {
+        self.debit_liquidity_rewards(owner, amount);
+        self.credit_liquidity(owner, amount);
+    }
+
+    /// Removes `amount` from the claimable rewards of `owner`, the rewards
+    /// distributed while a claim was in flight stay claimable.
+    pub fn debit_liquidity_rewards(&mut self, owner: Principal, amount: u64) {
+        match self.liquidity_rewards.get_mut(&owner) {
+            Some(rewards) => {
+                debug_assert!(*rewards >= amount);
+                *rewards -= amount;
+                if *rewards == 0 {
+                    self.liquidity_rewards.remove(&owner);
+                }
+            }
+            None => debug_assert_eq!(amount, 0),
+        }
+    }
+
+    pub fn set_auto_compound(&mut self, owner: Principal, enabled: bool) {
+        if enabled {
+            self.liquidity_auto_compound.insert(owner);
+        } else {
+            self.liquidity_auto_compound.remove(&owner);
         }
     }
 
@@ -374,6 +1093,85 @@ impl CoreState {
         }
     }
 
+    pub fn queue_liquidity_withdrawal(&mut self, withdrawal: LiquidityWithdrawal) {
+        debug_assert!(withdrawal.id >= self.next_liquidity_withdrawal_id);
+        self.next_liquidity_withdrawal_id = withdrawal.id + 1;
+        self.liquidity_withdrawal_queue
+            .insert(withdrawal.id, withdrawal);
+    }
+
+    pub fn remove_liquidity_withdrawal(&mut self, id: u64) -> Option<LiquidityWithdrawal> {
+        self.liquidity_withdrawal_queue.remove(&id)
+    }
+
+    /// Returns the amount of liquidity of `owner` reserved by queued withdrawals.
+    pub fn get_pending_withdrawal_amount(&self, owner: &Principal) -> u64 {
+        self.liquidity_withdrawal_queue
+            .values()
+            .filter(|w| &w.owner == owner)
+            .map(|w| w.amount)
+            .sum()
+    }
+
+    pub fn get_liquidity_withdrawals_of(&self, owner: &Principal) -> Vec<LiquidityWithdrawal> {
+        self.liquidity_withdrawal_queue
+            .values()
+            .filter(|w| &w.owner == owner)
+            .cloned()
+            .collect()
+    }
+
+    fn balances_mut(&mut self, asset: &Asset) -> &mut BTreeMap<Principal, u64> {
+        match asset {
+            Asset::ICP => &mut self.icp_balances,
+            Asset::EUSD => &mut self.eusd_balances,
+            Asset::Collateral(_) => panic!("bug: collaterals have no internal balances"),
+        }
+    }
+
+    pub fn get_balance(&self, owner: &Principal, asset: &Asset) -> u64 {
+        let balances = match asset {
+            Asset::ICP => &self.icp_balances,
+            Asset::EUSD => &self.eusd_balances,
+            // Collaterals cannot be held in internal balances.
+            Asset::Collateral(_) => return 0,
+        };
+        balances.get(owner).cloned().unwrap_or(0)
+    }
+
+    pub fn credit_balance(&mut self, owner: Principal, asset: &Asset, amount: u64) {
+        *self.balances_mut(asset).entry(owner).or_insert(0) += amount;
+    }
+
+    pub fn debit_balance(&mut self, owner: Principal, asset: &Asset, amount: u64) {
+        let balances = self.balances_mut(asset);
+        match balances.get_mut(&owner) {
+            Some(balance) => {
+                assert!(*balance >= amount, "bug: debiting more than the balance");
+                *balance -= amount;
+                if *balance == 0 {
+                    balances.remove(&owner);
+                }
+            }
+            None => assert_eq!(amount, 0, "bug: debiting an empty balance"),
+        }
+    }
+
+    /// Returns a new block index for an operation that does not go through a ledger.
+    pub fn allocate_internal_block_index(&mut self) -> u64 {
+        let block_index = INTERNAL_BLOCK_INDEX_OFFSET + self.next_internal_block_index;
+        self.next_internal_block_index += 1;
+        block_index
+    }
+
+    pub fn set_internal_balance_payouts(&mut self, owner: Principal, enabled: bool) {
+        if enabled {
+            self.internal_balance_payouts.insert(owner);
+        } else {
+            self.internal_balance_payouts.remove(&owner);
+        }
+    }
+
     pub fn open_leverage_position(&mut self, leverage_position: LeveragePosition) {
         self.icp_collateral_covered_amount += leverage_position.covered_amount;
         debug_assert!(leverage_position.amount >= leverage_position.fee);
@@ -408,10 +1206,11 @@ impl CoreState {
             self.icp_collateral_covered_amount -= leverage_position.covered_amount;
             debug_assert!(amount_to_transfer + protocol_fee <= self.icp_leverage_margin_amount);
             if amount_to_transfer > leverage_position.amount {
-                debug_assert!(
-                    self.icp_collateral_amount >= amount_to_transfer - leverage_position.amount
-                );
-                self.icp_collateral_amount -= amount_to_transfer - leverage_position.amount;
+                let profit = amount_to_transfer - leverage_position.amount;
+                if profit > self.icp_collateral_amount {
+                    self.cover_collateral_shortfall(profit - self.icp_collateral_amount);
+                }
+                self.icp_collateral_amount = self.icp_collateral_amount.saturating_sub(profit);
                 debug_assert!(self.icp_leverage_margin_amount >= leverage_position.amount);
                 self.icp_leverage_margin_amount -= leverage_position.amount;
                 ic_cdk::println!("Closing amount profit: {}", self.icp_leverage_margin_amount);
@@ -431,6 +1230,136 @@ impl CoreState {
             .remove(&leverage_position.deposit_block_index);
     }
 
+    /// Seizes the margin of a liquidated position into the collateral.
+    pub fn liquidate_leverage_position(
+        &mut self,
+        leverage_position: LeveragePosition,
+        last_icp_price: IcpPrice,
+    ) {
+        let margin = leverage_position.amount - leverage_position.fee;
+        match self.leverage_positions.get_mut(&leverage_position.owner) {
+            Some(user_positions) if user_positions.remove(&leverage_position) => {}
+            _ => panic!("Could not find block index in user's positions."),
+        }
+        self.icp_collateral_covered_amount -= leverage_position.covered_amount;
+        debug_assert!(self.icp_leverage_margin_amount >= margin);
+        self.icp_leverage_margin_amount -= margin;
+        self.icp_collateral_amount += margin;
+        self.cover_leverage_loss(&leverage_position, &last_icp_price);
+
+        self.block_index_to_owner
+            .remove(&leverage_position.deposit_block_index);
+    }
+
+    /// Covers the loss of a position exceeding its margin, which the
+    /// collateral can no longer recover from the position owner.
+    /// The liquidations recorded as closes without output block index
+    /// did not cover it.
+    fn cover_leverage_loss(
+        &mut self,
+        leverage_position: &LeveragePosition,
+        last_icp_price: &IcpPrice,
+    ) {
+        let pnl = crate::updates::leverage::compute_pnl(leverage_position, last_icp_price.rate);
+        let margin = leverage_position.amount - leverage_position.fee;
+        if pnl < 0 && pnl.unsigned_abs() > margin {
+            self.cover_collateral_shortfall(pnl.unsigned_abs() - margin);
+        }
+    }
+
+    /// Moves `shortfall` ICP into the collateral, drawing on the insurance
+    /// fund first, then on the reserve and on the liquidity providers
+    /// pro-rata. The part covered by none is recorded as bad debt.
+    pub fn cover_collateral_shortfall(&mut self, shortfall: u64) {
+        let from_fund = shortfall.min(self.insurance_fund_amount);
+        self.insurance_fund_amount -= from_fund;
+        let from_reserve = (shortfall - from_fund).min(self.reserve_amount);
+        self.reserve_amount -= from_reserve;
+        self.icp_collateral_amount += from_fund + from_reserve;
+        let shortfall = shortfall - from_fund - from_reserve;
+
+        let total_liquidity = self.icp_liqudity_amount as u128;
+        let socialized = (shortfall as u128).min(total_liquidity);
+        let mut from_liquidity = 0;
+        if socialized > 0 {
+            // Rounded up so that the liquidity providers cover the whole amount.
+            let losses: Vec<(Principal, u64)> = self
+                .liquidity_provided
+                .iter()
+                .map(|(owner, amount)| {
+                    let loss =
+                        (socialized * *amount as u128 + total_liquidity - 1) / total_liquidity;
+                    (*owner, loss as u64)
+                })
+                .collect();
+            for (owner, loss) in losses {
+                self.debit_liquidity_loss(owner, loss);
+                from_liquidity += loss;
+            }
+            self.icp_collateral_amount += from_liquidity;
+        }
+
+        self.bad_debt += shortfall.saturating_sub(from_liquidity);
+    }
+
+    /// Removes `loss` from the liquidity of `owner`, shrinking its queued
+    /// withdrawals from the newest so that they stay covered.
+    fn debit_liquidity_loss(&mut self, owner: Principal, loss: u64) {
+        let provided = self
+            .liquidity_provided
+            .get_mut(&owner)
+            .expect("bug: socializing a loss to an unknown liquidity provider");
+        debug_assert!(*provided >= loss);
+        *provided -= loss;
+        let remaining = *provided;
+        if remaining == 0 {
+            self.liquidity_provided.remove(&owner);
+        }
+        self.icp_liqudity_amount -= loss;
+
+        let mut excess = self
+            .get_pending_withdrawal_amount(&owner)
+            .saturating_sub(remaining);
+        let ids: Vec<u64> = self
+            .liquidity_withdrawal_queue
+            .values()
+            .rev()
+            .filter(|w| w.owner == owner)
+            .map(|w| w.id)
+            .collect();
+        for id in ids {
+            if excess == 0 {
+                break;
+            }
+            let withdrawal = self
+                .liquidity_withdrawal_queue
+                .get_mut(&id)
+                .expect("bug: missing queued withdrawal");
+            let trimmed = excess.min(withdrawal.amount);
+            withdrawal.amount -= trimmed;
+            excess -= trimmed;
+            if withdrawal.amount == 0 {
+                self.liquidity_withdrawal_queue.remove(&id);
+            }
+        }
+    }
+
+    /// Returns the ICP that eUSD redemptions can draw on, the collateral is
+    /// topped up by the insurance fund, the reserve and the liquidity if needed.
+    pub fn get_redeemable_icp_amount(&self) -> u64 {
+        let reserved: u64 = self
+            .open_swaps
+            .values()
+            .filter(|swap| swap.to == Asset::ICP)
+            .map(|swap| self.get_swap_output_amount(swap))
+            .sum();
+        (self.icp_collateral_amount
+            + self.insurance_fund_amount
+            + self.reserve_amount
+            + self.icp_liqudity_amount)
+            .saturating_sub(reserved)
+    }
+
     pub fn get_leverage_position(&self, deposit_block_index: u64) -> Option<LeveragePosition> {
         let owner = self.block_index_to_owner.get(&deposit_block_index).unwrap();
         if let Some(user_positions) = self.leverage_positions.get(owner) {
@@ -461,6 +1390,125 @@ impl CoreState {
             .saturating_sub(self.get_leverage_covered_amount())
     }
 
+    pub fn start_global_settlement(&mut self, icp_price: IcpPrice, timestamp: u64) {
+        self.global_settlement = Some(GlobalSettlement {
+            icp_price,
+            timestamp,
+            redemption_rate: None,
+            collateral_redemption_rates: BTreeMap::new(),
+        });
+    }
+
+    pub fn has_leverage_positions(&self) -> bool {
+        self.leverage_positions
+            .values()
+            .any(|positions| !positions.is_empty())
+    }
+
+    /// Returns the ICP price (e8s) at which eUSD is redeemed, the prices
+    /// of the other collaterals and the ICP left to liquidity providers
+    /// once every eUSD is redeemed.
+    ///
+    /// eUSD is redeemed at most 1$ against any collateral, at the frozen ICP
+    /// price and the last price of the other collaterals, pro-rata if they
+    /// do not cover the outstanding eUSD. The open swaps count as settled.
+    /// The other collaterals back eUSD before the ICP does.
+    pub fn compute_settlement_redemption(&self) -> (u64, BTreeMap<String, u64>, u64) {
+        let icp_price = self
+            .global_settlement
+            .as_ref()
+            .expect("bug: no global settlement")
+            .icp_price
+            .rate;
+        let outstanding = self.get_outstanding_eusd() as u128;
+        let icp_amount =
+            self.icp_collateral_amount + self.insurance_fund_amount + self.reserve_amount;
+        let collaterals_value = self.get_collaterals_value() as u128;
+        let total_value = crate::multiply_e8s(icp_amount, icp_price) as u128 + collaterals_value;
+        // Rounded up so that the redemptions never exceed the collaterals.
+        let redemption_rate = |price: u64| -> u64 {
+            if outstanding <= total_value {
+                price
+            } else if total_value == 0 {
+                u64::MAX
+            } else {
+                ((price as u128 * outstanding + total_value - 1) / total_value)
+                    .min(u64::MAX as u128) as u64
+            }
+        };
+        let collateral_redemption_rates = self
+            .collaterals
+            .keys()
+            .filter_map(|symbol| {
+                self.get_last_price(&Asset::Collateral(symbol.clone()))
+                    .map(|price| (symbol.clone(), redemption_rate(price.rate)))
+            })
+            .collect();
+
+        let e8s = crate::E8S as u128;
+        let icp_value_to_redeem = outstanding
+            .min(total_value)
+            .saturating_sub(collaterals_value);
+        let reserved_amount = if icp_price == 0 {
+            icp_amount
+        } else {
+            ((icp_value_to_redeem * e8s + icp_price as u128 - 1) / icp_price as u128)
+                .min(icp_amount as u128) as u64
+        };
+        (
+            redemption_rate(icp_price),
+            collateral_redemption_rates,
+            icp_amount - reserved_amount,
+        )
+    }
+
+    /// Opens the redemptions once all the leverage positions are closed and
+    /// credits `liquidity_surplus` to the liquidity providers pro-rata.
+    pub fn finalize_global_settlement(
+        &mut self,
+        redemption_rate: u64,
+        collateral_redemption_rates: BTreeMap<String, u64>,
+        liquidity_surplus: u64,
+    ) {
+        // The insurance fund and the reserve back eUSD, nothing is left to insure.
+        self.icp_collateral_amount += self.insurance_fund_amount + self.reserve_amount;
+        self.insurance_fund_amount = 0;
+        self.reserve_amount = 0;
+
+        let total_liquidity = self.get_total_liquidity_amount() as u128;
+        if total_liquidity > 0 {
+            let shares: Vec<(Principal, u64)> = self
+                .liquidity_provided
+                .iter()
+                .map(|(owner, amount)| {
+                    let share = liquidity_surplus as u128 * *amount as u128 / total_liquidity;
+                    (*owner, share as u64)
+                })
+                .collect();
+            for (owner, share) in shares {
+                self.icp_collateral_amount -= share;
+                self.credit_liquidity(owner, share);
+            }
+        }
+        let settlement = self
+            .global_settlement
+            .as_mut()
+            .expect("bug: no global settlement");
+        settlement.redemption_rate = Some(redemption_rate);
+        settlement.collateral_redemption_rates = collateral_redemption_rates;
+    }
+
+    /// Releases the guards of the requests which were in flight.
+    pub fn release_guards(&mut self) {
+        self.is_timer_running = false;
+        self.liquidity_principals_lock.clear();
+        self.leverage_principals_lock.clear();
+        self.convert_principals_lock.clear();
+        self.balance_principals_lock.clear();
+        self.treasury_principals_lock.clear();
+        self.mint_reservations.clear();
+    }
+
     /// Checks whether the internal state of the core canister matches the other state
     /// semantically (the state holds the same data, but maybe in a slightly
     /// different form).
@@ -480,6 +1528,11 @@ impl CoreState {
             other.xrc_principal,
             "xrc_principal does not match"
         );
+        ensure_eq!(
+            self.archive_principal,
+            other.archive_principal,
+            "archive_principal does not match"
+        );
         ensure_eq!(
             self.liquidity_provided,
             other.liquidity_provided,
@@ -488,7 +1541,22 @@ impl CoreState {
         ensure_eq!(
             self.liquidity_rewards,
             other.liquidity_rewards,
-            "finalized_requests do not match"
+            "liquidity_rewards does not match"
+        );
+        ensure_eq!(
+            self.liquidity_auto_compound,
+            other.liquidity_auto_compound,
+            "liquidity_auto_compound does not match"
+        );
+        ensure_eq!(
+            self.leverage_positions,
+            other.leverage_positions,
+            "leverage_positions does not match"
+        );
+        ensure_eq!(
+            self.block_index_to_owner,
+            other.block_index_to_owner,
+            "block_index_to_owner does not match"
         );
         ensure_eq!(self.fees, other.fees, "fees do not match");
         ensure_eq!(self.open_swaps, other.open_swaps, "open_swaps do not match");
@@ -532,10 +1600,199 @@ impl CoreState {
             other.total_available_fees,
             "total_available_fees does not match"
         );
+        ensure_eq!(
+            self.insurance_fund_fee_share,
+            other.insurance_fund_fee_share,
+            "insurance_fund_fee_share does not match"
+        );
+        ensure_eq!(
+            self.insurance_fund_amount,
+            other.insurance_fund_amount,
+            "insurance_fund_amount does not match"
+        );
+        ensure_eq!(self.bad_debt, other.bad_debt, "bad_debt does not match");
+        ensure_eq!(
+            self.treasury_fee_share,
+            other.treasury_fee_share,
+            "treasury_fee_share does not match"
+        );
+        ensure_eq!(
+            self.treasury_amount,
+            other.treasury_amount,
+            "treasury_amount does not match"
+        );
+        ensure_eq!(
+            self.total_treasury_fees,
+            other.total_treasury_fees,
+            "total_treasury_fees does not match"
+        );
+        ensure_eq!(
+            self.total_treasury_withdrawn,
+            other.total_treasury_withdrawn,
+            "total_treasury_withdrawn does not match"
+        );
+        ensure_eq!(
+            self.reserve_fee_share,
+            other.reserve_fee_share,
+            "reserve_fee_share does not match"
+        );
+        ensure_eq!(
+            self.reserve_amount,
+            other.reserve_amount,
+            "reserve_amount does not match"
+        );
+        ensure_eq!(
+            self.liquidity_withdrawal_queue,
+            other.liquidity_withdrawal_queue,
+            "liquidity_withdrawal_queue does not match"
+        );
+        ensure_eq!(
+            self.next_liquidity_withdrawal_id,
+            other.next_liquidity_withdrawal_id,
+            "next_liquidity_withdrawal_id does not match"
+        );
+        ensure_eq!(
+            self.liquidity_haircut_curve,
+            other.liquidity_haircut_curve,
+            "liquidity_haircut_curve does not match"
+        );
+        ensure_eq!(
+            self.icp_balances,
+            other.icp_balances,
+            "icp_balances does not match"
+        );
+        ensure_eq!(
+            self.eusd_balances,
+            other.eusd_balances,
+            "eusd_balances does not match"
+        );
+        ensure_eq!(
+            self.internal_balance_payouts,
+            other.internal_balance_payouts,
+            "internal_balance_payouts does not match"
+        );
+        ensure_eq!(
+            self.next_internal_block_index,
+            other.next_internal_block_index,
+            "next_internal_block_index does not match"
+        );
+        ensure_eq!(
+            self.collaterals,
+            other.collaterals,
+            "collaterals does not match"
+        );
+        ensure_eq!(
+            self.collateral_amounts,
+            other.collateral_amounts,
+            "collateral_amounts does not match"
+        );
+        ensure_eq!(
+            self.debt_ceiling,
+            other.debt_ceiling,
+            "debt_ceiling does not match"
+        );
+        ensure_eq!(
+            self.mint_cap_per_epoch,
+            other.mint_cap_per_epoch,
+            "mint_cap_per_epoch does not match"
+        );
+        ensure_eq!(
+            self.mint_epoch_nanos,
+            other.mint_epoch_nanos,
+            "mint_epoch_nanos does not match"
+        );
+        ensure_eq!(
+            self.recent_mints,
+            other.recent_mints,
+            "recent_mints does not match"
+        );
+        ensure_eq!(
+            self.min_collateral_ratio,
+            other.min_collateral_ratio,
+            "min_collateral_ratio does not match"
+        );
+        ensure_eq!(
+            self.recovery_collateral_ratio,
+            other.recovery_collateral_ratio,
+            "recovery_collateral_ratio does not match"
+        );
+        ensure_eq!(
+            self.recovery_fee_surcharge,
+            other.recovery_fee_surcharge,
+            "recovery_fee_surcharge does not match"
+        );
+        ensure_eq!(
+            self.swap_fee_curves,
+            other.swap_fee_curves,
+            "swap_fee_curves does not match"
+        );
+        ensure_eq!(
+            self.global_settlement,
+            other.global_settlement,
+            "global_settlement does not match"
+        );
         ensure_eq!(self.mode, other.mode, "mode do not match");
-        // TODO find a strategy to check the new ICP prices map.
-        // ensure_eq!(self.icp_prices, other.icp_prices, "icp_prices do not match");
+        ensure_eq!(
+            self.min_amount_to_stable,
+            other.min_amount_to_stable,
+            "min_amount_to_stable does not match"
+        );
+        ensure_eq!(
+            self.min_amount_from_stable,
+            other.min_amount_from_stable,
+            "min_amount_from_stable does not match"
+        );
+        ensure_eq!(
+            self.min_amount_leverage,
+            other.min_amount_leverage,
+            "min_amount_leverage does not match"
+        );
+        ensure_eq!(
+            self.min_amount_liquidity,
+            other.min_amount_liquidity,
+            "min_amount_liquidity does not match"
+        );
 
+        ensure_eq!(
+            self.has_price_updates,
+            other.has_price_updates,
+            "has_price_updates does not match"
+        );
+        if self.has_price_updates {
+            ensure_eq!(
+                self.icp_prices,
+                other.icp_prices,
+                "icp_prices does not match"
+            );
+            ensure_eq!(
+                self.collateral_prices,
+                other.collateral_prices,
+                "collateral_prices does not match"
+            );
+        } else {
+            // The fetched prices were not recorded in the event log, the
+            // replay only rebuilds the prices carried by the events. The
+            // prices held by both states at the same timestamp must match.
+            check_common_prices(&self.icp_prices, &other.icp_prices)
+                .map_err(|e| format!("icp_prices do not match: {}", e))?;
+            let symbols: BTreeSet<&String> = self
+                .collateral_prices
+                .keys()
+                .chain(other.collateral_prices.keys())
+                .collect();
+            let no_prices = BTreeMap::new();
+            for symbol in symbols {
+                check_common_prices(
+                    self.collateral_prices.get(symbol).unwrap_or(&no_prices),
+                    other.collateral_prices.get(symbol).unwrap_or(&no_prices),
+                )
+                .map_err(|e| format!("collateral_prices of {} do not match: {}", symbol, e))?;
+            }
+        }
+        // The ledger balance is not recorded in the event log,
+        // `run_audit` reconciles it with the ledger. The guards, the mint
+        // reservations and `is_timer_running` are transient: they are not
+        // derived from the events, a replayed state never holds them.
         Ok(())
     }
 
@@ -569,6 +1826,63 @@ impl CoreState {
             self.icp_leverage_margin_amount,
         );
 
+        self.check_settings()?;
+
+        ensure!(
+            self.total_treasury_withdrawn <= self.total_treasury_fees,
+            "The treasury withdrawals exceed its fees: withdrawn {}, fees: {}",
+            self.total_treasury_withdrawn,
+            self.total_treasury_fees,
+        );
+        ensure!(
+            self.treasury_amount == self.total_treasury_fees - self.total_treasury_withdrawn,
+            "Inconsistent treasury: tracked {}, fees: {}, withdrawn: {}",
+            self.treasury_amount,
+            self.total_treasury_fees,
+            self.total_treasury_withdrawn,
+        );
+
+        if let Some(settlement) = &self.global_settlement {
+            ensure!(
+                settlement.redemption_rate.is_none() || !self.has_leverage_positions(),
+                "Leverage positions are open after the global settlement closed them",
+            );
+        }
+
+        for (owner, amount) in self.liquidity_provided.iter() {
+            let pending = self.get_pending_withdrawal_amount(owner);
+            ensure!(
+                pending <= *amount,
+                "Queued withdrawals of {} exceed its liquidity: pending {}, provided: {}",
+                owner,
+                pending,
+                amount,
+            );
+        }
+
+        for balances in [&self.icp_balances, &self.eusd_balances] {
+            for (owner, balance) in balances.iter() {
+                ensure!(*balance > 0, "Empty internal balance entry for {}", owner);
+            }
+        }
+
+        for (symbol, amount) in self.collateral_amounts.iter() {
+            ensure!(
+                self.collaterals.contains_key(symbol),
+                "Collateral amount for unregistered collateral {}",
+                symbol
+            );
+            ensure!(*amount > 0, "Empty collateral amount entry for {}", symbol);
+        }
+
+        let eusd_balances: u64 = self.eusd_balances.values().sum();
+        ensure!(
+            eusd_balances <= self.total_eusd_minted - self.total_eusd_burned,
+            "Internal eusd balances exceed the eusd supply: balances {}, supply: {}",
+            eusd_balances,
+            self.total_eusd_minted - self.total_eusd_burned,
+        );
+
         Ok(())
     }
 }
@@ -611,9 +1925,33 @@ pub fn replace_state(state: CoreState) {
     });
 }
 
+/// Checks that the prices held at the same timestamp match.
+fn check_common_prices(
+    prices: &BTreeMap<Timestamp, IcpPrice>,
+    other_prices: &BTreeMap<Timestamp, IcpPrice>,
+) -> Result<(), String> {
+    for (timestamp, price) in other_prices.iter() {
+        if let Some(own_price) = prices.get(timestamp) {
+            ensure_eq!(
+                own_price,
+                price,
+                "the prices at {} differ",
+                timestamp.timestamp_nanos
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Default for CoreState {
+    fn default() -> Self {
+        Self::from(InitArgs::default())
+    }
+}
+
 impl From<InitArgs> for CoreState {
     fn from(args: InitArgs) -> Self {
-        Self {
+        let mut state = Self {
             eusd_ledger_principal: args
                 .eusd_ledger_principal
                 .unwrap_or(Principal::from_text(DEFAULT_EUSD_LEDGER_PRINCIPAL).unwrap()),
@@ -624,12 +1962,20 @@ impl From<InitArgs> for CoreState {
             xrc_principal: args
                 .xrc_principal
                 .unwrap_or(Principal::from_text(DEFAULT_XRC_PRINCIPAL).unwrap()),
+            archive_principal: args.archive_principal,
 
             /// All the positions of the last week
             liquidity_provided: Default::default(),
             liquidity_rewards: Default::default(),
+            liquidity_auto_compound: Default::default(),
             block_index_to_owner: Default::default(),
             leverage_positions: Default::default(),
+            liquidity_withdrawal_queue: Default::default(),
+            next_liquidity_withdrawal_id: 0,
+            icp_balances: Default::default(),
+            eusd_balances: Default::default(),
+            internal_balance_payouts: Default::default(),
+            next_internal_block_index: 0,
 
             mode: args.mode,
 
@@ -637,6 +1983,29 @@ impl From<InitArgs> for CoreState {
             total_eusd_minted: 0,
             total_eusd_burned: 0,
             total_available_fees: 0,
+            insurance_fund_fee_share: args.insurance_fund_fee_share.unwrap_or(0),
+            insurance_fund_amount: 0,
+            bad_debt: 0,
+            treasury_fee_share: args.treasury_fee_share.unwrap_or(0),
+            treasury_amount: 0,
+            total_treasury_fees: 0,
+            total_treasury_withdrawn: 0,
+            reserve_fee_share: args.reserve_fee_share.unwrap_or(0),
+            reserve_amount: 0,
+
+            debt_ceiling: args.debt_ceiling.unwrap_or(u64::MAX),
+            mint_cap_per_epoch: args.mint_cap_per_epoch.unwrap_or(u64::MAX),
+            mint_epoch_nanos: args.mint_epoch_nanos.unwrap_or(DEFAULT_MINT_EPOCH_NANOS),
+            recent_mints: Default::default(),
+            min_collateral_ratio: args.min_collateral_ratio.unwrap_or(0),
+            recovery_collateral_ratio: args.recovery_collateral_ratio.unwrap_or(0),
+            recovery_fee_surcharge: args
+                .recovery_fee_surcharge
+                .unwrap_or(DEFAULT_RECOVERY_FEE_SURCHARGE),
+            swap_fee_curves: args
+                .swap_fee_curves
+                .unwrap_or_else(|| SwapFeeCurves::flat(DEFAULT_BASE_FEE)),
+            global_settlement: None,
 
             min_amount_to_stable: args
                 .min_amount_to_stable
@@ -650,9 +2019,12 @@ impl From<InitArgs> for CoreState {
             min_amount_liquidity: args
                 .min_amount_liquidity
                 .unwrap_or(DEFAULT_MIN_AMOUNT_LIQUIDITY),
+            liquidity_haircut_curve: args.liquidity_haircut_curve.unwrap_or(
+                PiecewiseLinearCurve::new(DEFAULT_LIQUIDITY_HAIRCUT_CURVE.to_vec()),
+            ),
 
             fees: FeesPerAction {
-                base_fee: 250_000,
+                base_fee: DEFAULT_BASE_FEE,
                 liquidation_fee: 2_500_000,
                 stability_fee: 0,
             },
@@ -662,14 +2034,23 @@ impl From<InitArgs> for CoreState {
             icp_leverage_margin_amount: 0,
             icp_collateral_covered_amount: 0,
             protocol_balance: 0,
+            collaterals: Default::default(),
+            collateral_amounts: Default::default(),
             // List of all the recorded icp prices.
             icp_prices: Default::default(),
+            collateral_prices: Default::default(),
+            has_price_updates: false,
 
             // Init Guards
             is_timer_running: false,
             liquidity_principals_lock: Default::default(),
             leverage_principals_lock: Default::default(),
             convert_principals_lock: Default::default(),
-        }
+            balance_principals_lock: Default::default(),
+            treasury_principals_lock: Default::default(),
+            mint_reservations: Default::default(),
+        };
+        state.register_collaterals(args.collaterals.unwrap_or_default());
+        state
     }
 }
@@ -1,22 +1,32 @@
 use super::{eventlog::Event, LeveragePosition};
+use crate::price::PriceUpdate;
+use crate::state::Asset;
 use crate::state::CoreState;
 use crate::state::IcpPrice;
 use crate::storage::record_event;
-use crate::updates::liquidity::{Liquidity, LiquidityType};
+use crate::updates::liquidity::{Liquidity, LiquidityType, LiquidityWithdrawal};
 use crate::updates::swap::{Swap, SwapSuccess};
+use candid::Principal;
+use icrc_ledger_types::icrc1::account::Account;
+use std::collections::BTreeMap;
 
 pub fn record_swap(state: &mut CoreState, swap: Swap) {
     record_event(&Event::Swap(swap.clone()));
-    state.open_swaps.insert(swap.from_block_index, swap.clone());
-    state.distribute_fee(swap.fee);
+    state.open_swap(swap);
 }
 
-pub fn record_swap_success(state: &mut CoreState, from_block_index: u64, to_block_index: u64) {
+pub fn record_swap_success(
+    state: &mut CoreState,
+    from_asset: Asset,
+    from_block_index: u64,
+    to_block_index: u64,
+) {
     record_event(&Event::SwapSuccess(SwapSuccess {
+        from_asset: Some(from_asset.clone()),
         from_block_index,
         to_block_index,
     }));
-    state.finish_swap(from_block_index);
+    state.finish_swap(&from_asset, from_block_index);
     state.distribute_fee(0);
 }
 
@@ -33,6 +43,7 @@ pub fn record_close_leverage_position(
     fee: u64,
     timestamp: u64,
     icp_price: IcpPrice,
+    to_account: Option<Account>,
 ) {
     record_event(&Event::CloseLeveragePosition {
         deposit_block_index,
@@ -40,9 +51,11 @@ pub fn record_close_leverage_position(
         fee,
         timestamp,
         icp_price: icp_price.clone(),
+        to_account,
     });
     if let Some(leverage_position_to_remove) = state.get_leverage_position(deposit_block_index) {
-        state.close_leverage_position(leverage_position_to_remove, icp_price, fee);
+        state.close_leverage_position(leverage_position_to_remove.clone(), icp_price.clone(), fee);
+        state.cover_leverage_loss(&leverage_position_to_remove, &icp_price);
     } else {
         panic!("inconsistent state, cannot close leverage position");
     }
@@ -56,21 +69,17 @@ pub fn record_liquidate_leverage_position(
     timestamp: u64,
     icp_price: IcpPrice,
 ) {
-    record_event(&Event::CloseLeveragePosition {
+    record_event(&Event::LiquidateLeveragePosition {
         deposit_block_index,
-        output_block_index: None,
         fee,
-        icp_price,
         timestamp,
+        icp_price: icp_price.clone(),
     });
 
     if let Some(leverage_position_to_remove) = state.get_leverage_position(deposit_block_index) {
-        if let Some(user_positions) = state
-            .leverage_positions
-            .get_mut(&leverage_position_to_remove.owner)
-        {
-            user_positions.remove(&leverage_position_to_remove);
-        }
+        state.liquidate_leverage_position(leverage_position_to_remove, icp_price);
+    } else {
+        panic!("inconsistent state, cannot liquidate leverage position");
     }
     state.distribute_fee(fee);
 }
@@ -84,3 +93,177 @@ pub fn record_liquidity(state: &mut CoreState, liquidity: Liquidity) {
     }
     state.distribute_fee(liquidity.fee);
 }
+
+pub fn record_liquidity_withdrawal_request(state: &mut CoreState, withdrawal: LiquidityWithdrawal) {
+    record_event(&Event::RequestLiquidityWithdrawal(withdrawal.clone()));
+    state.queue_liquidity_withdrawal(withdrawal);
+}
+
+pub fn record_cancel_liquidity_withdrawal(state: &mut CoreState, id: u64) {
+    record_event(&Event::CancelLiquidityWithdrawal { id });
+    state.remove_liquidity_withdrawal(id);
+}
+
+pub fn record_serve_liquidity_withdrawal(state: &mut CoreState, id: u64, liquidity: Liquidity) {
+    record_liquidity(state, liquidity);
+    record_event(&Event::ServeLiquidityWithdrawal { id });
+    state.remove_liquidity_withdrawal(id);
+}
+
+pub fn record_compound_liquidity_rewards(state: &mut CoreState, owner: Principal, amount: u64) {
+    record_event(&Event::CompoundLiquidityRewards { owner, amount });
+    state.compound_liquidity_rewards(owner, amount);
+}
+
+pub fn record_set_auto_compound(state: &mut CoreState, owner: Principal, enabled: bool) {
+    record_event(&Event::SetAutoCompound { owner, enabled });
+    state.set_auto_compound(owner, enabled);
+}
+
+pub fn record_claim_liquidity_rewards(
+    state: &mut CoreState,
+    owner: Principal,
+    amount: u64,
+    block_index: u64,
+    to_account: Option<Account>,
+) {
+    record_event(&Event::ClaimLiquidityRewards {
+        owner,
+        amount,
+        block_index,
+        to_account,
+    });
+    state.debit_liquidity_rewards(owner, amount);
+}
+
+pub fn record_deposit(
+    state: &mut CoreState,
+    owner: Principal,
+    asset: Asset,
+    amount: u64,
+    block_index: u64,
+) {
+    record_event(&Event::Deposit {
+        owner,
+        asset: asset.clone(),
+        amount,
+        block_index,
+    });
+    state.credit_balance(owner, &asset, amount);
+}
+
+pub fn record_withdraw(
+    state: &mut CoreState,
+    owner: Principal,
+    asset: Asset,
+    amount: u64,
+    to_account: Option<Account>,
+) {
+    record_event(&Event::Withdraw {
+        owner,
+        asset: asset.clone(),
+        amount,
+        to_account,
+    });
+    state.debit_balance(owner, &asset, amount);
+}
+
+/// Returns the block index allocated to the credit.
+pub fn record_credit_balance(
+    state: &mut CoreState,
+    owner: Principal,
+    asset: Asset,
+    amount: u64,
+) -> u64 {
+    let block_index = state.allocate_internal_block_index();
+    record_event(&Event::CreditBalance {
+        owner,
+        asset: asset.clone(),
+        amount,
+        block_index,
+    });
+    state.credit_balance(owner, &asset, amount);
+    block_index
+}
+
+/// Returns the block index allocated to the debit.
+pub fn record_debit_balance(
+    state: &mut CoreState,
+    owner: Principal,
+    asset: Asset,
+    amount: u64,
+) -> u64 {
+    let block_index = state.allocate_internal_block_index();
+    record_event(&Event::DebitBalance {
+        owner,
+        asset: asset.clone(),
+        amount,
+        block_index,
+    });
+    state.debit_balance(owner, &asset, amount);
+    block_index
+}
+
+pub fn record_set_internal_balance_payouts(state: &mut CoreState, owner: Principal, enabled: bool) {
+    record_event(&Event::SetInternalBalancePayouts { owner, enabled });
+    state.set_internal_balance_payouts(owner, enabled);
+}
+
+/// Does not take the state as reclaimed funds were never accounted for.
+pub fn record_reclaim_deposit(owner: Principal, asset: Asset, amount: u64, block_index: u64) {
+    record_event(&Event::ReclaimDeposit {
+        owner,
+        asset,
+        amount,
+        block_index,
+    });
+}
+
+pub fn record_start_global_settlement(state: &mut CoreState, icp_price: IcpPrice, timestamp: u64) {
+    record_event(&Event::StartGlobalSettlement {
+        icp_price: icp_price.clone(),
+        timestamp,
+    });
+    state.start_global_settlement(icp_price, timestamp);
+}
+
+pub fn record_finalize_global_settlement(
+    state: &mut CoreState,
+    redemption_rate: u64,
+    collateral_redemption_rates: BTreeMap<String, u64>,
+    liquidity_surplus: u64,
+    timestamp: u64,
+) {
+    record_event(&Event::FinalizeGlobalSettlement {
+        redemption_rate,
+        collateral_redemption_rates: collateral_redemption_rates.clone(),
+        liquidity_surplus,
+        timestamp,
+    });
+    state.finalize_global_settlement(
+        redemption_rate,
+        collateral_redemption_rates,
+        liquidity_surplus,
+    );
+}
+
+pub fn record_withdraw_treasury(
+    state: &mut CoreState,
+    amount: u64,
+    to: Account,
+    block_index: u64,
+    timestamp: u64,
+) {
+    record_event(&Event::WithdrawTreasury {
+        amount,
+        to,
+        block_index,
+        timestamp,
+    });
+    state.withdraw_treasury(amount);
+}
+
+pub fn record_price_update(state: &mut CoreState, update: PriceUpdate) {
+    record_event(&Event::PriceUpdate(update.clone()));
+    state.apply_price_update(&update);
+}
@@ -1,15 +1,34 @@
 use crate::lifecycle::init::InitArgs;
 use crate::lifecycle::upgrade::UpgradeArgs;
 use crate::logs::P0;
+use crate::price::PriceUpdate;
+use crate::state::Asset;
 use crate::state::CoreState;
 use crate::state::IcpPrice;
 use crate::state::LeveragePosition;
-use crate::updates::liquidity::{Liquidity, LiquidityType};
+use crate::updates::liquidity::{Liquidity, LiquidityType, LiquidityWithdrawal};
 use crate::updates::swap::{Swap, SwapSuccess};
 use candid::Principal;
 use ic_canister_log::log;
-use ic_ledger_types::Timestamp;
+use icrc_ledger_types::icrc1::account::Account;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    /// Cleared while the audits replay the log, logging their events
+    /// would evict the other logs.
+    static LOG_REPLAYED_EVENTS: Cell<bool> = Cell::new(true);
+}
+
+/// Runs `f` without logging the replayed events.
+pub fn without_replay_logs<R>(f: impl FnOnce() -> R) -> R {
+    let previous = LOG_REPLAYED_EVENTS.with(|l| l.replace(false));
+    let _restore = scopeguard::guard(previous, |previous| {
+        LOG_REPLAYED_EVENTS.with(|l| l.set(previous))
+    });
+    f()
+}
 
 #[derive(candid::CandidType, Deserialize)]
 pub struct GetEventsArg {
@@ -17,6 +36,34 @@ pub struct GetEventsArg {
     pub length: u64,
 }
 
+/// A range of events served by the `get_events` query of the archive.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct ArchivedEvents {
+    pub start: u64,
+    pub length: u64,
+    pub archive_id: Principal,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+pub struct GetEventsResponse {
+    /// The number of events, archived ones included.
+    pub total_event_count: u64,
+    /// The index of the first event of `events`.
+    pub first_index: u64,
+    pub events: Vec<Event>,
+    /// The raw log entries of `events`, as hashed in the chain.
+    pub entries: Vec<Vec<u8>>,
+    /// The chain hash of the events before `first_index`.
+    pub prev_hash: Vec<u8>,
+    /// The chain hash of all the events, which is the certified data.
+    pub tip_hash: Vec<u8>,
+    /// The certificate of the tip, only set in queries.
+    pub certificate: Option<Vec<u8>>,
+    /// The requested events which are archived, the archive serves
+    /// their raw entries with `get_raw_events`.
+    pub archived_events: Vec<ArchivedEvents>,
+}
+
 #[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     #[serde(rename = "init")]
@@ -42,6 +89,26 @@ pub enum Event {
         timestamp: u64,
         /// The ICP price used to compute PnL.
         icp_price: IcpPrice,
+        /// The account receiving the payout, the owner if not set.
+        #[serde(default)]
+        to_account: Option<Account>,
+    },
+
+    /// The liquidation seizes the margin and the loss exceeding it is
+    /// covered by the insurance fund, then by the liquidity providers.
+    /// The liquidations recorded before this event are
+    /// [Event::CloseLeveragePosition] without output block index.
+    #[serde(rename = "liquidate_leverage_position")]
+    LiquidateLeveragePosition {
+        /// Block Index of the transfer to open the leverage
+        /// position.
+        deposit_block_index: u64,
+        /// The fee collected by the protocol.
+        fee: u64,
+        /// The timestamp at liquidation.
+        timestamp: u64,
+        /// The ICP price triggering the liquidation.
+        icp_price: IcpPrice,
     },
 
     #[serde(rename = "swap")]
@@ -54,7 +121,125 @@ pub enum Event {
     Liquidity(Liquidity),
 
     #[serde(rename = "claim_liquidity_rewards")]
-    ClaimLiquidityRewards { owner: Principal },
+    ClaimLiquidityRewards {
+        owner: Principal,
+        /// The amount of rewards claimed.
+        amount: u64,
+        /// The block index of the rewards transfer.
+        block_index: u64,
+        /// The account receiving the rewards, the owner if not set.
+        #[serde(default)]
+        to_account: Option<Account>,
+    },
+
+    /// Upgraded from the claims recorded before the claimed amount
+    /// was logged, all the rewards of the owner were claimed.
+    #[serde(rename = "claim_all_liquidity_rewards")]
+    ClaimAllLiquidityRewards { owner: Principal },
+
+    #[serde(rename = "request_liquidity_withdrawal")]
+    RequestLiquidityWithdrawal(LiquidityWithdrawal),
+
+    #[serde(rename = "cancel_liquidity_withdrawal")]
+    CancelLiquidityWithdrawal { id: u64 },
+
+    /// Recorded right after the [Event::Liquidity] removal
+    /// paying out the queued withdrawal.
+    #[serde(rename = "serve_liquidity_withdrawal")]
+    ServeLiquidityWithdrawal { id: u64 },
+
+    #[serde(rename = "compound_liquidity_rewards")]
+    CompoundLiquidityRewards { owner: Principal, amount: u64 },
+
+    #[serde(rename = "set_auto_compound")]
+    SetAutoCompound { owner: Principal, enabled: bool },
+
+    /// Funds transferred to the core canister and credited to an internal balance.
+    #[serde(rename = "deposit")]
+    Deposit {
+        owner: Principal,
+        asset: Asset,
+        amount: u64,
+        block_index: u64,
+    },
+
+    /// Debits an internal balance before transferring the funds out,
+    /// a failed transfer is refunded with [Event::CreditBalance].
+    #[serde(rename = "withdraw")]
+    Withdraw {
+        owner: Principal,
+        asset: Asset,
+        amount: u64,
+        to_account: Option<Account>,
+    },
+
+    /// Credits a payout to an internal balance, the block index is
+    /// allocated by the protocol.
+    #[serde(rename = "credit_balance")]
+    CreditBalance {
+        owner: Principal,
+        asset: Asset,
+        amount: u64,
+        block_index: u64,
+    },
+
+    /// Debits an internal balance to fund an operation, the block index
+    /// is allocated by the protocol.
+    #[serde(rename = "debit_balance")]
+    DebitBalance {
+        owner: Principal,
+        asset: Asset,
+        amount: u64,
+        block_index: u64,
+    },
+
+    #[serde(rename = "set_internal_balance_payouts")]
+    SetInternalBalancePayouts { owner: Principal, enabled: bool },
+
+    /// Funds left in a deposit subaccount sent back to their owner,
+    /// the protocol state is unaffected.
+    #[serde(rename = "reclaim_deposit")]
+    ReclaimDeposit {
+        owner: Principal,
+        asset: Asset,
+        amount: u64,
+        block_index: u64,
+    },
+
+    /// Freezes the ICP price and stops minting, the leverage positions are
+    /// then closed with [Event::CloseLeveragePosition].
+    #[serde(rename = "start_global_settlement")]
+    StartGlobalSettlement { icp_price: IcpPrice, timestamp: u64 },
+
+    /// Recorded once all the leverage positions are closed, opens the eUSD
+    /// redemptions and credits the surplus collateral to liquidity providers.
+    #[serde(rename = "finalize_global_settlement")]
+    FinalizeGlobalSettlement {
+        /// The ICP price (e8s) at which eUSD is redeemed.
+        redemption_rate: u64,
+        /// The prices (e8s) at which eUSD is redeemed against the other
+        /// collaterals.
+        #[serde(default)]
+        collateral_redemption_rates: BTreeMap<String, u64>,
+        /// The ICP credited to liquidity providers pro-rata.
+        liquidity_surplus: u64,
+        timestamp: u64,
+    },
+
+    /// A price fetched from the exchange rate canister, the replay
+    /// takes the prices from these events only.
+    #[serde(rename = "price_update")]
+    PriceUpdate(PriceUpdate),
+
+    /// Treasury funds sent out by a controller.
+    #[serde(rename = "withdraw_treasury")]
+    WithdrawTreasury {
+        /// The amount debited from the treasury, ledger fee included.
+        amount: u64,
+        to: Account,
+        block_index: u64,
+        timestamp: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -66,7 +251,7 @@ pub enum ReplayLogError {
 }
 
 pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<CoreState, ReplayLogError> {
-    let mut state = match events.next() {
+    let state = match events.next() {
         Some(Event::Init(args)) => CoreState::from(args),
         Some(evt) => {
             return Err(ReplayLogError::InconsistentLog(format!(
@@ -76,57 +261,124 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<CoreState, Repl
         }
         None => return Err(ReplayLogError::EmptyLog),
     };
+    apply_events(state, events)
+}
+
+/// Replays the events recorded after a snapshot of the state was taken.
+pub fn replay_from_snapshot(
+    mut state: CoreState,
+    events: impl Iterator<Item = Event>,
+) -> Result<CoreState, ReplayLogError> {
+    // The snapshot may have been taken while requests were in flight.
+    state.release_guards();
+    apply_events(state, events)
+}
+
+/// Applies the events on top of `state`, the offline replay calls it
+/// event by event to check the state in between.
+pub fn apply_events(
+    mut state: CoreState,
+    events: impl Iterator<Item = Event>,
+) -> Result<CoreState, ReplayLogError> {
+    let log_events = LOG_REPLAYED_EVENTS.with(|l| l.get());
     for event in events {
-        log!(P0, "Replaying event : {:?}", event);
+        if log_events {
+            log!(P0, "Replaying event : {:?}", event);
+        }
         match event {
             Event::Init(args) => {
                 state.reinit(args);
             }
-            Event::Upgrade(_args) => {}
+            Event::Upgrade(args) => {
+                state.upgrade(args);
+            }
             Event::OpenLeveragePosition(leverage_position) => {
-                state.icp_prices.insert(
-                    Timestamp {
-                        timestamp_nanos: leverage_position.timestamp,
-                    },
-                    leverage_position.icp_entry_price.clone(),
-                );
+                if !state.has_price_updates {
+                    state.insert_price(
+                        &Asset::ICP,
+                        leverage_position.timestamp,
+                        leverage_position.icp_entry_price.clone(),
+                    );
+                }
                 state.open_leverage_position(leverage_position.clone());
                 state.distribute_fee(leverage_position.fee);
             }
             Event::CloseLeveragePosition {
                 deposit_block_index,
-                output_block_index: _,
+                output_block_index,
                 fee,
                 timestamp,
                 icp_price,
+                to_account: _,
             } => {
-                state.icp_prices.insert(
-                    Timestamp {
-                        timestamp_nanos: timestamp,
-                    },
-                    icp_price.clone(),
-                );
+                if !state.has_price_updates {
+                    state.insert_price(&Asset::ICP, timestamp, icp_price.clone());
+                }
                 if let Some(leverage_position_to_remove) =
                     state.get_leverage_position(deposit_block_index)
                 {
-                    state.close_leverage_position(leverage_position_to_remove, icp_price, fee);
+                    state.close_leverage_position(
+                        leverage_position_to_remove.clone(),
+                        icp_price.clone(),
+                        fee,
+                    );
+                    // The liquidations recorded without output block index
+                    // replay as the close they were applied as, without
+                    // charging their shortfall.
+                    if output_block_index.is_some() {
+                        state.cover_leverage_loss(&leverage_position_to_remove, &icp_price);
+                    }
                 } else {
                     panic!("inconsistent state, cannot close leverage position");
                 }
                 state.distribute_fee(fee);
             }
+            Event::LiquidateLeveragePosition {
+                deposit_block_index,
+                fee,
+                timestamp: _,
+                icp_price,
+            } => {
+                if let Some(leverage_position_to_remove) =
+                    state.get_leverage_position(deposit_block_index)
+                {
+                    state.liquidate_leverage_position(leverage_position_to_remove, icp_price);
+                } else {
+                    panic!("inconsistent state, cannot liquidate leverage position");
+                }
+                state.distribute_fee(fee);
+            }
             Event::Swap(swap) => {
-                state.icp_prices.insert(
-                    Timestamp {
-                        timestamp_nanos: swap.timestamp,
-                    },
-                    IcpPrice { rate: swap.rate },
-                );
-                state.distribute_fee(swap.fee);
-                state.open_swaps.insert(swap.from_block_index, swap);
+                if let Asset::Collateral(symbol) = swap.collateral() {
+                    if state.get_collateral(symbol).is_none() {
+                        return Err(ReplayLogError::InconsistentLog(format!(
+                            "Swap of unregistered collateral {}",
+                            symbol
+                        )));
+                    }
+                }
+                if !state.has_price_updates {
+                    state.insert_price(
+                        swap.collateral(),
+                        swap.timestamp,
+                        IcpPrice { rate: swap.rate },
+                    );
+                }
+                state.open_swap(swap);
             }
             Event::SwapSuccess(swap_success) => {
-                state.finish_swap(swap_success.from_block_index);
+                // The successes recorded before the swaps were keyed by
+                // asset finish the swap of the block index.
+                let from_asset = swap_success.from_asset.or_else(|| {
+                    state
+                        .open_swaps
+                        .keys()
+                        .find(|(_, block_index)| *block_index == swap_success.from_block_index)
+                        .map(|(asset, _)| asset.clone())
+                });
+                if let Some(from_asset) = from_asset {
+                    state.finish_swap(&from_asset, swap_success.from_block_index);
+                }
             }
             Event::Liquidity(liquidity) => {
                 match liquidity.operation_type {
@@ -137,10 +389,137 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<CoreState, Repl
                 }
                 state.distribute_fee(liquidity.fee);
             }
-            Event::ClaimLiquidityRewards { owner } => {
+            Event::ClaimLiquidityRewards { owner, amount, .. } => {
+                state.debit_liquidity_rewards(owner, amount);
+            }
+            Event::ClaimAllLiquidityRewards { owner } => {
                 state.liquidity_rewards.remove(&owner);
             }
+            Event::CompoundLiquidityRewards { owner, amount } => {
+                state.compound_liquidity_rewards(owner, amount);
+            }
+            Event::SetAutoCompound { owner, enabled } => {
+                state.set_auto_compound(owner, enabled);
+            }
+            Event::RequestLiquidityWithdrawal(withdrawal) => {
+                state.queue_liquidity_withdrawal(withdrawal);
+            }
+            Event::CancelLiquidityWithdrawal { id } | Event::ServeLiquidityWithdrawal { id } => {
+                if state.remove_liquidity_withdrawal(id).is_none() {
+                    return Err(ReplayLogError::InconsistentLog(format!(
+                        "Unknown liquidity withdrawal {}",
+                        id
+                    )));
+                }
+            }
+            Event::Deposit {
+                owner,
+                asset,
+                amount,
+                ..
+            } => {
+                state.credit_balance(owner, &asset, amount);
+            }
+            Event::Withdraw {
+                owner,
+                asset,
+                amount,
+                ..
+            } => {
+                if state.get_balance(&owner, &asset) < amount {
+                    return Err(ReplayLogError::InconsistentLog(format!(
+                        "Withdrawing {} {:?} from the balance of {} exceeds it",
+                        amount, asset, owner
+                    )));
+                }
+                state.debit_balance(owner, &asset, amount);
+            }
+            Event::CreditBalance {
+                owner,
+                asset,
+                amount,
+                block_index,
+            } => {
+                check_internal_block_index(&mut state, block_index)?;
+                state.credit_balance(owner, &asset, amount);
+            }
+            Event::DebitBalance {
+                owner,
+                asset,
+                amount,
+                block_index,
+            } => {
+                check_internal_block_index(&mut state, block_index)?;
+                if state.get_balance(&owner, &asset) < amount {
+                    return Err(ReplayLogError::InconsistentLog(format!(
+                        "Debiting {} {:?} from the balance of {} exceeds it",
+                        amount, asset, owner
+                    )));
+                }
+                state.debit_balance(owner, &asset, amount);
+            }
+            Event::SetInternalBalancePayouts { owner, enabled } => {
+                state.set_internal_balance_payouts(owner, enabled);
+            }
+            Event::ReclaimDeposit { .. } => {}
+            Event::StartGlobalSettlement {
+                icp_price,
+                timestamp,
+            } => {
+                if state.global_settlement.is_some() {
+                    return Err(ReplayLogError::InconsistentLog(
+                        "The global settlement started twice".to_string(),
+                    ));
+                }
+                state.start_global_settlement(icp_price, timestamp);
+            }
+            Event::FinalizeGlobalSettlement {
+                redemption_rate,
+                collateral_redemption_rates,
+                liquidity_surplus,
+                ..
+            } => {
+                match &state.global_settlement {
+                    Some(settlement) if settlement.redemption_rate.is_none() => {}
+                    _ => {
+                        return Err(ReplayLogError::InconsistentLog(
+                            "Finalizing a global settlement which is not in progress".to_string(),
+                        ))
+                    }
+                }
+                state.finalize_global_settlement(
+                    redemption_rate,
+                    collateral_redemption_rates,
+                    liquidity_surplus,
+                );
+            }
+            Event::PriceUpdate(update) => {
+                state.apply_price_update(&update);
+            }
+            Event::WithdrawTreasury { amount, .. } => {
+                if amount > state.treasury_amount {
+                    return Err(ReplayLogError::InconsistentLog(format!(
+                        "Withdrawing {} from a treasury of {}",
+                        amount, state.treasury_amount
+                    )));
+                }
+                state.withdraw_treasury(amount);
+            }
         }
     }
     Ok(state)
 }
+
+fn check_internal_block_index(
+    state: &mut CoreState,
+    block_index: u64,
+) -> Result<(), ReplayLogError> {
+    let expected = state.allocate_internal_block_index();
+    if block_index != expected {
+        return Err(ReplayLogError::InconsistentLog(format!(
+            "Unexpected internal block index {}, expected {}",
+            block_index, expected
+        )));
+    }
+    Ok(())
+}
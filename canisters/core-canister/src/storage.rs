@@ -1,32 +1,211 @@
-use crate::state::eventlog::Event;
+use crate::logs::P0;
+use crate::state::eventlog::{ArchivedEvents, Event};
+use crate::state::CoreState;
+use candid::Principal;
+use ic_canister_log::log;
+use ic_crypto_sha::Sha256;
 use ic_stable_structures::{
     log::{Log as StableLog, NoSuchEntry},
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl,
+    storable::Blob,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
-use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+
+pub mod history;
+pub mod migration;
 
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+const SNAPSHOT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SNAPSHOT_DATA_MEMORY_ID: MemoryId = MemoryId::new(3);
+const USER_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(4);
+const POSITION_OWNERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const INDEXED_EVENT_COUNT_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ALT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(7);
+const ALT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(8);
+const LOG_LAYOUT_MEMORY_ID: MemoryId = MemoryId::new(9);
+const ALT_SNAPSHOT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(10);
+const ALT_SNAPSHOT_DATA_MEMORY_ID: MemoryId = MemoryId::new(11);
+const CHAIN_CHECKPOINTS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const WITHDRAWAL_OWNERS_MEMORY_ID: MemoryId = MemoryId::new(13);
+
+/// The number of events after which a new snapshot of the state is taken.
+pub const SNAPSHOT_INTERVAL_EVENTS: u64 = 10_000;
+
+/// The number of snapshots kept, the older ones are dropped.
+pub const MAX_SNAPSHOTS: u64 = 3;
+
+/// The number of events between two checkpoints of the chain hash,
+/// which bounds the entries hashed to verify a range of the log.
+pub const CHAIN_CHECKPOINT_INTERVAL: u64 = 1_000;
+
+/// Must be bumped whenever the layout of [CoreState] or the replay of
+/// the events changes, along with an upgrade function of the older
+/// snapshots in [migration].
+const SNAPSHOT_VERSION: u32 = 1;
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 type EventLog = StableLog<Vec<u8>, VMem, VMem>;
+type SnapshotLog = StableLog<Vec<u8>, VMem, VMem>;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
-    /// The log of the ckBTC state modifications.
-    static EVENTS: RefCell<EventLog> = MEMORY_MANAGER
+    static LOG_LAYOUT: RefCell<StableCell<LogLayout, VMem>> = MEMORY_MANAGER
         .with(|m|
               RefCell::new(
-                  StableLog::init(
-                      m.borrow().get(LOG_INDEX_MEMORY_ID),
-                      m.borrow().get(LOG_DATA_MEMORY_ID)
-                  ).expect("failed to initialize stable log")
+                  StableCell::init(m.borrow().get(LOG_LAYOUT_MEMORY_ID), LogLayout::default())
+                      .expect("failed to initialize the log layout")
               )
         );
+
+    /// The log of the ckBTC state modifications which are not archived.
+    static EVENTS: RefCell<EventLog> = {
+        let (index_memory, data_memory) = event_log_memories(log_layout().alternate);
+        RefCell::new(
+            StableLog::init(index_memory, data_memory).expect("failed to initialize stable log")
+        )
+    };
+
+    /// The snapshots of the state, each one replacing the replay
+    /// of the events recorded before it.
+    static SNAPSHOTS: RefCell<SnapshotLog> = {
+        let (index_memory, data_memory) = snapshot_log_memories(log_layout().alternate_snapshots);
+        RefCell::new(
+            StableLog::init(index_memory, data_memory)
+                .expect("failed to initialize the snapshot log")
+        )
+    };
+
+    /// Maps the event counts multiple of [CHAIN_CHECKPOINT_INTERVAL] to
+    /// the chain hash of these events.
+    static CHAIN_CHECKPOINTS: RefCell<StableBTreeMap<u64, Blob<32>, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(CHAIN_CHECKPOINTS_MEMORY_ID))));
+
+    /// The event count of the latest snapshot, read from the log on first use.
+    static LAST_SNAPSHOT_EVENT_COUNT: Cell<Option<u64>> = Cell::new(None);
+
+    /// The chain hash of all the recorded events, read from the log on first use.
+    static CHAIN_TIP: Cell<Option<[u8; 32]>> = Cell::new(None);
+}
+
+/// Locates the events which are not archived.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct LogLayout {
+    /// Whether the events are stored in the alternate memories, the
+    /// live events are moved between the two sets of memories when the
+    /// older ones are archived.
+    alternate: bool,
+    /// The index of the first event stored in the log.
+    first_index: u64,
+    /// The chain hash of the archived events.
+    first_prev_hash: [u8; 32],
+    /// The canister storing the archived events.
+    archive_id: Option<Principal>,
+    /// Whether the snapshots are stored in the alternate memories, the
+    /// latest snapshots are moved between the two sets of memories when
+    /// the older ones are dropped.
+    #[serde(default)]
+    alternate_snapshots: bool,
+}
+
+impl Storable for LogLayout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(self, &mut buf).expect("failed to encode the log layout");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode the log layout")
+    }
+}
+
+fn log_layout() -> LogLayout {
+    LOG_LAYOUT.with(|l| l.borrow().get().clone())
+}
+
+fn event_log_memories(alternate: bool) -> (VMem, VMem) {
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+        if alternate {
+            (
+                m.get(ALT_LOG_INDEX_MEMORY_ID),
+                m.get(ALT_LOG_DATA_MEMORY_ID),
+            )
+        } else {
+            (m.get(LOG_INDEX_MEMORY_ID), m.get(LOG_DATA_MEMORY_ID))
+        }
+    })
+}
+
+fn snapshot_log_memories(alternate: bool) -> (VMem, VMem) {
+    MEMORY_MANAGER.with(|m| {
+        let m = m.borrow();
+        if alternate {
+            (
+                m.get(ALT_SNAPSHOT_INDEX_MEMORY_ID),
+                m.get(ALT_SNAPSHOT_DATA_MEMORY_ID),
+            )
+        } else {
+            (
+                m.get(SNAPSHOT_INDEX_MEMORY_ID),
+                m.get(SNAPSHOT_DATA_MEMORY_ID),
+            )
+        }
+    })
+}
+
+/// The state obtained by replaying the first `event_count` events.
+#[derive(Deserialize)]
+pub struct StateSnapshot {
+    pub version: u32,
+    pub event_count: u64,
+    pub state: CoreState,
+}
+
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct StateSnapshotRef<'a> {
+    version: u32,
+    event_count: u64,
+    state: &'a CoreState,
+}
+
+/// The version of the events recorded in the log, see [migration].
+pub const EVENT_VERSION: u32 = 1;
+
+/// The chain hash of the empty event log.
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+#[derive(Serialize)]
+struct EventEnvelope<'a> {
+    #[serde(rename = "v")]
+    version: u32,
+    /// The chain hash of the events recorded before this one.
+    #[serde(rename = "p")]
+    prev_hash: [u8; 32],
+    #[serde(rename = "e")]
+    event: &'a Event,
+}
+
+#[derive(Deserialize)]
+struct EventHeader {
+    #[serde(rename = "v")]
+    version: u32,
+    /// Not recorded by the unversioned events.
+    #[serde(rename = "p", default)]
+    prev_hash: Option<[u8; 32]>,
 }
 
 pub struct EventIterator {
@@ -57,40 +236,655 @@ impl Iterator for EventIterator {
     }
 }
 
-/// Encodes an event into a byte array.
-fn encode_event(event: &Event) -> Vec<u8> {
+/// Encodes an event into a byte array, wrapped in an envelope
+/// carrying the current [EVENT_VERSION] and the chain hash of the
+/// previous events.
+fn encode_event(event: &Event, prev_hash: [u8; 32]) -> Vec<u8> {
     let mut buf = Vec::new();
-    ciborium::ser::into_writer(event, &mut buf).expect("failed to encode a minter event");
+    let envelope = EventEnvelope {
+        version: EVENT_VERSION,
+        prev_hash,
+        event,
+    };
+    ciborium::ser::into_writer(&envelope, &mut buf).expect("failed to encode a minter event");
     buf
 }
 
+/// Decodes an event of any version, the events recorded by older
+/// versions are upgraded to the current shape.
+///
 /// # Panics
 ///
 /// This function panics if the event decoding fails.
-fn decode_event(buf: &[u8]) -> Event {
-    ciborium::de::from_reader(buf).expect("failed to decode a minter event")
+pub fn decode_event(buf: &[u8]) -> Event {
+    // The events recorded before the envelope was introduced are
+    // plain events, which have no version field.
+    let version = match ciborium::de::from_reader::<EventHeader, _>(buf) {
+        Ok(EventHeader { version, .. }) => version,
+        Err(_) => 0,
+    };
+    migration::decode_event(version, buf)
+        .unwrap_or_else(|e| panic!("failed to decode a minter event: {}", e))
 }
 
 /// Returns an iterator over all minter events.
+///
+/// # Panics
+///
+/// This function panics if some events are archived.
 pub fn events() -> impl Iterator<Item = Event> {
+    events_from(0)
+}
+
+/// Returns an iterator over the events starting at index `start`.
+///
+/// # Panics
+///
+/// This function panics if the event at `start` is archived.
+pub fn events_from(start: u64) -> impl Iterator<Item = Event> {
+    let first_index = first_event_index();
+    assert!(
+        start >= first_index,
+        "the events before {} are archived",
+        first_index
+    );
     EventIterator {
         buf: vec![],
-        pos: 0,
+        pos: start - first_index,
     }
 }
 
-/// Returns the current number of events in the log.
+/// Returns the current number of events, archived ones included.
 pub fn count_events() -> u64 {
-    EVENTS.with(|events| events.borrow().len())
+    first_event_index() + EVENTS.with(|events| events.borrow().len())
+}
+
+/// Returns the index of the first event which is not archived.
+pub fn first_event_index() -> u64 {
+    LOG_LAYOUT.with(|l| l.borrow().get().first_index)
+}
+
+/// Returns the canister storing the archived events.
+pub fn archive_id() -> Option<Principal> {
+    LOG_LAYOUT.with(|l| l.borrow().get().archive_id)
+}
+
+/// Splits the events in `[start, start + length)` into the range
+/// stored by the archive and the range still in the log.
+pub fn split_archived_range(start: u64, length: u64) -> (Option<ArchivedEvents>, Range<u64>) {
+    let first_index = first_event_index();
+    let event_count = count_events();
+    let end = event_count.min(start.saturating_add(length));
+    let archived_end = end.min(first_index);
+    let archived = match archive_id() {
+        Some(archive_id) if start < archived_end => Some(ArchivedEvents {
+            start,
+            length: archived_end - start,
+            archive_id,
+        }),
+        _ => None,
+    };
+    let live_start = start.clamp(first_index, event_count);
+    (archived, live_start..end.max(live_start))
+}
+
+/// Returns the raw log entries in `[start, start + length)`, skipping
+/// the archived ones.
+pub fn read_raw_events(start: u64, length: u64) -> Vec<Vec<u8>> {
+    let first_index = first_event_index();
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        let end = events
+            .len()
+            .min(start.saturating_add(length).saturating_sub(first_index));
+        (start.saturating_sub(first_index)..end)
+            .map(|pos| {
+                let mut buf = vec![];
+                events
+                    .read_entry(pos, &mut buf)
+                    .expect("bug: missing an event log entry");
+                buf
+            })
+            .collect()
+    })
 }
 
 /// Records a new minter event.
 pub fn record_event(event: &Event) {
-    let bytes = encode_event(event);
+    let prev_hash = chain_tip();
+    let bytes = encode_event(event, prev_hash);
     EVENTS.with(|events| {
         events
             .borrow()
             .append(&bytes)
             .expect("failed to append an entry to the event log")
     });
+    let tip = chain_hash(&prev_hash, &bytes);
+    CHAIN_TIP.with(|t| t.set(Some(tip)));
+    let event_count = count_events();
+    if event_count % CHAIN_CHECKPOINT_INTERVAL == 0 {
+        insert_chain_checkpoint(event_count, tip);
+    }
+    certify_chain_tip();
+    history::index_new_events();
+}
+
+/// Returns the chain hash of the log entry `entry` recorded after
+/// the events of chain hash `prev_hash`.
+///
+/// Auditors verify the history by folding the raw entries from
+/// [GENESIS_HASH] up to the certified tip.
+pub fn chain_hash(prev_hash: &[u8; 32], entry: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.write(prev_hash);
+    hasher.write(entry);
+    hasher.finish()
+}
+
+fn entry_prev_hash(entry: &[u8]) -> Option<[u8; 32]> {
+    ciborium::de::from_reader::<EventHeader, _>(entry)
+        .ok()
+        .and_then(|header| header.prev_hash)
+}
+
+fn insert_chain_checkpoint(event_count: u64, hash: [u8; 32]) {
+    let hash = Blob::try_from(&hash[..]).expect("bug: chain hashes are 32 bytes long");
+    CHAIN_CHECKPOINTS.with(|c| c.borrow_mut().insert(event_count, hash));
+}
+
+/// Returns the latest chain checkpoint covering at most `count` events,
+/// the hash of the archived events if there is none.
+pub fn chain_checkpoint_before(count: u64) -> (u64, [u8; 32]) {
+    let layout = log_layout();
+    let checkpoint = CHAIN_CHECKPOINTS.with(|c| {
+        c.borrow()
+            .range(layout.first_index..count.saturating_add(1))
+            .last()
+    });
+    match checkpoint {
+        Some((event_count, hash)) => {
+            let mut buf = [0; 32];
+            buf.copy_from_slice(hash.as_slice());
+            (event_count, buf)
+        }
+        None => (layout.first_index, layout.first_prev_hash),
+    }
+}
+
+/// Records the chain checkpoints of the events logged before they
+/// existed, the unversioned entries are hashed once.
+pub fn record_chain_checkpoints() {
+    let event_count = count_events();
+    let (mut pos, mut hash) = chain_checkpoint_before(event_count);
+    while pos < event_count {
+        let end = (pos / CHAIN_CHECKPOINT_INTERVAL + 1) * CHAIN_CHECKPOINT_INTERVAL;
+        for entry in read_raw_events(pos, end.min(event_count) - pos) {
+            hash = chain_hash(&hash, &entry);
+        }
+        pos = end.min(event_count);
+        if pos % CHAIN_CHECKPOINT_INTERVAL == 0 {
+            insert_chain_checkpoint(pos, hash);
+        }
+    }
+    CHAIN_TIP.with(|tip| tip.set(Some(hash)));
+}
+
+/// Returns the chain hash of the first `count` events.
+///
+/// # Panics
+///
+/// This function panics if the last of these events is archived.
+pub fn chain_hash_at(count: u64) -> [u8; 32] {
+    let layout = log_layout();
+    assert!(
+        count >= layout.first_index,
+        "the events before {} are archived",
+        layout.first_index
+    );
+    if count == layout.first_index {
+        return layout.first_prev_hash;
+    }
+    let last_entry = read_raw_events(count - 1, 1)
+        .pop()
+        .expect("bug: hashing past the end of the event log");
+    match entry_prev_hash(&last_entry) {
+        Some(prev_hash) => chain_hash(&prev_hash, &last_entry),
+        // The unversioned entries do not carry the chain hash, the
+        // chain is folded from the latest checkpoint.
+        None => {
+            let (start, hash) = chain_checkpoint_before(count);
+            read_raw_events(start, count - start)
+                .iter()
+                .fold(hash, |hash, entry| chain_hash(&hash, entry))
+        }
+    }
+}
+
+/// Returns the chain hash of all the recorded events.
+pub fn chain_tip() -> [u8; 32] {
+    CHAIN_TIP.with(|tip| match tip.get() {
+        Some(hash) => hash,
+        None => {
+            let hash = chain_hash_at(count_events());
+            tip.set(Some(hash));
+            hash
+        }
+    })
+}
+
+/// Exposes the chain tip as the certified data of the canister.
+pub fn certify_chain_tip() {
+    // The certified data can only be set from the canister.
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::set_certified_data(&chain_tip());
+}
+
+/// Checks that the chain hash recorded in each entry matches the
+/// hash of the entries recorded before it, from the latest chain
+/// checkpoint covering at most `start` events up to the tip. The
+/// checkpoints passed by must match the chain as well.
+pub fn check_event_chain(start: u64) -> Result<(), String> {
+    let (first, mut hash) = chain_checkpoint_before(start.max(first_event_index()));
+    let entries = read_raw_events(first, count_events() - first);
+    for (pos, entry) in (first..).zip(entries.iter()) {
+        if let Some(prev_hash) = entry_prev_hash(entry) {
+            if prev_hash != hash {
+                return Err(format!(
+                    "event {} is chained to {}, expected {}",
+                    pos,
+                    hex::encode(prev_hash),
+                    hex::encode(hash)
+                ));
+            }
+        }
+        hash = chain_hash(&hash, entry);
+        if (pos + 1) % CHAIN_CHECKPOINT_INTERVAL != 0 {
+            continue;
+        }
+        if let Some(checkpoint) = CHAIN_CHECKPOINTS.with(|c| c.borrow().get(&(pos + 1))) {
+            if checkpoint.as_slice() != hash {
+                return Err(format!(
+                    "the checkpoint at event {} is {}, expected {}",
+                    pos + 1,
+                    hex::encode(checkpoint.as_slice()),
+                    hex::encode(hash)
+                ));
+            }
+        }
+    }
+    if hash != chain_tip() {
+        return Err(format!(
+            "the chain tip {} does not match the log hash {}",
+            hex::encode(chain_tip()),
+            hex::encode(hash)
+        ));
+    }
+    Ok(())
+}
+
+/// Drops the events before `end` from the log, once they are stored
+/// by `archive_id`. The live events are moved to the other set of
+/// memories, so that the log does not grow forever.
+pub fn drop_archived_events(end: u64, archive_id: Principal) {
+    let layout = log_layout();
+    assert!(
+        layout.first_index <= end && end <= count_events(),
+        "bug: dropping the events before {} out of [{}, {})",
+        end,
+        layout.first_index,
+        count_events()
+    );
+    let first_prev_hash = chain_hash_at(end);
+    let live_entries = read_raw_events(end, count_events() - end);
+
+    let alternate = !layout.alternate;
+    let (index_memory, data_memory) = event_log_memories(alternate);
+    let log = EventLog::new(index_memory, data_memory);
+    for entry in live_entries {
+        log.append(&entry)
+            .expect("failed to append an entry to the event log");
+    }
+    EVENTS.with(|events| *events.borrow_mut() = log);
+    CHAIN_CHECKPOINTS.with(|c| {
+        let mut checkpoints = c.borrow_mut();
+        let archived: Vec<u64> = checkpoints.range(..end).map(|(count, _)| count).collect();
+        for count in archived {
+            checkpoints.remove(&count);
+        }
+    });
+    LOG_LAYOUT.with(|l| {
+        l.borrow_mut()
+            .set(LogLayout {
+                alternate,
+                first_index: end,
+                first_prev_hash,
+                archive_id: Some(archive_id),
+                ..layout
+            })
+            .expect("failed to update the log layout")
+    });
+}
+
+/// Records a snapshot of `state`, which must reflect all the recorded events.
+pub fn record_snapshot(state: &CoreState) {
+    let event_count = count_events();
+    let mut buf = Vec::new();
+    let snapshot = StateSnapshotRef {
+        version: SNAPSHOT_VERSION,
+        event_count,
+        state,
+    };
+    ciborium::ser::into_writer(&snapshot, &mut buf).expect("failed to encode a state snapshot");
+    if count_snapshots() >= MAX_SNAPSHOTS {
+        drop_old_snapshots();
+    }
+    SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .append(&buf)
+            .expect("failed to append an entry to the snapshot log")
+    });
+    LAST_SNAPSHOT_EVENT_COUNT.with(|c| c.set(Some(event_count)));
+}
+
+/// Keeps the latest `MAX_SNAPSHOTS - 1` snapshots, which are moved to
+/// the other set of memories, so that the snapshot log does not grow
+/// forever.
+fn drop_old_snapshots() {
+    let kept_entries: Vec<Vec<u8>> = SNAPSHOTS.with(|snapshots| {
+        let snapshots = snapshots.borrow();
+        let len = snapshots.len();
+        (len.saturating_sub(MAX_SNAPSHOTS - 1)..len)
+            .map(|pos| {
+                let mut buf = vec![];
+                snapshots
+                    .read_entry(pos, &mut buf)
+                    .expect("bug: missing a snapshot log entry");
+                buf
+            })
+            .collect()
+    });
+
+    let layout = log_layout();
+    let alternate_snapshots = !layout.alternate_snapshots;
+    let (index_memory, data_memory) = snapshot_log_memories(alternate_snapshots);
+    let log = SnapshotLog::new(index_memory, data_memory);
+    for entry in kept_entries {
+        log.append(&entry)
+            .expect("failed to append an entry to the snapshot log");
+    }
+    SNAPSHOTS.with(|snapshots| *snapshots.borrow_mut() = log);
+    LOG_LAYOUT.with(|l| {
+        l.borrow_mut()
+            .set(LogLayout {
+                alternate_snapshots,
+                ..layout
+            })
+            .expect("failed to update the log layout")
+    });
+}
+
+fn read_snapshot(pos: u64) -> Result<StateSnapshot, String> {
+    let mut buf = vec![];
+    SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .read_entry(pos, &mut buf)
+            .expect("bug: missing a snapshot log entry")
+    });
+    let header =
+        ciborium::de::from_reader::<SnapshotHeader, _>(&buf[..]).map_err(|e| format!("{:?}", e))?;
+    migration::decode_snapshot(header.version, &buf)
+}
+
+/// Returns the latest snapshot of the state which decodes, the
+/// snapshots of older versions being upgraded.
+pub fn latest_snapshot() -> Option<StateSnapshot> {
+    (0..count_snapshots())
+        .rev()
+        .find_map(|pos| match read_snapshot(pos) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                log!(
+                    P0,
+                    "[snapshot]: failed to decode the snapshot {}: {}",
+                    pos,
+                    e
+                );
+                None
+            }
+        })
+}
+
+/// Returns the number of events covered by the latest snapshot.
+pub fn last_snapshot_event_count() -> u64 {
+    LAST_SNAPSHOT_EVENT_COUNT.with(|c| match c.get() {
+        Some(event_count) => event_count,
+        None => {
+            let event_count = latest_snapshot().map_or(0, |snapshot| snapshot.event_count);
+            c.set(Some(event_count));
+            event_count
+        }
+    })
+}
+
+/// Returns the current number of snapshots in the log.
+pub fn count_snapshots() -> u64 {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().len())
+}
+
+#[test]
+fn test_replay_from_snapshot() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::lifecycle::upgrade::UpgradeArgs;
+    use crate::state::eventlog::{replay, replay_from_snapshot};
+    use crate::updates::liquidity::{Liquidity, LiquidityType};
+    use candid::Principal;
+
+    let owner = Principal::from_slice(&[1]);
+    record_event(&Event::Init(default_init_args()));
+    record_event(&Event::Liquidity(Liquidity {
+        caller: owner,
+        operation_type: LiquidityType::Add,
+        amount: 100_000_000,
+        block_index: 0,
+        timestamp: 0,
+        fee: 0,
+        to_account: None,
+    }));
+    assert_eq!(latest_snapshot().map(|s| s.event_count), None);
+
+    let mut state = replay(events()).unwrap();
+    // Guards held while the snapshot is taken are released on replay.
+    state.is_timer_running = true;
+    record_snapshot(&state);
+
+    record_event(&Event::Upgrade(UpgradeArgs {
+        debt_ceiling: Some(1_000),
+        ..Default::default()
+    }));
+    record_event(&Event::SetAutoCompound {
+        owner,
+        enabled: true,
+    });
+
+    let snapshot = latest_snapshot().unwrap();
+    assert_eq!(snapshot.event_count, 2);
+    assert_eq!(last_snapshot_event_count(), 2);
+    let restored_state = replay_from_snapshot(snapshot.state, events_from(2)).unwrap();
+    let replayed_state = replay(events()).unwrap();
+    assert_eq!(
+        restored_state.check_semantically_eq(&replayed_state),
+        Ok(())
+    );
+    assert_eq!(restored_state.debt_ceiling, 1_000);
+    assert!(!restored_state.is_timer_running);
+}
+
+#[test]
+fn test_drop_old_snapshots() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::lifecycle::upgrade::UpgradeArgs;
+    use crate::state::eventlog::replay;
+
+    record_event(&Event::Init(default_init_args()));
+    for debt_ceiling in 0..(2 * MAX_SNAPSHOTS + 1) {
+        record_event(&Event::Upgrade(UpgradeArgs {
+            debt_ceiling: Some(debt_ceiling),
+            ..Default::default()
+        }));
+        record_snapshot(&replay(events()).unwrap());
+        assert!(count_snapshots() <= MAX_SNAPSHOTS);
+    }
+
+    assert_eq!(count_snapshots(), MAX_SNAPSHOTS);
+    let snapshot = latest_snapshot().unwrap();
+    assert_eq!(snapshot.event_count, count_events());
+    assert_eq!(snapshot.state.debt_ceiling, 2 * MAX_SNAPSHOTS);
+}
+
+#[test]
+fn test_latest_snapshot_skips_unknown_versions() {
+    use crate::lifecycle::init::default_init_args;
+
+    let mut state = CoreState::from(default_init_args());
+    state.debt_ceiling = 42;
+    record_snapshot(&state);
+    let mut buf = vec![];
+    ciborium::ser::into_writer(
+        &StateSnapshotRef {
+            version: SNAPSHOT_VERSION + 1,
+            event_count: 0,
+            state: &CoreState::from(default_init_args()),
+        },
+        &mut buf,
+    )
+    .unwrap();
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().append(&buf).unwrap());
+
+    let snapshot = latest_snapshot().unwrap();
+    assert_eq!(snapshot.version, SNAPSHOT_VERSION);
+    assert_eq!(snapshot.state, state);
+}
+
+#[test]
+fn test_chain_checkpoints() {
+    use crate::lifecycle::upgrade::UpgradeArgs;
+
+    // The unversioned entries are not chained.
+    let mut hash = GENESIS_HASH;
+    for debt_ceiling in 0..(CHAIN_CHECKPOINT_INTERVAL + 2) {
+        let mut entry = vec![];
+        ciborium::ser::into_writer(
+            &Event::Upgrade(UpgradeArgs {
+                debt_ceiling: Some(debt_ceiling),
+                ..Default::default()
+            }),
+            &mut entry,
+        )
+        .unwrap();
+        EVENTS.with(|events| events.borrow().append(&entry).unwrap());
+        hash = chain_hash(&hash, &entry);
+    }
+    let checkpoint_hash = chain_hash_at(CHAIN_CHECKPOINT_INTERVAL);
+
+    record_chain_checkpoints();
+    assert_eq!(
+        chain_checkpoint_before(CHAIN_CHECKPOINT_INTERVAL + 1),
+        (CHAIN_CHECKPOINT_INTERVAL, checkpoint_hash)
+    );
+    assert_eq!(chain_tip(), hash);
+    assert_eq!(chain_hash_at(CHAIN_CHECKPOINT_INTERVAL + 2), hash);
+
+    // The recorded events add the checkpoints as they go.
+    while count_events() < 2 * CHAIN_CHECKPOINT_INTERVAL {
+        record_event(&Event::Upgrade(UpgradeArgs::default()));
+    }
+    assert_eq!(
+        chain_checkpoint_before(count_events()),
+        (2 * CHAIN_CHECKPOINT_INTERVAL, chain_tip())
+    );
+    assert_eq!(check_event_chain(0), Ok(()));
+
+    // The check starts from the latest checkpoint before its start.
+    insert_chain_checkpoint(CHAIN_CHECKPOINT_INTERVAL, GENESIS_HASH);
+    assert!(check_event_chain(0).is_err());
+    assert!(check_event_chain(CHAIN_CHECKPOINT_INTERVAL + 1).is_err());
+    assert_eq!(check_event_chain(2 * CHAIN_CHECKPOINT_INTERVAL), Ok(()));
+}
+
+#[test]
+fn test_event_chain() {
+    use crate::lifecycle::upgrade::UpgradeArgs;
+
+    assert_eq!(chain_tip(), GENESIS_HASH);
+    for debt_ceiling in 0..3 {
+        record_event(&Event::Upgrade(UpgradeArgs {
+            debt_ceiling: Some(debt_ceiling),
+            ..Default::default()
+        }));
+    }
+
+    let entries = read_raw_events(0, 10);
+    assert_eq!(entries.len(), 3);
+    let mut hash = GENESIS_HASH;
+    for (pos, entry) in entries.iter().enumerate() {
+        assert_eq!(chain_hash_at(pos as u64), hash);
+        assert_eq!(entry_prev_hash(entry), Some(hash));
+        hash = chain_hash(&hash, entry);
+    }
+    assert_eq!(chain_tip(), hash);
+    assert_eq!(chain_hash_at(3), hash);
+    assert_eq!(check_event_chain(0), Ok(()));
+
+    // The tip is recovered from the log after an upgrade.
+    CHAIN_TIP.with(|tip| tip.set(None));
+    assert_eq!(chain_tip(), hash);
+}
+
+#[test]
+fn test_drop_archived_events() {
+    use crate::lifecycle::upgrade::UpgradeArgs;
+
+    let upgrade = |debt_ceiling| {
+        Event::Upgrade(UpgradeArgs {
+            debt_ceiling: Some(debt_ceiling),
+            ..Default::default()
+        })
+    };
+    for debt_ceiling in 0..3 {
+        record_event(&upgrade(debt_ceiling));
+    }
+    let tip = chain_tip();
+    let archive_id = Principal::from_slice(&[1]);
+
+    drop_archived_events(2, archive_id);
+    assert_eq!(first_event_index(), 2);
+    assert_eq!(count_events(), 3);
+    assert_eq!(events_from(2).collect::<Vec<_>>(), vec![upgrade(2)]);
+    assert_eq!(read_raw_events(0, 2), Vec::<Vec<u8>>::new());
+    assert_eq!(chain_tip(), tip);
+    assert_eq!(chain_hash_at(3), tip);
+    assert_eq!(
+        split_archived_range(1, 10),
+        (
+            Some(ArchivedEvents {
+                start: 1,
+                length: 1,
+                archive_id
+            }),
+            2..3
+        )
+    );
+
+    // The events are recorded after the live ones.
+    record_event(&upgrade(3));
+    assert_eq!(events_from(2).count(), 2);
+    assert_eq!(check_event_chain(0), Ok(()));
+
+    drop_archived_events(4, archive_id);
+    assert_eq!(count_events(), 4);
+    assert_eq!(events_from(4).count(), 0);
+    assert_eq!(split_archived_range(5, 10), (None, 4..4));
+    assert_eq!(check_event_chain(0), Ok(()));
 }
@@ -0,0 +1,462 @@
+//! An index of the events by principal, serving the history of a user
+//! without downloading the whole event log.
+
+use super::{
+    count_events, decode_event, events_from, read_raw_events, VMem, INDEXED_EVENT_COUNT_MEMORY_ID,
+    MEMORY_MANAGER, POSITION_OWNERS_MEMORY_ID, USER_HISTORY_MEMORY_ID, WITHDRAWAL_OWNERS_MEMORY_ID,
+};
+use crate::state::eventlog::Event;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, StableCell, Storable};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum HistoryKind {
+    Swap,
+    /// Liquidity added, removed or queued for withdrawal.
+    Liquidity,
+    LeveragePosition,
+    /// Liquidity rewards claimed or compounded.
+    Rewards,
+    /// Deposits, withdrawals and payouts of the internal balances.
+    Balance,
+}
+
+impl HistoryKind {
+    fn to_u64(self) -> u64 {
+        match self {
+            Self::Swap => 0,
+            Self::Liquidity => 1,
+            Self::LeveragePosition => 2,
+            Self::Rewards => 3,
+            Self::Balance => 4,
+        }
+    }
+
+    fn from_u64(kind: u64) -> Self {
+        match kind {
+            0 => Self::Swap,
+            1 => Self::Liquidity,
+            2 => Self::LeveragePosition,
+            3 => Self::Rewards,
+            4 => Self::Balance,
+            _ => panic!("bug: unknown history kind {}", kind),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetUserHistoryArg {
+    pub owner: Principal,
+    pub start: u64,
+    pub length: u64,
+    /// Only the items of these kinds are returned, all of them if not set.
+    pub kinds: Option<Vec<HistoryKind>>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct HistoryItem {
+    /// The index of the event in the log.
+    pub event_index: u64,
+    pub kind: HistoryKind,
+    pub timestamp: Option<u64>,
+    /// The ledger block index of the operation.
+    pub block_index: Option<u64>,
+    /// Not set once the event is archived, the archive serves it.
+    pub event: Option<Event>,
+}
+
+const PRINCIPAL_MAX_LENGTH: usize = 29;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct UserEventKey {
+    owner: Principal,
+    event_index: u64,
+}
+
+impl Storable for UserEventKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let owner = self.owner.as_slice();
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.push(owner.len() as u8);
+        buf.extend_from_slice(owner);
+        buf.resize(1 + PRINCIPAL_MAX_LENGTH, 0);
+        buf.extend_from_slice(&self.event_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner_length = bytes[0] as usize;
+        let mut event_index = [0; 8];
+        event_index.copy_from_slice(&bytes[1 + PRINCIPAL_MAX_LENGTH..]);
+        Self {
+            owner: Principal::from_slice(&bytes[1..1 + owner_length]),
+            event_index: u64::from_be_bytes(event_index),
+        }
+    }
+}
+
+impl BoundedStorable for UserEventKey {
+    const MAX_SIZE: u32 = 1 + PRINCIPAL_MAX_LENGTH as u32 + 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// The history item of an event, without the event which the
+/// archive serves once it is archived.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HistoryEntry {
+    kind: HistoryKind,
+    timestamp: Option<u64>,
+    block_index: Option<u64>,
+}
+
+impl Storable for HistoryEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.push(self.kind.to_u64() as u8);
+        for value in [self.timestamp, self.block_index] {
+            buf.push(value.is_some() as u8);
+            buf.extend_from_slice(&value.unwrap_or_default().to_be_bytes());
+        }
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let read_value = |pos: usize| {
+            let mut value = [0; 8];
+            value.copy_from_slice(&bytes[pos + 1..pos + 9]);
+            (bytes[pos] == 1).then(|| u64::from_be_bytes(value))
+        };
+        Self {
+            kind: HistoryKind::from_u64(bytes[0] as u64),
+            timestamp: read_value(1),
+            block_index: read_value(10),
+        }
+    }
+}
+
+impl BoundedStorable for HistoryEntry {
+    const MAX_SIZE: u32 = 1 + 2 * 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    /// Maps the events of each principal to their history entry.
+    static USER_HISTORY: RefCell<StableBTreeMap<UserEventKey, HistoryEntry, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(USER_HISTORY_MEMORY_ID))));
+
+    /// Maps the queued liquidity withdrawals to their owner.
+    static WITHDRAWAL_OWNERS: RefCell<StableBTreeMap<u64, Blob<PRINCIPAL_MAX_LENGTH>, VMem>> =
+        MEMORY_MANAGER
+            .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(WITHDRAWAL_OWNERS_MEMORY_ID))));
+
+    /// Maps the deposit block index of the leverage positions to their owner.
+    static POSITION_OWNERS: RefCell<StableBTreeMap<u64, Blob<PRINCIPAL_MAX_LENGTH>, VMem>> =
+        MEMORY_MANAGER
+            .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(POSITION_OWNERS_MEMORY_ID))));
+
+    /// The number of events already indexed.
+    static INDEXED_EVENT_COUNT: RefCell<StableCell<u64, VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableCell::init(m.borrow().get(INDEXED_EVENT_COUNT_MEMORY_ID), 0)
+                      .expect("failed to initialize the indexed event count")
+              )
+        );
+}
+
+fn read_event(event_index: u64) -> Option<Event> {
+    read_raw_events(event_index, 1)
+        .pop()
+        .map(|entry| decode_event(&entry))
+}
+
+fn position_owner(deposit_block_index: u64) -> Option<Principal> {
+    POSITION_OWNERS
+        .with(|p| p.borrow().get(&deposit_block_index))
+        .map(|owner| Principal::from_slice(owner.as_slice()))
+}
+
+fn withdrawal_owner(id: u64) -> Option<Principal> {
+    WITHDRAWAL_OWNERS
+        .with(|w| w.borrow().get(&id))
+        .map(|owner| Principal::from_slice(owner.as_slice()))
+}
+
+/// Returns the principal whose history holds `event`.
+fn classify_event(event: &Event) -> Option<(Principal, HistoryKind)> {
+    match event {
+        Event::Swap(swap) => Some((swap.caller, HistoryKind::Swap)),
+        Event::Liquidity(liquidity) => Some((liquidity.caller, HistoryKind::Liquidity)),
+        Event::RequestLiquidityWithdrawal(withdrawal) => {
+            Some((withdrawal.owner, HistoryKind::Liquidity))
+        }
+        Event::CancelLiquidityWithdrawal { id } | Event::ServeLiquidityWithdrawal { id } => {
+            withdrawal_owner(*id).map(|owner| (owner, HistoryKind::Liquidity))
+        }
+        Event::OpenLeveragePosition(position) => {
+            Some((position.owner, HistoryKind::LeveragePosition))
+        }
+        Event::CloseLeveragePosition {
+            deposit_block_index,
+            ..
+        }
+        | Event::LiquidateLeveragePosition {
+            deposit_block_index,
+            ..
+        } => {
+            position_owner(*deposit_block_index).map(|owner| (owner, HistoryKind::LeveragePosition))
+        }
+        Event::ClaimLiquidityRewards { owner, .. }
+        | Event::ClaimAllLiquidityRewards { owner }
+        | Event::CompoundLiquidityRewards { owner, .. } => Some((*owner, HistoryKind::Rewards)),
+        Event::Deposit { owner, .. }
+        | Event::Withdraw { owner, .. }
+        | Event::CreditBalance { owner, .. }
+        | Event::DebitBalance { owner, .. }
+        | Event::ReclaimDeposit { owner, .. } => Some((*owner, HistoryKind::Balance)),
+        // The settings and the events only referring to other events
+        // are not part of the history.
+        Event::Init(_)
+        | Event::Upgrade(_)
+        | Event::SwapSuccess(_)
+        | Event::SetAutoCompound { .. }
+        | Event::SetInternalBalancePayouts { .. }
+        | Event::StartGlobalSettlement { .. }
+        | Event::FinalizeGlobalSettlement { .. }
+        | Event::PriceUpdate(_)
+        | Event::WithdrawTreasury { .. } => None,
+    }
+}
+
+fn to_blob(principal: &Principal) -> Blob<PRINCIPAL_MAX_LENGTH> {
+    Blob::try_from(principal.as_slice()).expect("bug: principals are at most 29 bytes long")
+}
+
+fn index_event(event_index: u64, event: &Event) {
+    if let Event::OpenLeveragePosition(position) = event {
+        POSITION_OWNERS.with(|p| {
+            p.borrow_mut()
+                .insert(position.deposit_block_index, to_blob(&position.owner))
+        });
+    }
+    if let Event::RequestLiquidityWithdrawal(withdrawal) = event {
+        WITHDRAWAL_OWNERS.with(|w| {
+            w.borrow_mut()
+                .insert(withdrawal.id, to_blob(&withdrawal.owner))
+        });
+    }
+    if let Some((owner, kind)) = classify_event(event) {
+        let entry = HistoryEntry {
+            kind,
+            timestamp: event_timestamp(event),
+            block_index: event_block_index(event),
+        };
+        USER_HISTORY.with(|index| {
+            index
+                .borrow_mut()
+                .insert(UserEventKey { owner, event_index }, entry)
+        });
+    }
+}
+
+/// Indexes the events recorded since the last call, the whole log
+/// the first time.
+pub fn index_new_events() {
+    let indexed_event_count = INDEXED_EVENT_COUNT.with(|c| *c.borrow().get());
+    let event_count = count_events();
+    for (event_index, event) in
+        (indexed_event_count..event_count).zip(events_from(indexed_event_count))
+    {
+        index_event(event_index, &event);
+    }
+    INDEXED_EVENT_COUNT.with(|c| {
+        c.borrow_mut()
+            .set(event_count)
+            .expect("failed to update the indexed event count")
+    });
+}
+
+fn event_timestamp(event: &Event) -> Option<u64> {
+    match event {
+        Event::Swap(swap) => Some(swap.timestamp),
+        Event::Liquidity(liquidity) => Some(liquidity.timestamp),
+        Event::RequestLiquidityWithdrawal(withdrawal) => Some(withdrawal.timestamp),
+        Event::OpenLeveragePosition(position) => Some(position.timestamp),
+        Event::CloseLeveragePosition { timestamp, .. }
+        | Event::LiquidateLeveragePosition { timestamp, .. } => Some(*timestamp),
+        _ => None,
+    }
+}
+
+fn event_block_index(event: &Event) -> Option<u64> {
+    match event {
+        Event::Swap(swap) => Some(swap.from_block_index),
+        Event::Liquidity(liquidity) => Some(liquidity.block_index),
+        Event::OpenLeveragePosition(position) => Some(position.deposit_block_index),
+        Event::CloseLeveragePosition {
+            output_block_index, ..
+        } => *output_block_index,
+        Event::ClaimLiquidityRewards { block_index, .. }
+        | Event::Deposit { block_index, .. }
+        | Event::CreditBalance { block_index, .. }
+        | Event::DebitBalance { block_index, .. }
+        | Event::ReclaimDeposit { block_index, .. } => Some(*block_index),
+        _ => None,
+    }
+}
+
+/// Returns the history of `arg.owner`, oldest items first.
+pub fn get_user_history(arg: &GetUserHistoryArg) -> Vec<HistoryItem> {
+    let is_requested = |kind: &HistoryKind| match &arg.kinds {
+        Some(kinds) => kinds.contains(kind),
+        None => true,
+    };
+    let first = UserEventKey {
+        owner: arg.owner,
+        event_index: 0,
+    };
+    let last = UserEventKey {
+        owner: arg.owner,
+        event_index: u64::MAX,
+    };
+    let entries: Vec<(u64, HistoryEntry)> = USER_HISTORY.with(|index| {
+        index
+            .borrow()
+            .range(first..=last)
+            .map(|(key, entry)| (key.event_index, entry))
+            .filter(|(_, entry)| is_requested(&entry.kind))
+            .skip(arg.start as usize)
+            .take(arg.length as usize)
+            .collect()
+    });
+    entries
+        .into_iter()
+        .map(|(event_index, entry)| HistoryItem {
+            event_index,
+            kind: entry.kind,
+            timestamp: entry.timestamp,
+            block_index: entry.block_index,
+            // The archived events are no longer in the log.
+            event: read_event(event_index),
+        })
+        .collect()
+}
+
+#[test]
+fn test_user_history() {
+    use super::{drop_archived_events, record_event};
+    use crate::state::{Asset, IcpPrice, LeveragePosition};
+    use crate::updates::liquidity::{Liquidity, LiquidityType, LiquidityWithdrawal};
+
+    let alice = Principal::from_slice(&[1]);
+    let bob = Principal::from_slice(&[2]);
+
+    record_event(&Event::Liquidity(Liquidity {
+        caller: alice,
+        operation_type: LiquidityType::Add,
+        amount: 100_000_000,
+        block_index: 1,
+        timestamp: 10,
+        fee: 0,
+        to_account: None,
+    }));
+    record_event(&Event::OpenLeveragePosition(LeveragePosition {
+        owner: bob,
+        amount: 500_000_000,
+        covered_amount: 500_000_000,
+        take_profit: 1_500_000_000,
+        timestamp: 20,
+        icp_entry_price: IcpPrice {
+            rate: 1_000_000_000,
+        },
+        deposit_block_index: 2,
+        fee: 0,
+    }));
+    record_event(&Event::SetAutoCompound {
+        owner: alice,
+        enabled: true,
+    });
+    record_event(&Event::CloseLeveragePosition {
+        deposit_block_index: 2,
+        output_block_index: Some(3),
+        fee: 0,
+        timestamp: 30,
+        icp_price: IcpPrice {
+            rate: 1_100_000_000,
+        },
+        to_account: None,
+    });
+    record_event(&Event::Deposit {
+        owner: alice,
+        asset: Asset::ICP,
+        amount: 1_000,
+        block_index: 4,
+    });
+    record_event(&Event::RequestLiquidityWithdrawal(LiquidityWithdrawal {
+        id: 0,
+        owner: alice,
+        amount: 1_000,
+        timestamp: 50,
+        to_account: None,
+    }));
+    record_event(&Event::ServeLiquidityWithdrawal { id: 0 });
+
+    let history = |owner, start, length, kinds| {
+        get_user_history(&GetUserHistoryArg {
+            owner,
+            start,
+            length,
+            kinds,
+        })
+        .iter()
+        .map(|item| {
+            (
+                item.event_index,
+                item.kind,
+                item.timestamp,
+                item.block_index,
+            )
+        })
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        history(alice, 0, 10, None),
+        vec![
+            (0, HistoryKind::Liquidity, Some(10), Some(1)),
+            (4, HistoryKind::Balance, None, Some(4)),
+            (5, HistoryKind::Liquidity, Some(50), None),
+            (6, HistoryKind::Liquidity, None, None),
+        ]
+    );
+    // The close of a position is indexed under the owner of the position.
+    assert_eq!(
+        history(bob, 0, 10, None),
+        vec![
+            (1, HistoryKind::LeveragePosition, Some(20), Some(2)),
+            (3, HistoryKind::LeveragePosition, Some(30), Some(3)),
+        ]
+    );
+    assert_eq!(history(bob, 1, 10, None).len(), 1);
+    assert_eq!(
+        history(alice, 0, 10, Some(vec![HistoryKind::Balance])),
+        vec![(4, HistoryKind::Balance, None, Some(4))]
+    );
+    assert_eq!(history(Principal::anonymous(), 0, 10, None), vec![]);
+
+    // The archived items keep their timestamp and block index.
+    drop_archived_events(4, Principal::management_canister());
+    let items = get_user_history(&GetUserHistoryArg {
+        owner: bob,
+        start: 0,
+        length: 10,
+        kinds: None,
+    });
+    assert_eq!(items[1].timestamp, Some(30));
+    assert_eq!(items[1].block_index, Some(3));
+    assert_eq!(items[1].event, None);
+}
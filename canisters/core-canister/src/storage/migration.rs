@@ -0,0 +1,399 @@
+//! Decoding of the events and the snapshots recorded by the previous
+//! versions of the canister.
+//!
+//! Changing the shape of a recorded event bumps [super::EVENT_VERSION] and adds an
+//! upgrade function from the shapes of the previous version, so that the
+//! whole event log stays decodable across upgrades.
+//!
+//! Likewise, changing the layout of the state or the replay of the events
+//! bumps [super::SNAPSHOT_VERSION] and adds an upgrade function from the
+//! snapshots of the previous version, so that an upgrade replays from the
+//! latest snapshot without the archived events.
+
+use super::StateSnapshot;
+use crate::state::eventlog::Event;
+use candid::Principal;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct EventEnvelope<T> {
+    #[serde(rename = "e")]
+    event: T,
+}
+
+/// The shapes of the unversioned events which differ from version 1.
+#[derive(Deserialize)]
+enum EventV0 {
+    #[serde(rename = "claim_liquidity_rewards")]
+    ClaimLiquidityRewards { owner: Principal },
+}
+
+fn from_cbor<T: DeserializeOwned>(buf: &[u8]) -> Result<T, String> {
+    ciborium::de::from_reader(buf).map_err(|e| format!("{:?}", e))
+}
+
+/// Decodes an event recorded with the given version.
+pub fn decode_event(version: u32, buf: &[u8]) -> Result<Event, String> {
+    match version {
+        0 => decode_event_v0(buf),
+        1 => from_cbor::<EventEnvelope<Event>>(buf).map(|envelope| envelope.event),
+        _ => Err(format!("unknown event version {}", version)),
+    }
+}
+
+/// Decodes a snapshot recorded with the given version, its state
+/// upgraded to the current layout.
+pub fn decode_snapshot(version: u32, buf: &[u8]) -> Result<StateSnapshot, String> {
+    match version {
+        1 => from_cbor::<StateSnapshot>(buf),
+        _ => Err(format!("unknown snapshot version {}", version)),
+    }
+}
+
+/// The events recorded before the envelope mostly share the shapes of
+/// version 1, only the [EventV0] ones need an upgrade.
+fn decode_event_v0(buf: &[u8]) -> Result<Event, String> {
+    match from_cbor::<Event>(buf) {
+        Ok(event) => Ok(event),
+        Err(e) => from_cbor::<EventV0>(buf)
+            .map(upgrade_event_v0)
+            .map_err(|_| e),
+    }
+}
+
+fn upgrade_event_v0(event: EventV0) -> Event {
+    match event {
+        // The claimed amount was not recorded, all the rewards were claimed.
+        EventV0::ClaimLiquidityRewards { owner } => Event::ClaimAllLiquidityRewards { owner },
+    }
+}
+
+/// The shapes recorded by the baseline release, before the events were
+/// versioned, which generate the `test_data/events_v0.cbor` golden log.
+#[cfg(test)]
+mod golden_v0 {
+    use candid::Principal;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    enum Mode {
+        GeneralAvailability,
+    }
+
+    #[derive(Serialize)]
+    struct InitArgs {
+        mode: Mode,
+        eusd_ledger_principal: Option<Principal>,
+        xrc_principal: Option<Principal>,
+        icp_ledger_principal: Option<Principal>,
+        min_amount_to_stable: Option<u64>,
+        min_amount_from_stable: Option<u64>,
+        min_amount_leverage: Option<u64>,
+        min_amount_liquidity: Option<u64>,
+    }
+
+    #[derive(Serialize)]
+    struct UpgradeArgs {}
+
+    #[derive(Serialize)]
+    enum Asset {
+        ICP,
+        EUSD,
+    }
+
+    #[derive(Serialize)]
+    struct IcpPrice {
+        rate: u64,
+    }
+
+    #[derive(Serialize)]
+    struct LeveragePosition {
+        owner: Principal,
+        amount: u64,
+        covered_amount: u64,
+        take_profit: u64,
+        timestamp: u64,
+        icp_entry_price: IcpPrice,
+        deposit_block_index: u64,
+        fee: u64,
+    }
+
+    #[derive(Serialize)]
+    struct Swap {
+        caller: Principal,
+        from: Asset,
+        from_block_index: u64,
+        from_amount: u64,
+        to: Asset,
+        rate: u64,
+        fee: u64,
+        timestamp: u64,
+    }
+
+    #[derive(Serialize)]
+    struct SwapSuccess {
+        from_block_index: u64,
+        to_block_index: u64,
+    }
+
+    #[derive(Serialize)]
+    enum LiquidityType {
+        Add,
+    }
+
+    #[derive(Serialize)]
+    struct Liquidity {
+        caller: Principal,
+        operation_type: LiquidityType,
+        amount: u64,
+        block_index: u64,
+        timestamp: u64,
+        fee: u64,
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        #[serde(rename = "init")]
+        Init(InitArgs),
+        #[serde(rename = "upgrade")]
+        Upgrade(UpgradeArgs),
+        #[serde(rename = "open_leverage_position")]
+        OpenLeveragePosition(LeveragePosition),
+        #[serde(rename = "close_leverage_position")]
+        CloseLeveragePosition {
+            deposit_block_index: u64,
+            output_block_index: Option<u64>,
+            fee: u64,
+            timestamp: u64,
+            icp_price: IcpPrice,
+        },
+        #[serde(rename = "swap")]
+        Swap(Swap),
+        #[serde(rename = "swap_success")]
+        SwapSuccess(SwapSuccess),
+        #[serde(rename = "liquidity")]
+        Liquidity(Liquidity),
+        #[serde(rename = "claim_liquidity_rewards")]
+        ClaimLiquidityRewards { owner: Principal },
+    }
+
+    const T0: u64 = 1_680_000_000_000_000_000;
+
+    fn events() -> Vec<Event> {
+        let user = |n: u8| Principal::from_slice(&[n]);
+        vec![
+            Event::Init(InitArgs {
+                mode: Mode::GeneralAvailability,
+                eusd_ledger_principal: None,
+                xrc_principal: None,
+                icp_ledger_principal: None,
+                min_amount_to_stable: None,
+                min_amount_from_stable: None,
+                min_amount_leverage: None,
+                min_amount_liquidity: None,
+            }),
+            Event::Liquidity(Liquidity {
+                caller: user(1),
+                operation_type: LiquidityType::Add,
+                amount: 100_000_000,
+                block_index: 1,
+                timestamp: T0,
+                fee: 0,
+            }),
+            Event::Swap(Swap {
+                caller: user(3),
+                from: Asset::ICP,
+                from_block_index: 3,
+                from_amount: 1_000_000_000,
+                to: Asset::EUSD,
+                rate: 1_000_000_000,
+                fee: 5_000_000,
+                timestamp: T0 + 2,
+            }),
+            Event::SwapSuccess(SwapSuccess {
+                from_block_index: 3,
+                to_block_index: 4,
+            }),
+            Event::OpenLeveragePosition(LeveragePosition {
+                owner: user(2),
+                amount: 500_000_000,
+                covered_amount: 500_000_000,
+                take_profit: 1_500_000_000,
+                timestamp: T0 + 3,
+                icp_entry_price: IcpPrice {
+                    rate: 1_000_000_000,
+                },
+                deposit_block_index: 2,
+                fee: 1_000_000,
+            }),
+            Event::CloseLeveragePosition {
+                deposit_block_index: 2,
+                output_block_index: Some(5),
+                fee: 1_000_000,
+                timestamp: T0 + 4,
+                icp_price: IcpPrice { rate: 950_000_000 },
+            },
+            Event::Upgrade(UpgradeArgs {}),
+            Event::ClaimLiquidityRewards { owner: user(1) },
+        ]
+    }
+
+    /// Returns the golden log, a CBOR array of the encoded events.
+    pub fn encode_log() -> Vec<u8> {
+        use ciborium::value::Value;
+
+        let entries = events()
+            .iter()
+            .map(|event| {
+                let mut buf = vec![];
+                ciborium::ser::into_writer(event, &mut buf).expect("failed to encode an event");
+                Value::Bytes(buf)
+            })
+            .collect();
+        let mut log = vec![];
+        ciborium::ser::into_writer(&Value::Array(entries), &mut log)
+            .expect("failed to encode the golden log");
+        log
+    }
+}
+
+/// Regenerates the golden log:
+/// `cargo test -p core-canister generate_events_v0 -- --ignored`.
+#[test]
+#[ignore]
+fn generate_events_v0() {
+    std::fs::write(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/events_v0.cbor"),
+        golden_v0::encode_log(),
+    )
+    .expect("failed to write the golden log");
+}
+
+#[test]
+fn test_events_v0_golden_log() {
+    assert_eq!(
+        golden_v0::encode_log(),
+        include_bytes!("../../test_data/events_v0.cbor")
+    );
+}
+
+#[cfg(test)]
+fn decode_golden_log(log: &[u8]) -> Vec<Event> {
+    use ciborium::value::Value;
+
+    let entries: Vec<Value> = ciborium::de::from_reader(log).expect("bad golden log");
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            Value::Bytes(buf) => super::decode_event(&buf),
+            _ => panic!("bad golden log entry"),
+        })
+        .collect()
+}
+
+#[test]
+fn test_decode_events_v0() {
+    use crate::state::eventlog::replay;
+    use crate::state::{Asset, IcpPrice};
+    use crate::updates::swap::{Swap, SwapSuccess};
+    use candid::Principal;
+
+    let events = decode_golden_log(include_bytes!("../../test_data/events_v0.cbor"));
+    assert_eq!(events.len(), 8);
+    assert_eq!(
+        events[2],
+        Event::Swap(Swap {
+            caller: Principal::from_slice(&[3]),
+            from: Asset::ICP,
+            from_block_index: 3,
+            from_amount: 1_000_000_000,
+            to: Asset::EUSD,
+            rate: 1_000_000_000,
+            fee: 5_000_000,
+            timestamp: 1_680_000_000_000_000_002,
+            to_account: None,
+            fee_rate: None,
+        })
+    );
+    assert_eq!(
+        events[3],
+        Event::SwapSuccess(SwapSuccess {
+            from_asset: None,
+            from_block_index: 3,
+            to_block_index: 4,
+        })
+    );
+    assert_eq!(
+        events[5],
+        Event::CloseLeveragePosition {
+            deposit_block_index: 2,
+            output_block_index: Some(5),
+            fee: 1_000_000,
+            timestamp: 1_680_000_000_000_000_004,
+            icp_price: IcpPrice { rate: 950_000_000 },
+            to_account: None,
+        }
+    );
+    assert_eq!(
+        events[7],
+        Event::ClaimAllLiquidityRewards {
+            owner: Principal::from_slice(&[1])
+        }
+    );
+
+    let state = replay(events.clone().into_iter()).expect("failed to replay the v0 log");
+    assert_eq!(state.icp_liqudity_amount, 100_000_000);
+    assert!(state.open_swaps.is_empty());
+    assert_eq!(state.total_eusd_minted, 9_950_000_000);
+    // The swapped ICP and the loss of the closed position.
+    assert_eq!(state.icp_collateral_amount, 995_000_000 + 26_315_785);
+    assert_eq!(state.leverage_positions.len(), 1);
+    assert!(state.leverage_positions[&Principal::from_slice(&[2])].is_empty());
+    assert!(state.liquidity_rewards.is_empty());
+    // The events recorded before the price updates carry the prices.
+    assert!(!state.has_price_updates);
+    assert_eq!(state.icp_prices.len(), 3);
+    assert_eq!(
+        state.get_last_icp_price(),
+        Some(IcpPrice { rate: 950_000_000 })
+    );
+
+    // The upgraded events are recorded with the current version.
+    for event in events {
+        assert_eq!(
+            super::decode_event(&super::encode_event(&event, super::GENESIS_HASH)),
+            event
+        );
+    }
+}
+
+#[test]
+fn test_decode_claim_liquidity_rewards_v0() {
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    enum LegacyEvent {
+        #[serde(rename = "claim_liquidity_rewards")]
+        ClaimLiquidityRewards { owner: Principal },
+    }
+
+    let owner = Principal::from_slice(&[1]);
+    let mut buf = vec![];
+    ciborium::ser::into_writer(&LegacyEvent::ClaimLiquidityRewards { owner }, &mut buf)
+        .expect("failed to encode the legacy event");
+    assert_eq!(
+        super::decode_event(&buf),
+        Event::ClaimAllLiquidityRewards { owner }
+    );
+}
+
+#[test]
+fn test_unknown_event_version() {
+    assert!(decode_event(super::EVENT_VERSION + 1, &[]).is_err());
+}
+
+#[test]
+fn test_unknown_snapshot_version() {
+    assert!(decode_snapshot(super::SNAPSHOT_VERSION + 1, &[]).is_err());
+}
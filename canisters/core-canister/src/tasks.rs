@@ -16,6 +16,11 @@ pub enum TaskType {
     FetchPrice,
     CheckLeveragePositions,
     CloseLeveragePosition(LeveragePosition),
+    ProcessLiquidityWithdrawals,
+    ProcessGlobalSettlement,
+    TakeSnapshot,
+    ArchiveEvents,
+    Audit,
 }
 
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -1,8 +1,9 @@
-use crate::state::IcpPrice;
+use crate::price::{PriceMetadata, PriceUpdate};
+use crate::state::audit::record_price_update;
+use crate::state::Asset;
 use crate::tasks::schedule_now;
 use crate::updates::leverage::compute_pnl;
 use crate::{management::get_exchange_rate, state::mutate_state};
-use ic_ledger_types::Timestamp;
 use ic_xrc_types::GetExchangeRateResult;
 use scopeguard::guard;
 use std::time::Duration;
@@ -19,6 +20,51 @@ fn convert_to_8_decimals(amount: u64, decimals: u32) -> u64 {
     }
 }
 
+/// Fetches the e8s USD price of `asset` from the exchange rate canister.
+async fn fetch_price(asset: Asset, xrc_symbol: &str) -> Option<PriceUpdate> {
+    match get_exchange_rate(xrc_symbol).await {
+        Ok(GetExchangeRateResult::Ok(exchange_rate_result)) => {
+            let rate = convert_to_8_decimals(
+                exchange_rate_result.rate,
+                exchange_rate_result.metadata.decimals,
+            );
+            let timestamp = exchange_rate_result.timestamp * crate::SEC_NANOS;
+            // The prices are pruned relative to the latest one, a price
+            // from the future would drop the recent history.
+            if timestamp > ic_cdk::api::time() {
+                ic_canister_log::log!(
+                    crate::logs::P0,
+                    "[fetch_price]: rejected the {xrc_symbol} price at {timestamp}, it is in the future"
+                );
+                return None;
+            }
+            Some(PriceUpdate {
+                asset,
+                rate,
+                timestamp,
+                source: crate::read_state(|s| s.xrc_principal),
+                metadata: PriceMetadata::from(&exchange_rate_result.metadata),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Schedules the periodic tasks.
+pub fn schedule_startup_tasks() {
+    use crate::tasks::TaskType;
+
+    schedule_now(TaskType::FetchPrice);
+    // schedule_now(TaskType::ProtocolBalanceUpdate);
+    schedule_now(TaskType::CheckLeveragePositions);
+    schedule_now(TaskType::TakeSnapshot);
+    schedule_now(TaskType::ArchiveEvents);
+    schedule_now(TaskType::Audit);
+    if crate::read_state(|s| s.global_settlement.is_some()) {
+        schedule_now(TaskType::ProcessGlobalSettlement);
+    }
+}
+
 pub fn timer() {
     use crate::tasks::{pop_if_ready, schedule_after, TaskType};
 
@@ -47,25 +93,26 @@ pub fn timer() {
         TaskType::FetchPrice => {
             ic_cdk::spawn(async {
                 const FETCH_RETRY_DELAY_MINUTES: u64 = 10 * 60;
-                let call_result = get_exchange_rate().await;
-                if let Ok(GetExchangeRateResult::Ok(exchange_rate_result)) = call_result {
-                    let icp_price_e8s = convert_to_8_decimals(
-                        exchange_rate_result.rate,
-                        exchange_rate_result.metadata.decimals,
-                    );
-                    mutate_state(|s| {
-                        s.icp_prices.insert(
-                            Timestamp {
-                                timestamp_nanos: exchange_rate_result.timestamp,
-                            },
-                            IcpPrice {
-                                rate: icp_price_e8s,
-                            },
-                        )
-                    });
+                if let Some(update) = fetch_price(Asset::ICP, "ICP").await {
+                    mutate_state(|s| record_price_update(s, update));
                     // We have a new price entry we should check the
                     // leverage positions that we have.
                     schedule_now(TaskType::CheckLeveragePositions);
+                    // The collateral ratio moved, queued withdrawals
+                    // might be served.
+                    schedule_now(TaskType::ProcessLiquidityWithdrawals);
+                }
+                let collaterals: Vec<_> = crate::read_state(|s| {
+                    s.collaterals
+                        .values()
+                        .map(|c| (c.symbol.clone(), c.xrc_symbol.clone()))
+                        .collect()
+                });
+                for (symbol, xrc_symbol) in collaterals {
+                    if let Some(update) = fetch_price(Asset::Collateral(symbol), &xrc_symbol).await
+                    {
+                        mutate_state(|s| record_price_update(s, update));
+                    }
                 }
                 // We fetch data price every 2 minutes
                 // Even if the call failed.
@@ -76,14 +123,11 @@ pub fn timer() {
             });
         }
         TaskType::ProtocolBalanceUpdate => ic_cdk::spawn(async {
-            if let Ok(balance) = crate::management::balance_of(ic_cdk::id()).await {
+            let icp_ledger = crate::read_state(|s| s.icp_ledger_principal);
+            let main_account = crate::management::main_account();
+            if let Ok(balance) = crate::management::balance_of(icp_ledger, main_account).await {
                 mutate_state(|s| s.protocol_balance = balance);
-                let known_balance = crate::read_state(|s| {
-                    s.icp_collateral_amount
-                        + s.icp_liqudity_amount
-                        + s.icp_leverage_margin_amount
-                        + s.liquidity_rewards.values().sum::<u64>()
-                });
+                let known_balance = crate::read_state(|s| s.get_known_icp_balance());
                 debug_assert!(known_balance <= balance);
             }
             schedule_after(Duration::from_secs(1), TaskType::ProtocolBalanceUpdate);
@@ -91,8 +135,58 @@ pub fn timer() {
         TaskType::CheckLeveragePositions => ic_cdk::spawn(async {
             crate::updates::leverage::check_leverage_positions().await;
         }),
+        TaskType::ProcessLiquidityWithdrawals => ic_cdk::spawn(async {
+            crate::updates::liquidity::process_liquidity_withdrawals().await;
+        }),
+        TaskType::TakeSnapshot => {
+            use crate::storage::{
+                count_events, last_snapshot_event_count, SNAPSHOT_INTERVAL_EVENTS,
+            };
+
+            const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+            if count_events() >= last_snapshot_event_count() + SNAPSHOT_INTERVAL_EVENTS {
+                crate::read_state(crate::storage::record_snapshot);
+                ic_canister_log::log!(
+                    crate::logs::P0,
+                    "[take_snapshot]: recorded a snapshot at event {}",
+                    count_events()
+                );
+            }
+            schedule_after(SNAPSHOT_CHECK_INTERVAL, TaskType::TakeSnapshot);
+        }
+        TaskType::ArchiveEvents => ic_cdk::spawn(async {
+            const ARCHIVE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+            crate::archive::archive_events().await;
+            schedule_after(ARCHIVE_CHECK_INTERVAL, TaskType::ArchiveEvents);
+        }),
+        TaskType::Audit => ic_cdk::spawn(async {
+            const AUDIT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+            // Reconcile with a fresh ledger balance.
+            let icp_ledger = crate::read_state(|s| s.icp_ledger_principal);
+            let main_account = crate::management::main_account();
+            if let Ok(balance) = crate::management::balance_of(icp_ledger, main_account).await {
+                mutate_state(|s| s.protocol_balance = balance);
+            }
+            crate::read_state(|s| crate::checks::record_audit(s, ic_cdk::api::time()));
+            schedule_after(AUDIT_INTERVAL, TaskType::Audit);
+        }),
+        TaskType::ProcessGlobalSettlement => ic_cdk::spawn(async {
+            crate::updates::settlement::process_global_settlement().await;
+        }),
         TaskType::CloseLeveragePosition(leverage_position) => ic_cdk::spawn(async {
-            let last_icp_price = crate::read_state(|s| s.get_last_icp_price()).unwrap();
+            let last_icp_price = match crate::read_state(|s| s.get_last_icp_price()) {
+                Some(price) => price,
+                None => {
+                    schedule_after(
+                        Duration::from_secs(60),
+                        TaskType::CloseLeveragePosition(leverage_position),
+                    );
+                    return;
+                }
+            };
             let deposit_block_index = leverage_position.deposit_block_index;
             let owner = leverage_position.owner;
             let now = ic_cdk::api::time();
@@ -105,7 +199,9 @@ pub fn timer() {
             };
             let protocol_fee =
                 crate::multiply_e8s(crate::read_state(|s| s.fees.base_fee), amount_to_transfer);
-            match crate::transfer_icp(None, owner, amount_to_transfer - protocol_fee).await {
+            match crate::updates::balance::pay_icp(owner, None, amount_to_transfer - protocol_fee)
+                .await
+            {
                 Ok(output_block_index) => {
                     crate::mutate_state(|s| {
                         crate::state::audit::record_close_leverage_position(
@@ -115,6 +211,7 @@ pub fn timer() {
                             protocol_fee,
                             now,
                             last_icp_price,
+                            None,
                         );
                     });
                 }
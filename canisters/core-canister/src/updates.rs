@@ -1,3 +1,7 @@
+pub mod balance;
+pub mod deposit;
 pub mod leverage;
 pub mod liquidity;
+pub mod settlement;
 pub mod swap;
+pub mod treasury;
@@ -0,0 +1,240 @@
+use crate::compute_subaccount;
+use crate::guard::GuardError;
+use crate::guard::{
+    balance_update_guard, convert_update_guard, leverage_update_guard, liquidity_update_guard,
+};
+use crate::management::{balance_of, mint_eusd, transfer, transfer_icp};
+use crate::state::audit::{
+    record_credit_balance, record_deposit, record_reclaim_deposit,
+    record_set_internal_balance_payouts, record_withdraw,
+};
+use crate::state::{mutate_state, read_state, Asset};
+use crate::updates::deposit::{pull_eusd, pull_icp, DepositMethod};
+use crate::{EUSD_TRANSFER_FEE, ICP_TRANSFER_FEE};
+use candid::{CandidType, Principal};
+use ic_base_types::PrincipalId;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub enum BalanceError {
+    LedgerError(TransferError),
+    AlreadyProcessing,
+    TemporarilyUnavailable(String),
+    AmountTooSmall,
+    InsufficientBalance(u64),
+    InvalidDepositMethod,
+    /// Collaterals other than ICP cannot be held in internal balances.
+    UnsupportedAsset,
+    UnknownCollateral(String),
+}
+
+impl From<GuardError> for BalanceError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => {
+                Self::TemporarilyUnavailable("too many concurrent requests".to_string())
+            }
+        }
+    }
+}
+
+/// Moves funds of the caller into its internal balance.
+pub async fn deposit(
+    asset: Asset,
+    amount: u64,
+    deposit_method: DepositMethod,
+) -> Result<u64, BalanceError> {
+    let caller = ic_cdk::caller();
+    let _guard = balance_update_guard(caller)?;
+
+    if deposit_method == DepositMethod::InternalBalance {
+        return Err(BalanceError::InvalidDepositMethod);
+    }
+    if amount == 0 {
+        return Err(BalanceError::AmountTooSmall);
+    }
+
+    let result = match asset {
+        Asset::ICP => pull_icp(caller, amount, deposit_method).await,
+        Asset::EUSD => pull_eusd(caller, amount, deposit_method).await,
+        Asset::Collateral(_) => return Err(BalanceError::UnsupportedAsset),
+    };
+    match result {
+        Ok(block_index) => {
+            mutate_state(|s| record_deposit(s, caller, asset, amount, block_index));
+            Ok(block_index)
+        }
+        Err(e) => Err(BalanceError::LedgerError(e)),
+    }
+}
+
+/// Sends `amount` from the internal balance of the caller to `to_account`.
+/// The ICP ledger fee is deducted from the amount sent.
+pub async fn withdraw(
+    asset: Asset,
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, BalanceError> {
+    let caller = ic_cdk::caller();
+    let _guard = balance_update_guard(caller)?;
+
+    let amount_to_send = match asset {
+        Asset::ICP => amount.saturating_sub(ICP_TRANSFER_FEE),
+        Asset::EUSD => amount,
+        Asset::Collateral(_) => return Err(BalanceError::UnsupportedAsset),
+    };
+    if amount_to_send == 0 {
+        return Err(BalanceError::AmountTooSmall);
+    }
+    let balance = read_state(|s| s.get_balance(&caller, &asset));
+    if balance < amount {
+        return Err(BalanceError::InsufficientBalance(balance));
+    }
+
+    // Debit before transferring as operations funded from the
+    // internal balance do not take the balance guard.
+    mutate_state(|s| record_withdraw(s, caller, asset.clone(), amount, to_account));
+    let to = to_account.unwrap_or(Account {
+        owner: caller,
+        subaccount: None,
+    });
+    let result = match asset {
+        Asset::ICP => transfer_icp(None, to, amount_to_send).await,
+        Asset::EUSD => mint_eusd(amount_to_send, to).await,
+        Asset::Collateral(_) => unreachable!("rejected above"),
+    };
+    match result {
+        Ok(block_index) => Ok(block_index),
+        Err(e) => {
+            mutate_state(|s| record_credit_balance(s, caller, asset, amount));
+            Err(BalanceError::LedgerError(e))
+        }
+    }
+}
+
+/// Sends back to the caller the funds left in its deposit subaccount,
+/// e.g. after an operation rejected the deposited amount.
+pub async fn reclaim_deposit(asset: Asset, nonce: u64) -> Result<u64, BalanceError> {
+    let caller = ic_cdk::caller();
+    // Every operation pulling from the deposit subaccount must be excluded.
+    let _liquidity_guard = liquidity_update_guard(caller)?;
+    let _leverage_guard = leverage_update_guard(caller)?;
+    let _convert_guard = convert_update_guard(caller)?;
+    let _balance_guard = balance_update_guard(caller)?;
+
+    let (ledger, fee) = match &asset {
+        Asset::ICP => (read_state(|s| s.icp_ledger_principal), ICP_TRANSFER_FEE),
+        Asset::EUSD => (read_state(|s| s.eusd_ledger_principal), EUSD_TRANSFER_FEE),
+        Asset::Collateral(symbol) => match read_state(|s| s.get_collateral(symbol).cloned()) {
+            Some(collateral) => (collateral.ledger_principal, collateral.transfer_fee),
+            None => return Err(BalanceError::UnknownCollateral(symbol.clone())),
+        },
+    };
+    let subaccount = compute_subaccount(PrincipalId(caller), nonce);
+    let deposit_account = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    };
+    let balance = balance_of(ledger, deposit_account)
+        .await
+        .map_err(BalanceError::LedgerError)?;
+    if balance <= fee {
+        return Err(BalanceError::AmountTooSmall);
+    }
+    let amount = balance - fee;
+    let to = Account {
+        owner: caller,
+        subaccount: None,
+    };
+    match transfer(ledger, Some(subaccount), to, amount).await {
+        Ok(block_index) => {
+            record_reclaim_deposit(caller, asset, amount, block_index);
+            Ok(block_index)
+        }
+        Err(e) => Err(BalanceError::LedgerError(e)),
+    }
+}
+
+pub fn set_internal_balance_payouts(enabled: bool) -> Result<(), BalanceError> {
+    let caller = ic_cdk::caller();
+    let _guard = balance_update_guard(caller)?;
+
+    if read_state(|s| s.internal_balance_payouts.contains(&caller)) != enabled {
+        mutate_state(|s| record_set_internal_balance_payouts(s, caller, enabled));
+    }
+    Ok(())
+}
+
+/// Credits the payout to the internal balance of `owner` if it opted in and
+/// no destination is given, returns `None` if the payout goes through the ledger.
+fn maybe_credit_balance(
+    owner: Principal,
+    to_account: Option<Account>,
+    asset: Asset,
+    amount: u64,
+) -> Option<u64> {
+    if to_account.is_some() || !read_state(|s| s.internal_balance_payouts.contains(&owner)) {
+        return None;
+    }
+    Some(mutate_state(|s| {
+        record_credit_balance(s, owner, asset, amount)
+    }))
+}
+
+/// Pays `amount` ICP to `owner`, see [maybe_credit_balance].
+pub async fn pay_icp(
+    owner: Principal,
+    to_account: Option<Account>,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    if let Some(block_index) = maybe_credit_balance(owner, to_account, Asset::ICP, amount) {
+        return Ok(block_index);
+    }
+    let to = to_account.unwrap_or(Account {
+        owner,
+        subaccount: None,
+    });
+    transfer_icp(None, to, amount).await
+}
+
+/// Mints `amount` eUSD to `owner`, see [maybe_credit_balance].
+pub async fn pay_eusd(
+    owner: Principal,
+    to_account: Option<Account>,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    if let Some(block_index) = maybe_credit_balance(owner, to_account, Asset::EUSD, amount) {
+        return Ok(block_index);
+    }
+    let to = to_account.unwrap_or(Account {
+        owner,
+        subaccount: None,
+    });
+    mint_eusd(amount, to).await
+}
+
+/// Sends `amount` of a registered collateral to `owner`, the ledger fee
+/// is deducted from the amount sent. Collateral payouts always go
+/// through the ledger.
+pub async fn pay_collateral(
+    symbol: &str,
+    owner: Principal,
+    to_account: Option<Account>,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    let collateral = read_state(|s| s.get_collateral(symbol).cloned())
+        .expect("bug: paying an unknown collateral");
+    let to = to_account.unwrap_or(Account {
+        owner,
+        subaccount: None,
+    });
+    transfer(
+        collateral.ledger_principal,
+        None,
+        to,
+        amount.saturating_sub(collateral.transfer_fee),
+    )
+    .await
+}
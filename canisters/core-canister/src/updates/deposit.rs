@@ -0,0 +1,120 @@
+use crate::collateral::CollateralConfig;
+use crate::compute_subaccount;
+use crate::management::{burn_eusd, main_account, transfer, transfer_from, transfer_icp};
+use crate::state::audit::record_debit_balance;
+use crate::state::{mutate_state, read_state, Asset};
+use candid::{CandidType, Nat, Principal};
+use ic_base_types::PrincipalId;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use serde::Deserialize;
+
+/// How the core canister collects the funds of an operation.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum DepositMethod {
+    /// The caller first transfers the funds to its deposit account
+    /// for the given nonce (0 if not set), see `get_deposit_account`.
+    Subaccount(Option<u64>),
+    /// The caller approves the core canister beforehand and the funds
+    /// are pulled from its default account with `icrc2_transfer_from`.
+    Icrc2,
+    /// The funds are debited from the internal balance of the caller,
+    /// see `deposit`.
+    InternalBalance,
+}
+
+impl Default for DepositMethod {
+    fn default() -> Self {
+        Self::Subaccount(None)
+    }
+}
+
+/// Debits `amount` from the internal balance of `caller`, returning
+/// the block index allocated to the debit.
+fn debit_internal_balance(
+    caller: Principal,
+    asset: Asset,
+    amount: u64,
+) -> Result<u64, TransferError> {
+    mutate_state(|s| {
+        let balance = s.get_balance(&caller, &asset);
+        if balance < amount {
+            return Err(TransferError::InsufficientFunds {
+                balance: Nat::from(balance),
+            });
+        }
+        Ok(record_debit_balance(s, caller, asset, amount))
+    })
+}
+
+fn caller_account(caller: Principal) -> Account {
+    Account {
+        owner: caller,
+        subaccount: None,
+    }
+}
+
+/// Moves `amount` ICP of `caller` to the main account of the core canister.
+pub async fn pull_icp(
+    caller: Principal,
+    amount: u64,
+    method: DepositMethod,
+) -> Result<u64, TransferError> {
+    match method {
+        DepositMethod::Subaccount(nonce) => {
+            let caller_subaccount = compute_subaccount(PrincipalId(caller), nonce.unwrap_or(0));
+            transfer_icp(Some(caller_subaccount), main_account(), amount).await
+        }
+        DepositMethod::Icrc2 => {
+            let icp_ledger = read_state(|s| s.icp_ledger_principal);
+            transfer_from(icp_ledger, caller_account(caller), amount).await
+        }
+        DepositMethod::InternalBalance => debit_internal_balance(caller, Asset::ICP, amount),
+    }
+}
+
+/// Burns `amount` eUSD of `caller`. Debiting the internal balance burns
+/// nothing as the eUSD was already burned when deposited.
+pub async fn pull_eusd(
+    caller: Principal,
+    amount: u64,
+    method: DepositMethod,
+) -> Result<u64, TransferError> {
+    match method {
+        DepositMethod::Subaccount(nonce) => burn_eusd(caller, nonce.unwrap_or(0), amount).await,
+        DepositMethod::Icrc2 => {
+            let eusd_ledger = read_state(|s| s.eusd_ledger_principal);
+            transfer_from(eusd_ledger, caller_account(caller), amount).await
+        }
+        DepositMethod::InternalBalance => debit_internal_balance(caller, Asset::EUSD, amount),
+    }
+}
+
+/// Moves `amount` of a registered collateral of `caller` to the main account
+/// of the core canister. Collaterals cannot be held in internal balances.
+pub async fn pull_collateral(
+    collateral: &CollateralConfig,
+    caller: Principal,
+    amount: u64,
+    method: DepositMethod,
+) -> Result<u64, TransferError> {
+    match method {
+        DepositMethod::Subaccount(nonce) => {
+            let caller_subaccount = compute_subaccount(PrincipalId(caller), nonce.unwrap_or(0));
+            transfer(
+                collateral.ledger_principal,
+                Some(caller_subaccount),
+                main_account(),
+                amount,
+            )
+            .await
+        }
+        DepositMethod::Icrc2 => {
+            transfer_from(collateral.ledger_principal, caller_account(caller), amount).await
+        }
+        DepositMethod::InternalBalance => Err(TransferError::GenericError {
+            error_code: Nat::from(0),
+            message: format!("{} cannot be held in internal balances", collateral.symbol),
+        }),
+    }
+}
@@ -1,4 +1,3 @@
-use crate::compute_subaccount;
 use crate::divide_e8s;
 use crate::guard::leverage_update_guard;
 use crate::guard::GuardError;
@@ -6,12 +5,15 @@ use crate::multiply_e8s;
 use crate::read_state;
 use crate::state::audit::record_liquidate_leverage_position;
 use crate::state::mutate_state;
+use crate::state::Asset;
 use crate::state::LeveragePosition;
-use crate::transfer_icp;
-use crate::PrincipalId;
+use crate::state::ProtocolRegime;
+use crate::updates::balance::pay_icp;
+use crate::updates::deposit::{pull_icp, DepositMethod};
 use crate::ICP_TRANSFER_FEE;
 use crate::ONE_HOUR_NANOS;
 use candid::CandidType;
+use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferError;
 
 #[derive(CandidType, serde::Deserialize)]
@@ -19,6 +21,8 @@ pub struct OpenLeveragePositionArg {
     pub amount: u64,
     pub take_profit: u64,
     pub covered_amount: u64,
+    /// How the position amount is collected, the deposit subaccount if not set.
+    pub deposit_method: Option<DepositMethod>,
 }
 
 #[derive(CandidType, serde::Deserialize, Debug)]
@@ -32,6 +36,12 @@ pub enum LeveragePositionError {
     NotEnoughFundsToCover,
     TemporarilyUnavailable(String),
     TooEarlyToClose,
+    /// No ICP price was fetched yet.
+    NoPriceData,
+    /// New positions are paused while the protocol is in recovery mode.
+    RecoveryMode,
+    /// The global settlement closes the positions itself.
+    GlobalSettlement,
 }
 
 impl From<GuardError> for LeveragePositionError {
@@ -53,6 +63,13 @@ pub async fn open_leverage_position(
     let caller = ic_cdk::caller();
     let _guard = leverage_update_guard(caller)?;
 
+    if read_state(|s| s.global_settlement.is_some()) {
+        return Err(LeveragePositionError::GlobalSettlement);
+    }
+    if read_state(|s| s.get_regime()) != ProtocolRegime::Normal {
+        return Err(LeveragePositionError::RecoveryMode);
+    }
+
     // Check if the position is not too big or too small.
     let available_coverable_amount = read_state(|s| s.get_leverage_coverable_amount());
     if arg.covered_amount > available_coverable_amount {
@@ -61,13 +78,13 @@ pub async fn open_leverage_position(
         return Err(LeveragePositionError::AmountTooSmall);
     }
 
-    // Tranfer ICP back to main account
-    let caller_subaccount = compute_subaccount(PrincipalId(caller), 0);
-    let core_id = ic_cdk::id();
+    let last_icp_price =
+        read_state(|s| s.get_last_icp_price()).ok_or(LeveragePositionError::NoPriceData)?;
 
-    match transfer_icp(Some(caller_subaccount), core_id, arg.amount).await {
+    // Tranfer ICP back to main account
+    let deposit_method = arg.deposit_method.unwrap_or_default();
+    match pull_icp(caller, arg.amount, deposit_method).await {
         Ok(block_index) => {
-            let last_icp_price = read_state(|s| s.get_last_icp_price()).unwrap();
             let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), arg.amount);
             let leverage_position = LeveragePosition {
                 owner: caller,
@@ -91,10 +108,15 @@ pub async fn open_leverage_position(
 
 pub async fn close_leverage_position(
     deposit_block_index: u64,
+    to_account: Option<Account>,
 ) -> Result<u64, LeveragePositionError> {
     let caller = ic_cdk::caller();
     let _guard = leverage_update_guard(caller)?;
 
+    if read_state(|s| s.global_settlement.is_some()) {
+        return Err(LeveragePositionError::GlobalSettlement);
+    }
+
     let position_to_close = read_state(|s| s.get_leverage_position(deposit_block_index));
     if position_to_close.is_none() {
         return Err(LeveragePositionError::PositionNotFound);
@@ -108,12 +130,13 @@ pub async fn close_leverage_position(
         return Err(LeveragePositionError::TooEarlyToClose);
     }
 
-    let last_icp_price = read_state(|s| s.get_last_icp_price()).unwrap();
+    let last_icp_price =
+        read_state(|s| s.get_last_icp_price()).ok_or(LeveragePositionError::NoPriceData)?;
     let amount_to_transfer = compute_cash_out_amount(&position_to_close, last_icp_price.rate);
     let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), amount_to_transfer);
-    match transfer_icp(
-        None,
+    match pay_icp(
         caller,
+        to_account,
         amount_to_transfer - protocol_fee - ICP_TRANSFER_FEE,
     )
     .await
@@ -127,6 +150,7 @@ pub async fn close_leverage_position(
                     protocol_fee,
                     now,
                     last_icp_price,
+                    to_account,
                 );
             });
             Ok(output_block_index)
@@ -150,12 +174,23 @@ pub fn compute_cash_out_amount(position: &LeveragePosition, current_icp_price: u
     if diff > 0 {
         (position.amount - position.fee) + diff as u64
     } else {
-        (position.amount - position.fee) - diff.unsigned_abs()
+        // The loss beyond the margin is covered by the protocol.
+        (position.amount - position.fee).saturating_sub(diff.unsigned_abs())
     }
 }
 
 pub async fn check_leverage_positions() {
-    let last_icp_price = read_state(|s| s.get_last_icp_price().unwrap());
+    if read_state(|s| s.global_settlement.is_some()) {
+        return;
+    }
+    let last_icp_price = match read_state(|s| s.get_last_icp_price()) {
+        Some(price) => price,
+        None => return,
+    };
+    // The liquidations use the average price, a short dip of the
+    // price does not liquidate the positions.
+    let average_icp_price = read_state(|s| s.get_average_price(&Asset::ICP, ic_cdk::api::time()))
+        .unwrap_or_else(|| last_icp_price.clone());
     for (_principal, positions) in read_state(|s| s.leverage_positions.clone()) {
         for position in positions {
             let now = ic_cdk::api::time();
@@ -172,7 +207,7 @@ pub async fn check_leverage_positions() {
                     amount_to_transfer,
                     protocol_fee
                 );
-                match transfer_icp(None, position.owner, amount_to_transfer - protocol_fee).await {
+                match pay_icp(position.owner, None, amount_to_transfer - protocol_fee).await {
                     Ok(output_block_index) => {
                         crate::mutate_state(|s| {
                             crate::state::audit::record_close_leverage_position(
@@ -182,12 +217,13 @@ pub async fn check_leverage_positions() {
                                 protocol_fee,
                                 now,
                                 last_icp_price.clone(),
+                                None,
                             );
                         });
                     }
                     Err(_error) => {}
                 }
-            } else if should_liquidate(position.clone(), last_icp_price.rate) {
+            } else if should_liquidate(position.clone(), average_icp_price.rate) {
                 // TODO Add fees
                 mutate_state(|s| {
                     record_liquidate_leverage_position(
@@ -195,7 +231,7 @@ pub async fn check_leverage_positions() {
                         position.deposit_block_index,
                         0,
                         now,
-                        last_icp_price.clone(),
+                        average_icp_price.clone(),
                     )
                 });
             }
@@ -261,3 +297,137 @@ fn test_pnl_computation() {
     assert!(pnl == 200_000_000);
     assert!(cash_out_amount == 700_000_000);
 }
+
+#[test]
+fn test_liquidation_shortfall() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::{CoreState, IcpPrice};
+    use crate::updates::liquidity::LiquidityWithdrawal;
+    use crate::E8S;
+    use candid::Principal;
+
+    let mut state = CoreState::from(InitArgs {
+        insurance_fund_fee_share: Some(E8S / 2),
+        ..default_init_args()
+    });
+    let user_1 = Principal::from_slice(&[1]);
+    let user_2 = Principal::from_slice(&[2]);
+    state.icp_collateral_amount = 20 * E8S;
+    state.credit_liquidity(user_1, 3 * E8S);
+    state.credit_liquidity(user_2, E8S);
+    state.queue_liquidity_withdrawal(LiquidityWithdrawal {
+        id: 0,
+        owner: user_2,
+        amount: E8S,
+        timestamp: 0,
+        to_account: None,
+    });
+
+    // Half of the fees go to the insurance fund.
+    state.distribute_fee(2 * E8S);
+    assert_eq!(state.insurance_fund_amount, E8S);
+    assert_eq!(state.liquidity_rewards.values().sum::<u64>(), E8S);
+
+    let position = LeveragePosition {
+        owner: Principal::anonymous(),
+        amount: E8S,
+        covered_amount: 10 * E8S,
+        take_profit: 20 * E8S,
+        timestamp: 0,
+        icp_entry_price: IcpPrice { rate: 10 * E8S },
+        deposit_block_index: 0,
+        fee: 0,
+    };
+    state.open_leverage_position(position.clone());
+
+    // The loss of 2.5 ICP exceeds the margin by 1.5 ICP, the insurance
+    // fund covers 1 ICP and the liquidity providers the rest.
+    state.liquidate_leverage_position(position, IcpPrice { rate: 8 * E8S });
+    assert_eq!(state.insurance_fund_amount, 0);
+    assert_eq!(state.liquidity_provided[&user_1], 3 * E8S - 37_500_000);
+    assert_eq!(state.liquidity_provided[&user_2], E8S - 12_500_000);
+    assert_eq!(
+        state.get_pending_withdrawal_amount(&user_2),
+        E8S - 12_500_000
+    );
+    assert_eq!(state.icp_collateral_amount, 22 * E8S + 50_000_000);
+    assert_eq!(state.icp_leverage_margin_amount, 0);
+    assert_eq!(state.bad_debt, 0);
+    assert_eq!(state.check_invariants(), Ok(()));
+
+    // What the liquidity cannot cover is bad debt, repaid by the fund first.
+    state.cover_collateral_shortfall(10 * E8S);
+    assert!(state.liquidity_provided.is_empty());
+    assert!(state.liquidity_withdrawal_queue.is_empty());
+    assert_eq!(state.bad_debt, 6 * E8S + 50_000_000);
+    state.distribute_fee(2 * E8S);
+    assert_eq!(state.bad_debt, 5 * E8S + 50_000_000);
+    assert_eq!(state.insurance_fund_amount, 0);
+    assert_eq!(state.check_invariants(), Ok(()));
+}
+
+#[test]
+fn test_replay_liquidations() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::eventlog::{apply_events, Event};
+    use crate::state::{CoreState, IcpPrice};
+    use crate::E8S;
+    use candid::Principal;
+
+    let position = LeveragePosition {
+        owner: Principal::anonymous(),
+        amount: E8S,
+        covered_amount: 10 * E8S,
+        take_profit: 20 * E8S,
+        timestamp: 0,
+        icp_entry_price: IcpPrice { rate: 10 * E8S },
+        deposit_block_index: 0,
+        fee: 0,
+    };
+    let new_state = || {
+        let mut state = CoreState::from(InitArgs {
+            insurance_fund_fee_share: Some(E8S),
+            ..default_init_args()
+        });
+        state.icp_collateral_amount = 20 * E8S;
+        state.distribute_fee(E8S);
+        state.open_leverage_position(position.clone());
+        state
+    };
+
+    // The liquidations recorded as closes keep seizing the margin only.
+    let state = apply_events(
+        new_state(),
+        vec![Event::CloseLeveragePosition {
+            deposit_block_index: 0,
+            output_block_index: None,
+            fee: 0,
+            timestamp: 0,
+            icp_price: IcpPrice { rate: 8 * E8S },
+            to_account: None,
+        }]
+        .into_iter(),
+    )
+    .expect("failed to replay the legacy liquidation");
+    assert!(state.leverage_positions[&Principal::anonymous()].is_empty());
+    assert_eq!(state.insurance_fund_amount, E8S);
+    assert_eq!(state.icp_collateral_amount, 21 * E8S);
+
+    // The loss of 2.5 ICP exceeds the margin by 1.5 ICP, the fund covers
+    // 1 ICP and the rest is bad debt.
+    let state = apply_events(
+        new_state(),
+        vec![Event::LiquidateLeveragePosition {
+            deposit_block_index: 0,
+            fee: 0,
+            timestamp: 0,
+            icp_price: IcpPrice { rate: 8 * E8S },
+        }]
+        .into_iter(),
+    )
+    .expect("failed to replay the liquidation");
+    assert!(state.leverage_positions[&Principal::anonymous()].is_empty());
+    assert_eq!(state.insurance_fund_amount, 0);
+    assert_eq!(state.icp_collateral_amount, 22 * E8S);
+    assert_eq!(state.bad_debt, 50_000_000);
+}
@@ -1,17 +1,28 @@
-use crate::compute_subaccount;
+use crate::curve::PiecewiseLinearCurve;
 use crate::guard::liquidity_update_guard;
 use crate::guard::GuardError;
 use crate::multiply_e8s;
-use crate::state::audit::record_liquidity;
+use crate::state::audit::{
+    record_cancel_liquidity_withdrawal, record_claim_liquidity_rewards,
+    record_compound_liquidity_rewards, record_liquidity, record_liquidity_withdrawal_request,
+    record_serve_liquidity_withdrawal, record_set_auto_compound,
+};
 use crate::state::CoreState;
 use crate::state::{mutate_state, read_state};
-use crate::transfer_icp;
+use crate::tasks::{schedule_after, schedule_now, TaskType};
+use crate::updates::balance::pay_icp;
+use crate::updates::deposit::{pull_icp, DepositMethod};
+use crate::E8S;
 use crate::ICP_TRANSFER_FEE;
 use candid::CandidType;
 use candid::{Deserialize, Principal};
-use ic_base_types::PrincipalId;
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferError;
 use serde::Serialize;
+use std::time::Duration;
+
+const WITHDRAWAL_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[derive(CandidType, serde::Deserialize, Debug)]
 pub enum LiquidityError {
@@ -22,6 +33,10 @@ pub enum LiquidityError {
     NoClaimableReward,
     NoLiquidityProvided,
     AmountTooSmall,
+    WithdrawalNotFound,
+    CallerNotOwner,
+    /// No liquidity can be added during the global settlement.
+    GlobalSettlement,
 }
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -38,6 +53,33 @@ pub struct Liquidity {
     pub block_index: u64,
     pub timestamp: u64,
     pub fee: u64,
+    /// The account receiving the removed liquidity, the caller if not set.
+    #[serde(default)]
+    pub to_account: Option<Account>,
+}
+
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoveLiquidityQuote {
+    /// The amount of liquidity removed.
+    pub amount: u64,
+    /// The protocol fee.
+    pub fee: u64,
+    /// The fraction of the liquidity paid out at the current collateral ratio e8s.
+    pub payout_fraction: u64,
+    /// The amount received after haircut, fee and ledger fee.
+    pub amount_received: u64,
+}
+
+/// A request to withdraw liquidity once it can be served without haircut.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiquidityWithdrawal {
+    pub id: u64,
+    pub owner: Principal,
+    pub amount: u64,
+    pub timestamp: u64,
+    /// The account receiving the withdrawal, the owner if not set.
+    #[serde(default)]
+    pub to_account: Option<Account>,
 }
 
 impl From<GuardError> for LiquidityError {
@@ -51,17 +93,21 @@ impl From<GuardError> for LiquidityError {
     }
 }
 
-pub async fn add_liquidity(amount: u64) -> Result<u64, LiquidityError> {
+pub async fn add_liquidity(
+    amount: u64,
+    deposit_method: DepositMethod,
+) -> Result<u64, LiquidityError> {
     let caller = ic_cdk::caller();
     let _guard = liquidity_update_guard(caller)?;
 
-    let caller_subaccount = compute_subaccount(PrincipalId(caller), 0);
-    let core_id = ic_cdk::id();
+    if read_state(|s| s.global_settlement.is_some()) {
+        return Err(LiquidityError::GlobalSettlement);
+    }
     if amount < read_state(|s| s.min_amount_liquidity) {
         return Err(LiquidityError::AmountTooSmall);
     }
 
-    match transfer_icp(Some(caller_subaccount), core_id, amount).await {
+    match pull_icp(caller, amount, deposit_method).await {
         Ok(block_index) => {
             let transfer_amount = amount;
             let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), transfer_amount);
@@ -72,10 +118,13 @@ pub async fn add_liquidity(amount: u64) -> Result<u64, LiquidityError> {
                 block_index,
                 timestamp: ic_cdk::api::time(),
                 fee: protocol_fee,
+                to_account: None,
             };
             mutate_state(|s| {
                 record_liquidity(s, liquidity);
             });
+            // New liquidity may allow serving queued withdrawals.
+            schedule_now(TaskType::ProcessLiquidityWithdrawals);
 
             Ok(block_index)
         }
@@ -83,25 +132,15 @@ pub async fn add_liquidity(amount: u64) -> Result<u64, LiquidityError> {
     }
 }
 
-pub async fn remove_liquidity(amount: u64, to: Principal) -> Result<u64, LiquidityError> {
+pub async fn remove_liquidity(
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, LiquidityError> {
     let caller = ic_cdk::caller();
     let _guard = liquidity_update_guard(caller)?;
 
-    let caller_balance = read_state(|s| s.liquidity_provided.get(&caller).cloned());
-    if caller_balance.is_none() {
-        return Err(LiquidityError::NoLiquidityProvided);
-    }
-    let caller_balance = caller_balance.unwrap();
-    if amount > caller_balance {
-        return Err(LiquidityError::NotEnoughLiquidity(caller_balance));
-    }
-    let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), amount);
-    if amount < protocol_fee + ICP_TRANSFER_FEE {
-        return Err(LiquidityError::AmountTooSmall);
-    }
-    let collateral_ratio = read_state(|s| s.get_collateral_ratio());
-    let amount_to_withdraw = compute_liquidity_claimable(amount - protocol_fee, collateral_ratio);
-    match transfer_icp(None, to, amount_to_withdraw - ICP_TRANSFER_FEE).await {
+    let quote = read_state(|s| quote_remove_liquidity(s, caller, amount))?;
+    match pay_icp(caller, to_account, quote.amount_received).await {
         Ok(block_index) => {
             let liq = Liquidity {
                 caller,
@@ -109,7 +148,8 @@ pub async fn remove_liquidity(amount: u64, to: Principal) -> Result<u64, Liquidi
                 amount,
                 block_index,
                 timestamp: ic_cdk::api::time(),
-                fee: protocol_fee,
+                fee: quote.fee,
+                to_account,
             };
             mutate_state(|s| {
                 record_liquidity(s, liq);
@@ -120,14 +160,193 @@ pub async fn remove_liquidity(amount: u64, to: Principal) -> Result<u64, Liquidi
     }
 }
 
-pub async fn claim_liquidity_rewards() -> Result<u64, LiquidityError> {
+/// Returns the liquidity of `owner` which is not reserved by a queued withdrawal.
+fn get_withdrawable_liquidity(state: &CoreState, owner: Principal) -> Result<u64, LiquidityError> {
+    match state.liquidity_provided.get(&owner) {
+        Some(provided) => Ok(provided.saturating_sub(state.get_pending_withdrawal_amount(&owner))),
+        None => Err(LiquidityError::NoLiquidityProvided),
+    }
+}
+
+/// Computes what `owner` receives when removing `amount` of liquidity right now.
+pub fn quote_remove_liquidity(
+    state: &CoreState,
+    owner: Principal,
+    amount: u64,
+) -> Result<RemoveLiquidityQuote, LiquidityError> {
+    let caller_balance = get_withdrawable_liquidity(state, owner)?;
+    if amount > caller_balance {
+        return Err(LiquidityError::NotEnoughLiquidity(caller_balance));
+    }
+    let fee = get_removal_fee(state, amount);
+    if amount < fee + ICP_TRANSFER_FEE {
+        return Err(LiquidityError::AmountTooSmall);
+    }
+    let payout_fraction = match &state.global_settlement {
+        Some(settlement) if settlement.redemption_rate.is_none() => {
+            return Err(LiquidityError::TemporarilyUnavailable(
+                "the global settlement is closing leverage positions".to_string(),
+            ))
+        }
+        // The liquidity is paid out in full once the settlement is finalized.
+        Some(_) => E8S,
+        None => state
+            .liquidity_haircut_curve
+            .evaluate(state.get_collateral_ratio()),
+    };
+    let amount_to_withdraw = compute_liquidity_claimable(amount - fee, payout_fraction);
+    if amount_to_withdraw <= ICP_TRANSFER_FEE {
+        return Err(LiquidityError::AmountTooSmall);
+    }
+    Ok(RemoveLiquidityQuote {
+        amount,
+        fee,
+        payout_fraction,
+        amount_received: amount_to_withdraw - ICP_TRANSFER_FEE,
+    })
+}
+
+/// Queues a liquidity withdrawal which is served, in FIFO order,
+/// as soon as the collateral ratio allows a withdrawal without haircut.
+pub fn request_liquidity_withdrawal(
+    amount: u64,
+    to_account: Option<Account>,
+) -> Result<u64, LiquidityError> {
+    let caller = ic_cdk::caller();
+    let _guard = liquidity_update_guard(caller)?;
+
+    let caller_balance = read_state(|s| get_withdrawable_liquidity(s, caller))?;
+    if amount > caller_balance {
+        return Err(LiquidityError::NotEnoughLiquidity(caller_balance));
+    }
+    let protocol_fee = read_state(|s| get_removal_fee(s, amount));
+    if amount < protocol_fee + ICP_TRANSFER_FEE {
+        return Err(LiquidityError::AmountTooSmall);
+    }
+
+    let withdrawal = LiquidityWithdrawal {
+        id: read_state(|s| s.next_liquidity_withdrawal_id),
+        owner: caller,
+        amount,
+        timestamp: ic_cdk::api::time(),
+        to_account,
+    };
+    let id = withdrawal.id;
+    mutate_state(|s| record_liquidity_withdrawal_request(s, withdrawal));
+    schedule_now(TaskType::ProcessLiquidityWithdrawals);
+    Ok(id)
+}
+
+pub fn cancel_liquidity_withdrawal(id: u64) -> Result<(), LiquidityError> {
+    let caller = ic_cdk::caller();
+    let _guard = liquidity_update_guard(caller)?;
+
+    match read_state(|s| s.liquidity_withdrawal_queue.get(&id).cloned()) {
+        Some(withdrawal) if withdrawal.owner != caller => Err(LiquidityError::CallerNotOwner),
+        Some(_) => {
+            mutate_state(|s| record_cancel_liquidity_withdrawal(s, id));
+            Ok(())
+        }
+        None => Err(LiquidityError::WithdrawalNotFound),
+    }
+}
+
+/// Serves the queued liquidity withdrawals in FIFO order and stops at the
+/// first one that would suffer a haircut.
+pub async fn process_liquidity_withdrawals() {
+    let queue = read_state(|s| s.liquidity_withdrawal_queue.clone());
+    for (id, withdrawal) in queue {
+        if !read_state(can_serve_liquidity_withdrawals) {
+            return;
+        }
+        let _guard = match liquidity_update_guard(withdrawal.owner) {
+            Ok(guard) => guard,
+            Err(_) => {
+                schedule_after(
+                    WITHDRAWAL_RETRY_DELAY,
+                    TaskType::ProcessLiquidityWithdrawals,
+                );
+                return;
+            }
+        };
+        // The request may have been cancelled in the meantime.
+        if read_state(|s| !s.liquidity_withdrawal_queue.contains_key(&id)) {
+            continue;
+        }
+        let protocol_fee = read_state(|s| get_removal_fee(s, withdrawal.amount));
+        // The fee may have grown since the request, the liquidity stays
+        // with its owner.
+        let payout = match withdrawal
+            .amount
+            .checked_sub(protocol_fee)
+            .and_then(|amount| amount.checked_sub(ICP_TRANSFER_FEE))
+        {
+            Some(payout) => payout,
+            None => {
+                log!(
+                    crate::P1,
+                    "[process_liquidity_withdrawals] cancelled withdrawal {} of {} below the fee {}",
+                    id,
+                    withdrawal.amount,
+                    protocol_fee
+                );
+                mutate_state(|s| record_cancel_liquidity_withdrawal(s, id));
+                continue;
+            }
+        };
+        match pay_icp(withdrawal.owner, withdrawal.to_account, payout).await {
+            Ok(block_index) => {
+                log!(
+                    crate::P1,
+                    "[process_liquidity_withdrawals] served withdrawal {} of {} for {}",
+                    id,
+                    withdrawal.amount,
+                    withdrawal.owner
+                );
+                let liquidity = Liquidity {
+                    caller: withdrawal.owner,
+                    operation_type: LiquidityType::Remove,
+                    amount: withdrawal.amount,
+                    block_index,
+                    timestamp: ic_cdk::api::time(),
+                    fee: protocol_fee,
+                    to_account: withdrawal.to_account,
+                };
+                mutate_state(|s| record_serve_liquidity_withdrawal(s, id, liquidity));
+            }
+            Err(e) => {
+                log!(
+                    crate::P1,
+                    "[process_liquidity_withdrawals] failed to serve withdrawal {}: {:?}",
+                    id,
+                    e
+                );
+                schedule_after(
+                    WITHDRAWAL_RETRY_DELAY,
+                    TaskType::ProcessLiquidityWithdrawals,
+                );
+                return;
+            }
+        }
+    }
+}
+
+pub async fn claim_liquidity_rewards(to_account: Option<Account>) -> Result<u64, LiquidityError> {
     let caller = ic_cdk::caller();
     let _guard = liquidity_update_guard(caller)?;
 
     match read_state(|s| s.liquidity_rewards.get(&caller).cloned()) {
-        Some(claimable_amount) => match transfer_icp(None, caller, claimable_amount).await {
+        Some(claimable_amount) => match pay_icp(caller, to_account, claimable_amount).await {
             Ok(block_index) => {
-                mutate_state(|s| s.liquidity_rewards.remove(&caller));
+                mutate_state(|s| {
+                    record_claim_liquidity_rewards(
+                        s,
+                        caller,
+                        claimable_amount,
+                        block_index,
+                        to_account,
+                    )
+                });
                 Ok(block_index)
             }
             Err(e) => Err(LiquidityError::LedgerError(e)),
@@ -136,6 +355,33 @@ pub async fn claim_liquidity_rewards() -> Result<u64, LiquidityError> {
     }
 }
 
+/// Adds the claimable rewards of the caller to its provided liquidity
+/// without going through the ledger.
+pub fn compound_liquidity_rewards() -> Result<u64, LiquidityError> {
+    let caller = ic_cdk::caller();
+    let _guard = liquidity_update_guard(caller)?;
+
+    match read_state(|s| s.liquidity_rewards.get(&caller).cloned()) {
+        Some(amount) if amount > 0 => {
+            mutate_state(|s| record_compound_liquidity_rewards(s, caller, amount));
+            Ok(amount)
+        }
+        _ => Err(LiquidityError::NoClaimableReward),
+    }
+}
+
+/// When enabled, the future rewards of the caller are directly
+/// added to its provided liquidity.
+pub fn set_auto_compound(enabled: bool) -> Result<(), LiquidityError> {
+    let caller = ic_cdk::caller();
+    let _guard = liquidity_update_guard(caller)?;
+
+    if read_state(|s| s.liquidity_auto_compound.contains(&caller)) != enabled {
+        mutate_state(|s| record_set_auto_compound(s, caller, enabled));
+    }
+    Ok(())
+}
+
 pub fn distribute_protocol_rewards(state: &mut CoreState) {
     let fees_amount_to_distribute = state.total_available_fees;
 
@@ -160,7 +406,9 @@ pub fn distribute_protocol_rewards(state: &mut CoreState) {
         liquidity_provider_count
     );
     for (principal, amount) in fee_distributed {
-        if let Some(entry_ref) = state.liquidity_rewards.get_mut(&principal) {
+        if state.liquidity_auto_compound.contains(&principal) {
+            state.credit_liquidity(principal, amount);
+        } else if let Some(entry_ref) = state.liquidity_rewards.get_mut(&principal) {
             *entry_ref += amount;
         } else {
             state.liquidity_rewards.insert(principal, amount);
@@ -193,18 +441,61 @@ fn compute_share(amount_provided: u64, total_amount: u64) -> f64 {
     amount_provided as f64 / total_amount as f64
 }
 
-fn compute_liquidity_claimable(amount_to_claim: u64, collateral_ratio: u64) -> u64 {
-    const UPPER_COLLATERAL_RATIO: u64 = 120_000_000;
-    const SLOPE: u64 = 83_333_333;
-    if collateral_ratio > UPPER_COLLATERAL_RATIO {
-        amount_to_claim
+/// Checks that the haircut curve maps collateral ratios to
+/// payout fractions which never decrease and reach 100%.
+pub fn validate_haircut_curve(curve: &PiecewiseLinearCurve) -> Result<(), String> {
+    curve.validate()?;
+    for window in curve.points.windows(2) {
+        if window[0].y > window[1].y {
+            return Err(format!(
+                "the payout fraction decreases from {} to {} at collateral ratio {}",
+                window[0].y, window[1].y, window[1].x
+            ));
+        }
+    }
+    if let Some(point) = curve.points.iter().find(|p| p.y > E8S) {
+        return Err(format!(
+            "the payout fraction {} at collateral ratio {} is greater than 100%",
+            point.y, point.x
+        ));
+    }
+    if curve.points.last().map(|p| p.y) != Some(E8S) {
+        return Err("the payout fraction never reaches 100%".to_string());
+    }
+    Ok(())
+}
+
+/// Liquidity providers pay no fee to withdraw during the global settlement.
+fn get_removal_fee(state: &CoreState, amount: u64) -> u64 {
+    if state.global_settlement.is_some() {
+        0
     } else {
-        let slippage = multiply_e8s(SLOPE, collateral_ratio);
-        debug_assert!(slippage <= 100_000_000);
-        multiply_e8s(amount_to_claim, slippage)
+        multiply_e8s(state.fees.base_fee, amount)
     }
 }
 
+fn can_serve_liquidity_withdrawals(state: &CoreState) -> bool {
+    match &state.global_settlement {
+        Some(settlement) => settlement.redemption_rate.is_some(),
+        None => {
+            !state.icp_prices.is_empty()
+                && is_withdrawal_without_haircut(
+                    &state.liquidity_haircut_curve,
+                    state.get_collateral_ratio(),
+                )
+        }
+    }
+}
+
+fn is_withdrawal_without_haircut(curve: &PiecewiseLinearCurve, collateral_ratio: u64) -> bool {
+    curve.evaluate(collateral_ratio) >= E8S
+}
+
+fn compute_liquidity_claimable(amount_to_claim: u64, payout_fraction: u64) -> u64 {
+    debug_assert!(payout_fraction <= E8S);
+    multiply_e8s(amount_to_claim, payout_fraction)
+}
+
 #[test]
 fn test_fee_distribution() {
     let fee_to_share: u64 = 100_000;
@@ -233,16 +524,77 @@ fn test_fee_distribution() {
     assert!(fee_vec[2].1 == 57142);
 }
 
+#[cfg(test)]
+fn default_haircut_curve() -> PiecewiseLinearCurve {
+    PiecewiseLinearCurve::new(vec![(0, 0), (120_000_000, 100_000_000)])
+}
+
 #[test]
 fn test_slippage() {
+    let curve = default_haircut_curve();
     let user_wants_to_claim: u64 = 1_000_000_000; // 10 ICP
     let collateral_ratio: u64 = 100_000_000; // 100% CR
-    let result = compute_liquidity_claimable(user_wants_to_claim, collateral_ratio);
+    let result = compute_liquidity_claimable(user_wants_to_claim, curve.evaluate(collateral_ratio));
     assert!(result == 833_333_330);
     let collateral_ratio: u64 = 140_000_000; // 140% CR
-    let result = compute_liquidity_claimable(user_wants_to_claim, collateral_ratio);
+    let result = compute_liquidity_claimable(user_wants_to_claim, curve.evaluate(collateral_ratio));
     assert!(result == user_wants_to_claim);
     let collateral_ratio: u64 = 50_000_000; // 50% CR
-    let result = compute_liquidity_claimable(user_wants_to_claim, collateral_ratio);
+    let result = compute_liquidity_claimable(user_wants_to_claim, curve.evaluate(collateral_ratio));
     assert!(result == 416_666_660); // 4,16 ICP
 }
+
+#[test]
+fn test_withdrawal_without_haircut() {
+    let curve = default_haircut_curve();
+    assert!(!is_withdrawal_without_haircut(&curve, 100_000_000));
+    assert!(!is_withdrawal_without_haircut(&curve, 119_999_999));
+    assert!(is_withdrawal_without_haircut(&curve, 120_000_000));
+    assert!(is_withdrawal_without_haircut(&curve, u64::MAX));
+}
+
+#[test]
+fn test_haircut_curve_validation() {
+    assert!(validate_haircut_curve(&default_haircut_curve()).is_ok());
+    // Payout fraction decreasing.
+    assert!(validate_haircut_curve(&PiecewiseLinearCurve::new(vec![
+        (0, 50_000_000),
+        (100_000_000, 40_000_000),
+        (120_000_000, 100_000_000)
+    ]))
+    .is_err());
+    // Payout fraction above 100%.
+    assert!(
+        validate_haircut_curve(&PiecewiseLinearCurve::new(vec![(0, 0), (1, 100_000_001)])).is_err()
+    );
+    // Payout fraction never reaching 100%.
+    assert!(
+        validate_haircut_curve(&PiecewiseLinearCurve::new(vec![(0, 0), (1, 99_999_999)])).is_err()
+    );
+}
+
+#[test]
+fn test_auto_compound_distribution() {
+    use crate::lifecycle::init::default_init_args;
+
+    let mut state = CoreState::from(default_init_args());
+    let user_1 = Principal::from_slice(&[1]);
+    let user_2 = Principal::from_slice(&[2]);
+    state.credit_liquidity(user_1, 100_000_000);
+    state.credit_liquidity(user_2, 100_000_000);
+    state.set_auto_compound(user_1, true);
+
+    state.distribute_fee(1_000);
+
+    assert_eq!(state.liquidity_provided.get(&user_1), Some(&100_000_500));
+    assert_eq!(state.liquidity_rewards.get(&user_1), None);
+    assert_eq!(state.liquidity_provided.get(&user_2), Some(&100_000_000));
+    assert_eq!(state.liquidity_rewards.get(&user_2), Some(&500));
+    assert_eq!(state.icp_liqudity_amount, 200_000_500);
+    assert_eq!(state.check_invariants(), Ok(()));
+
+    state.compound_liquidity_rewards(user_2, 500);
+    assert_eq!(state.liquidity_provided.get(&user_2), Some(&100_000_500));
+    assert_eq!(state.liquidity_rewards.get(&user_2), None);
+    assert_eq!(state.icp_liqudity_amount, 200_001_000);
+}
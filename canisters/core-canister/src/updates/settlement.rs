@@ -0,0 +1,268 @@
+use crate::guard::leverage_update_guard;
+use crate::logs::P0;
+use crate::state::audit::{
+    record_close_leverage_position, record_finalize_global_settlement,
+    record_liquidate_leverage_position, record_start_global_settlement,
+};
+use crate::state::{mutate_state, read_state};
+use crate::tasks::{schedule_after, schedule_now, TaskType};
+use crate::updates::balance::pay_icp;
+use crate::updates::leverage::{compute_cash_out_amount, compute_pnl};
+use crate::ICP_TRANSFER_FEE;
+use ic_canister_log::log;
+use std::time::Duration;
+
+const SETTLEMENT_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// The redemptions open without waiting for the swaps still open that
+/// long after the start of the settlement.
+const SETTLEMENT_OPEN_SWAPS_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Freezes the last ICP price and stops minting. Called on upgrade
+/// as only the controllers can trigger the settlement.
+pub fn start_global_settlement() {
+    if read_state(|s| s.global_settlement.is_some()) {
+        log!(P0, "[global_settlement]: the settlement already started");
+        return;
+    }
+    let icp_price = read_state(|s| s.get_last_icp_price()).unwrap_or_else(|| {
+        ic_cdk::trap("[global_settlement]: cannot start the settlement without an ICP price")
+    });
+    log!(
+        P0,
+        "[global_settlement]: starting the settlement at {} $/ICP",
+        icp_price.rate
+    );
+    mutate_state(|s| record_start_global_settlement(s, icp_price, ic_cdk::api::time()));
+}
+
+/// Closes every leverage position at the frozen price, then opens the
+/// redemptions once no position nor swap is pending.
+pub async fn process_global_settlement() {
+    let (icp_price, started_at) = match read_state(|s| s.global_settlement.clone()) {
+        Some(settlement) if settlement.redemption_rate.is_none() => {
+            (settlement.icp_price, settlement.timestamp)
+        }
+        _ => return,
+    };
+
+    let positions: Vec<_> = read_state(|s| {
+        s.leverage_positions
+            .values()
+            .flat_map(|positions| positions.iter().cloned())
+            .collect()
+    });
+    for position in positions {
+        let _guard = match leverage_update_guard(position.owner) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        let deposit_block_index = position.deposit_block_index;
+        // The owner may have closed the position in the meantime.
+        let is_open = read_state(|s| {
+            s.leverage_positions
+                .get(&position.owner)
+                .map_or(false, |positions| positions.contains(&position))
+        });
+        if !is_open {
+            continue;
+        }
+        let now = ic_cdk::api::time();
+        let pnl = compute_pnl(&position, icp_price.rate);
+        if pnl < 0 && pnl.unsigned_abs() + ICP_TRANSFER_FEE >= position.amount - position.fee {
+            mutate_state(|s| {
+                record_liquidate_leverage_position(
+                    s,
+                    deposit_block_index,
+                    0,
+                    now,
+                    icp_price.clone(),
+                )
+            });
+            continue;
+        }
+        let amount_to_transfer = compute_cash_out_amount(&position, icp_price.rate);
+        match pay_icp(position.owner, None, amount_to_transfer - ICP_TRANSFER_FEE).await {
+            Ok(output_block_index) => {
+                mutate_state(|s| {
+                    record_close_leverage_position(
+                        s,
+                        output_block_index,
+                        deposit_block_index,
+                        0,
+                        now,
+                        icp_price.clone(),
+                        None,
+                    )
+                });
+            }
+            Err(e) => {
+                log!(
+                    P0,
+                    "[global_settlement]: failed to close position {}: {:?}",
+                    deposit_block_index,
+                    e
+                );
+            }
+        }
+    }
+
+    // A swap whose payout keeps failing must not block the redemptions,
+    // its eUSD is then counted as outstanding.
+    let open_swaps_count = read_state(|s| s.open_swaps.len());
+    let swaps_timed_out = ic_cdk::api::time()
+        >= started_at.saturating_add(SETTLEMENT_OPEN_SWAPS_TIMEOUT.as_nanos() as u64);
+    if read_state(|s| s.has_leverage_positions()) || (open_swaps_count > 0 && !swaps_timed_out) {
+        schedule_after(SETTLEMENT_RETRY_DELAY, TaskType::ProcessGlobalSettlement);
+        return;
+    }
+    if open_swaps_count > 0 {
+        log!(
+            P0,
+            "[global_settlement]: {} swaps are still open, counting them as settled",
+            open_swaps_count
+        );
+    }
+    let (redemption_rate, collateral_redemption_rates, liquidity_surplus) =
+        read_state(|s| s.compute_settlement_redemption());
+    log!(
+        P0,
+        "[global_settlement]: redeeming eUSD at {} $/ICP and {:?} for the collaterals, {} ICP left to liquidity providers",
+        redemption_rate,
+        collateral_redemption_rates,
+        liquidity_surplus
+    );
+    mutate_state(|s| {
+        record_finalize_global_settlement(
+            s,
+            redemption_rate,
+            collateral_redemption_rates,
+            liquidity_surplus,
+            ic_cdk::api::time(),
+        )
+    });
+    // Queued withdrawals are now served without haircut.
+    schedule_now(TaskType::ProcessLiquidityWithdrawals);
+}
+
+#[test]
+fn test_settlement_redemption() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::state::{CoreState, IcpPrice};
+    use crate::E8S;
+    use candid::Principal;
+    use std::collections::BTreeMap;
+
+    let new_state = |icp_rate| {
+        let mut state = CoreState::from(default_init_args());
+        // 10 ICP backing 40 eUSD.
+        state.icp_collateral_amount = 10 * E8S;
+        state.total_eusd_minted = 40 * E8S;
+        state.credit_liquidity(Principal::management_canister(), 3 * E8S);
+        state.credit_liquidity(Principal::anonymous(), E8S);
+        state.start_global_settlement(IcpPrice { rate: icp_rate }, 0);
+        state
+    };
+
+    // Over-collateralized, eUSD is redeemed at the frozen price.
+    let mut state = new_state(5 * E8S);
+    assert_eq!(
+        state.compute_settlement_redemption(),
+        (5 * E8S, BTreeMap::new(), 2 * E8S)
+    );
+    state.finalize_global_settlement(5 * E8S, BTreeMap::new(), 2 * E8S);
+    assert_eq!(state.icp_collateral_amount, 8 * E8S);
+    assert_eq!(
+        state.liquidity_provided[&Principal::management_canister()],
+        3 * E8S + 150_000_000
+    );
+    assert_eq!(
+        state.liquidity_provided[&Principal::anonymous()],
+        E8S + 50_000_000
+    );
+    assert_eq!(state.check_invariants(), Ok(()));
+
+    // Under-collateralized, eUSD is redeemed pro-rata.
+    let state = new_state(3 * E8S);
+    assert_eq!(
+        state.compute_settlement_redemption(),
+        (4 * E8S, BTreeMap::new(), 0)
+    );
+
+    // Redemptions never exceed the collateral.
+    let mut state = new_state(3 * E8S);
+    state.icp_collateral_amount = 3;
+    let (redemption_rate, _, _) = state.compute_settlement_redemption();
+    assert!(crate::divide_e8s(state.total_eusd_minted, redemption_rate) <= 3);
+}
+
+#[test]
+fn test_settlement_redemption_with_collaterals() {
+    use crate::collateral::CollateralConfig;
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::{Asset, CoreState, IcpPrice};
+    use crate::updates::swap::quote_swap;
+    use crate::E8S;
+    use candid::Principal;
+    use std::collections::BTreeMap;
+
+    let new_state = |btc_rate| {
+        let mut state = CoreState::from(InitArgs {
+            collaterals: Some(vec![CollateralConfig {
+                symbol: "ckBTC".to_string(),
+                xrc_symbol: "BTC".to_string(),
+                ledger_principal: Principal::anonymous(),
+                transfer_fee: 10,
+                decimals: 8,
+            }]),
+            ..default_init_args()
+        });
+        // 10 ICP at 3$ and 1 ckBTC backing 40 eUSD.
+        state.icp_collateral_amount = 10 * E8S;
+        state.collateral_amounts.insert("ckBTC".to_string(), E8S);
+        state.insert_price(
+            &Asset::Collateral("ckBTC".to_string()),
+            0,
+            IcpPrice { rate: btc_rate },
+        );
+        state.total_eusd_minted = 40 * E8S;
+        state.start_global_settlement(IcpPrice { rate: 3 * E8S }, 0);
+        state
+    };
+
+    // The ckBTC backs 20 eUSD, the ICP worth the other 20 eUSD is reserved.
+    let mut state = new_state(20 * E8S);
+    let (redemption_rate, collateral_redemption_rates, liquidity_surplus) =
+        state.compute_settlement_redemption();
+    assert_eq!(redemption_rate, 3 * E8S);
+    assert_eq!(
+        collateral_redemption_rates,
+        BTreeMap::from([("ckBTC".to_string(), 20 * E8S)])
+    );
+    assert_eq!(liquidity_surplus, 10 * E8S - 666_666_667);
+
+    state.finalize_global_settlement(
+        redemption_rate,
+        collateral_redemption_rates,
+        liquidity_surplus,
+    );
+    let quote = quote_swap(
+        &state,
+        &Asset::EUSD,
+        &Asset::Collateral("ckBTC".to_string()),
+        20 * E8S,
+        0,
+    )
+    .expect("failed to quote a ckBTC redemption");
+    assert_eq!(quote.amount_out, E8S);
+
+    // Under-collateralized, both collaterals are redeemed pro-rata.
+    let state = new_state(5 * E8S);
+    assert_eq!(
+        state.compute_settlement_redemption(),
+        (
+            342_857_143,
+            BTreeMap::from([("ckBTC".to_string(), 571_428_572)]),
+            0
+        )
+    );
+}
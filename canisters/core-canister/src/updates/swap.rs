@@ -1,21 +1,24 @@
-use crate::compute_subaccount;
+use crate::collateral::{from_value_e8s, to_value_e8s};
+use crate::curve::PiecewiseLinearCurve;
 use crate::divide_e8s;
 use crate::guard::convert_update_guard;
 use crate::guard::GuardError;
-use crate::management::{burn_eusd, transfer_icp};
+use crate::guard::MintReservation;
 use crate::multiply_e8s;
 use crate::state::audit::record_swap;
 use crate::state::mutate_state;
 use crate::state::read_state;
 use crate::state::Asset;
+use crate::state::CoreState;
 use crate::state::LeveragePosition;
 use crate::tasks::schedule_now;
 use crate::tasks::TaskType;
+use crate::updates::deposit::{pull_collateral, pull_eusd, pull_icp, DepositMethod};
 use crate::E8S;
 use candid::CandidType;
 use candid::Principal;
-use ic_base_types::PrincipalId;
 use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferError;
 use std::collections::BTreeSet;
 
@@ -27,6 +30,18 @@ pub enum SwapError {
     AlreadyProcessing,
     TemporarilyUnavailable(String),
     AmountTooSmall,
+    CollateralLedgerError(TransferError),
+    UnknownCollateral(String),
+    /// The collateral available for swaps is lower than the output amount.
+    InsufficientCollateral(u64),
+    /// Only swaps between eUSD and a collateral are supported.
+    UnsupportedSwap,
+    /// Minting would exceed the debt ceiling or the mint cap of the epoch.
+    MintCapReached,
+    /// The collateral ratio is below the minimum required to mint eUSD.
+    CollateralRatioTooLow,
+    /// Only redemptions of eUSD for ICP are possible during the global settlement.
+    GlobalSettlement,
 }
 
 #[derive(
@@ -49,12 +64,125 @@ pub struct Swap {
     pub rate: u64,
     pub fee: u64,
     pub timestamp: u64,
+    /// The account receiving the swapped asset, the caller if not set.
+    #[serde(default)]
+    pub to_account: Option<Account>,
+    /// The fee rate (e8s) the swap was priced at.
+    #[serde(default)]
+    pub fee_rate: Option<u64>,
+}
+
+impl Swap {
+    /// The collateral side of the swap, the other side being eUSD.
+    pub fn collateral(&self) -> &Asset {
+        if self.from == Asset::EUSD {
+            &self.to
+        } else {
+            &self.from
+        }
+    }
+}
+
+/// Whether a swap mints or redeems eUSD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapDirection {
+    Mint,
+    Redeem,
+}
+
+/// The curves setting the fee rate of swaps.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, serde::Serialize, candid::Deserialize)]
+pub struct SwapFeeCurves {
+    /// Maps the collateral ratio to the fee rate of mints.
+    pub mint: PiecewiseLinearCurve,
+    /// Maps the collateral ratio to the fee rate of redemptions.
+    pub redeem: PiecewiseLinearCurve,
+    /// Maps the covered ratio to the fraction of the fee rate charged.
+    pub coverage_multiplier: PiecewiseLinearCurve,
+}
+
+impl SwapFeeCurves {
+    /// Returns the fee rate (e8s) of a swap at the given ratios.
+    pub fn fee_rate(
+        &self,
+        direction: SwapDirection,
+        collateral_ratio: u64,
+        covered_ratio: u64,
+    ) -> u64 {
+        let fee_rate = match direction {
+            SwapDirection::Mint => self.mint.evaluate(collateral_ratio),
+            SwapDirection::Redeem => self.redeem.evaluate(collateral_ratio),
+        };
+        multiply_e8s(fee_rate, self.coverage_multiplier.evaluate(covered_ratio))
+    }
+
+    /// The curves charging `fee_rate` (e8s) on every swap.
+    pub fn flat(fee_rate: u64) -> Self {
+        Self {
+            mint: PiecewiseLinearCurve::new(vec![(0, fee_rate)]),
+            redeem: PiecewiseLinearCurve::new(vec![(0, fee_rate)]),
+            coverage_multiplier: PiecewiseLinearCurve::new(vec![(0, E8S)]),
+        }
+    }
+
+    /// Returns the highest fee rate (e8s) of the curves, the fee
+    /// multiplier being at most 100%.
+    pub fn max_fee_rate(&self) -> u64 {
+        self.mint
+            .points
+            .iter()
+            .chain(self.redeem.points.iter())
+            .map(|point| point.y)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub fn validate_swap_fee_curves(curves: &SwapFeeCurves) -> Result<(), String> {
+    for (name, curve) in [("mint", &curves.mint), ("redeem", &curves.redeem)] {
+        curve.validate()?;
+        if let Some(point) = curve.points.iter().find(|p| p.y >= E8S) {
+            return Err(format!(
+                "the {} fee rate {} at collateral ratio {} is not lower than 100%",
+                name, point.y, point.x
+            ));
+        }
+    }
+    curves.coverage_multiplier.validate()?;
+    if let Some(point) = curves.coverage_multiplier.points.iter().find(|p| p.y > E8S) {
+        return Err(format!(
+            "the fee multiplier {} at covered ratio {} is greater than 100%",
+            point.y, point.x
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the fee rate of swaps stays lower than 100% once the
+/// recovery surcharge is added.
+pub fn validate_swap_fee_rate(
+    curves: &SwapFeeCurves,
+    recovery_fee_surcharge: u64,
+) -> Result<(), String> {
+    let max_fee_rate = curves.max_fee_rate().saturating_add(recovery_fee_surcharge);
+    if max_fee_rate >= E8S {
+        return Err(format!(
+            "the swap fee rate {} with the recovery surcharge {} is not lower than 100%",
+            curves.max_fee_rate(),
+            recovery_fee_surcharge
+        ));
+    }
+    Ok(())
 }
 
 #[derive(
     candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Serialize, candid::Deserialize,
 )]
 pub struct SwapSuccess {
+    /// The asset of the swapped deposit, not recorded by the earlier
+    /// versions.
+    #[serde(default)]
+    pub from_asset: Option<Asset>,
     pub from_block_index: u64,
     pub to_block_index: u64,
 }
@@ -64,6 +192,10 @@ pub struct SwapArg {
     pub from_asset: Asset,
     pub to_asset: Asset,
     pub amount: u64,
+    /// The account receiving the swapped asset, the caller if not set.
+    pub to_account: Option<Account>,
+    /// How the swapped asset is collected, the deposit subaccount if not set.
+    pub deposit_method: Option<DepositMethod>,
 }
 
 impl From<GuardError> for SwapError {
@@ -77,34 +209,121 @@ impl From<GuardError> for SwapError {
     }
 }
 
-pub async fn convert_icp_to_eusd(amount: u64) -> Result<u64, SwapError> {
+/// The outcome of a swap at the current price and fees.
+#[derive(candid::CandidType, Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct SwapQuote {
+    /// The price of the collateral side e8s.
+    pub rate: u64,
+    /// The fee rate e8s, see [CoreState::get_swap_fee_rate].
+    pub fee_rate: u64,
+    /// The fee in the input asset.
+    pub fee: u64,
+    /// The amount of the output asset, before ledger fees.
+    pub amount_out: u64,
+}
+
+/// Prices a swap of `amount` from `from` to `to`, at the worse of the spot
+/// and the average price for the caller.
+pub fn quote_swap(
+    state: &CoreState,
+    from: &Asset,
+    to: &Asset,
+    amount: u64,
+    now: u64,
+) -> Result<SwapQuote, SwapError> {
+    let (collateral, direction) = match (from, to) {
+        (Asset::EUSD, Asset::EUSD) => return Err(SwapError::UnsupportedSwap),
+        (collateral, Asset::EUSD) => (collateral, SwapDirection::Mint),
+        (Asset::EUSD, collateral) => (collateral, SwapDirection::Redeem),
+        _ => return Err(SwapError::UnsupportedSwap),
+    };
+    if let Some(settlement) = &state.global_settlement {
+        if direction == SwapDirection::Mint {
+            return Err(SwapError::GlobalSettlement);
+        }
+        let icp_rate = settlement.redemption_rate.ok_or_else(|| {
+            SwapError::TemporarilyUnavailable(
+                "the global settlement is closing leverage positions".to_string(),
+            )
+        })?;
+        let rate = match collateral {
+            Asset::Collateral(symbol) => *settlement
+                .collateral_redemption_rates
+                .get(symbol)
+                .ok_or_else(|| SwapError::UnknownCollateral(symbol.clone()))?,
+            _ => icp_rate,
+        };
+        return Ok(SwapQuote {
+            rate,
+            fee_rate: 0,
+            fee: 0,
+            amount_out: from_value_e8s(amount, rate, state.get_decimals(collateral)),
+        });
+    }
+    if let Asset::Collateral(symbol) = collateral {
+        if state.get_collateral(symbol).is_none() {
+            return Err(SwapError::UnknownCollateral(symbol.clone()));
+        }
+    }
+    // The average price lags the spot price, a swap gets the worse of the
+    // two so that it cannot trade a stale average against the protocol.
+    let spot = state
+        .get_last_price(collateral)
+        .ok_or(SwapError::NoPriceData)?
+        .rate;
+    let average = state
+        .get_average_price(collateral, now)
+        .ok_or(SwapError::NoPriceData)?
+        .rate;
+    let rate = match direction {
+        SwapDirection::Mint => spot.min(average),
+        SwapDirection::Redeem => spot.max(average),
+    };
+    let fee_rate = state.get_swap_fee_rate(direction);
+    let fee = multiply_e8s(fee_rate, amount);
+    let amount_after_fee = amount.checked_sub(fee).ok_or(SwapError::AmountTooSmall)?;
+    let decimals = state.get_decimals(collateral);
+    let amount_out = match direction {
+        SwapDirection::Mint => to_value_e8s(amount_after_fee, rate, decimals),
+        SwapDirection::Redeem => from_value_e8s(amount_after_fee, rate, decimals),
+    };
+    Ok(SwapQuote {
+        rate,
+        fee_rate,
+        fee,
+        amount_out,
+    })
+}
+
+pub async fn convert_icp_to_eusd(
+    amount: u64,
+    to_account: Option<Account>,
+    deposit_method: DepositMethod,
+) -> Result<u64, SwapError> {
     let caller = ic_cdk::caller();
     let _guard = convert_update_guard(caller)?;
 
-    if read_state(|s| s.icp_prices.is_empty()) {
-        return Err(SwapError::NoPriceData);
-    }
+    let quote =
+        read_state(|s| quote_swap(s, &Asset::ICP, &Asset::EUSD, amount, ic_cdk::api::time()))?;
     if read_state(|s| amount < s.min_amount_to_stable) {
         return Err(SwapError::AmountTooSmall);
     }
+    let _reservation =
+        mutate_state(|s| reserve_mint_capacity(s, caller, quote.amount_out, ic_cdk::api::time()))?;
 
-    let caller_subaccount = compute_subaccount(PrincipalId(caller), 0);
-    let core_id = ic_cdk::id();
-    match transfer_icp(Some(caller_subaccount), core_id, amount).await {
+    match pull_icp(caller, amount, deposit_method).await {
         Ok(from_block_index) => {
-            // We can unwrap as we reject calls if we don't have any price entry
-            let last_icp_price_entry = read_state(|s| s.get_last_icp_price()).unwrap();
-            let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), amount);
-            debug_assert!(amount >= protocol_fee);
             let swap = Swap {
                 caller,
                 from: Asset::ICP,
                 to: Asset::EUSD,
                 from_block_index,
-                rate: last_icp_price_entry.rate,
-                fee: protocol_fee,
+                rate: quote.rate,
+                fee: quote.fee,
                 from_amount: amount,
                 timestamp: ic_cdk::api::time(),
+                to_account,
+                fee_rate: Some(quote.fee_rate),
             };
             log!(
                 crate::P1,
@@ -123,33 +342,36 @@ pub async fn convert_icp_to_eusd(amount: u64) -> Result<u64, SwapError> {
     }
 }
 
-pub async fn convert_eusd_to_icp(amount: u64) -> Result<u64, SwapError> {
+pub async fn convert_eusd_to_icp(
+    amount: u64,
+    to_account: Option<Account>,
+    deposit_method: DepositMethod,
+) -> Result<u64, SwapError> {
     let caller = ic_cdk::caller();
     let _guard = convert_update_guard(caller)?;
 
-    if read_state(|s| s.icp_prices.is_empty()) {
-        return Err(SwapError::NoPriceData);
-    }
+    let quote =
+        read_state(|s| quote_swap(s, &Asset::EUSD, &Asset::ICP, amount, ic_cdk::api::time()))?;
     if read_state(|s| amount < s.min_amount_from_stable) {
         return Err(SwapError::AmountTooSmall);
     }
-    let amount_to_transfer = amount;
-    match burn_eusd(caller, amount_to_transfer).await {
+    let redeemable_amount = read_state(|s| s.get_redeemable_icp_amount());
+    if redeemable_amount < quote.amount_out {
+        return Err(SwapError::InsufficientCollateral(redeemable_amount));
+    }
+    match pull_eusd(caller, amount, deposit_method).await {
         Ok(eusd_block_index) => {
-            // Here we can unwrap as we reject calls if we don't have any price entry
-            let last_icp_price_entry = read_state(|s| s.get_last_icp_price()).unwrap();
-            let last_icp_price = last_icp_price_entry.rate;
-            let protocol_fee = multiply_e8s(read_state(|s| s.fees.base_fee), amount_to_transfer);
-            let deposited_amount = amount_to_transfer - protocol_fee;
             let swap = Swap {
                 caller,
                 from: Asset::EUSD,
                 to: Asset::ICP,
                 from_block_index: eusd_block_index,
-                rate: last_icp_price_entry.rate,
-                fee: protocol_fee,
-                from_amount: amount_to_transfer,
+                rate: quote.rate,
+                fee: quote.fee,
+                from_amount: amount,
                 timestamp: ic_cdk::api::time(),
+                to_account,
+                fee_rate: Some(quote.fee_rate),
             };
             log!(
                 crate::P1,
@@ -162,9 +384,7 @@ pub async fn convert_eusd_to_icp(amount: u64) -> Result<u64, SwapError> {
                 record_swap(s, swap.clone());
             });
 
-            let icp_amount_to_transfer: u64 = divide_e8s(deposited_amount, last_icp_price);
-
-            assert!(icp_amount_to_transfer > protocol_fee);
+            assert!(quote.amount_out > quote.fee);
             schedule_now(TaskType::ProcessLogic);
             maybe_close_leverage_position();
             Ok(eusd_block_index)
@@ -173,6 +393,131 @@ pub async fn convert_eusd_to_icp(amount: u64) -> Result<u64, SwapError> {
     }
 }
 
+pub async fn convert_collateral_to_eusd(
+    symbol: String,
+    amount: u64,
+    to_account: Option<Account>,
+    deposit_method: DepositMethod,
+) -> Result<u64, SwapError> {
+    let caller = ic_cdk::caller();
+    let _guard = convert_update_guard(caller)?;
+
+    let from = Asset::Collateral(symbol.clone());
+    let quote = read_state(|s| quote_swap(s, &from, &Asset::EUSD, amount, ic_cdk::api::time()))?;
+    if read_state(|s| quote.amount_out < s.min_amount_from_stable) {
+        return Err(SwapError::AmountTooSmall);
+    }
+    let _reservation =
+        mutate_state(|s| reserve_mint_capacity(s, caller, quote.amount_out, ic_cdk::api::time()))?;
+    let collateral = read_state(|s| s.get_collateral(&symbol).cloned())
+        .expect("bug: quoted an unknown collateral");
+
+    match pull_collateral(&collateral, caller, amount, deposit_method).await {
+        Ok(from_block_index) => {
+            let swap = Swap {
+                caller,
+                from,
+                to: Asset::EUSD,
+                from_block_index,
+                rate: quote.rate,
+                fee: quote.fee,
+                from_amount: amount,
+                timestamp: ic_cdk::api::time(),
+                to_account,
+                fee_rate: Some(quote.fee_rate),
+            };
+            log!(
+                crate::P1,
+                "[swap]: Success swap from {} {:?} to {:?} ",
+                swap.from_amount,
+                swap.from,
+                swap.to,
+            );
+            mutate_state(|s| {
+                record_swap(s, swap.clone());
+            });
+            schedule_now(TaskType::ProcessLogic);
+            Ok(from_block_index)
+        }
+        Err(e) => Err(SwapError::CollateralLedgerError(e)),
+    }
+}
+
+pub async fn convert_eusd_to_collateral(
+    symbol: String,
+    amount: u64,
+    to_account: Option<Account>,
+    deposit_method: DepositMethod,
+) -> Result<u64, SwapError> {
+    let caller = ic_cdk::caller();
+    let _guard = convert_update_guard(caller)?;
+
+    let to = Asset::Collateral(symbol.clone());
+    let quote = read_state(|s| quote_swap(s, &Asset::EUSD, &to, amount, ic_cdk::api::time()))?;
+    if read_state(|s| amount < s.min_amount_from_stable) {
+        return Err(SwapError::AmountTooSmall);
+    }
+    let transfer_fee = read_state(|s| s.get_collateral(&symbol).map(|c| c.transfer_fee))
+        .expect("bug: quoted an unknown collateral");
+    if quote.amount_out <= transfer_fee {
+        return Err(SwapError::AmountTooSmall);
+    }
+    let available_amount = read_state(|s| s.get_available_collateral_amount(&symbol));
+    if available_amount < quote.amount_out {
+        return Err(SwapError::InsufficientCollateral(available_amount));
+    }
+
+    match pull_eusd(caller, amount, deposit_method).await {
+        Ok(eusd_block_index) => {
+            let swap = Swap {
+                caller,
+                from: Asset::EUSD,
+                to,
+                from_block_index: eusd_block_index,
+                rate: quote.rate,
+                fee: quote.fee,
+                from_amount: amount,
+                timestamp: ic_cdk::api::time(),
+                to_account,
+                fee_rate: Some(quote.fee_rate),
+            };
+            log!(
+                crate::P1,
+                "[swap]: Success swap from {} {:?} to {:?} ",
+                swap.from_amount / E8S,
+                swap.from,
+                swap.to,
+            );
+            mutate_state(|s| {
+                record_swap(s, swap.clone());
+            });
+            schedule_now(TaskType::ProcessLogic);
+            Ok(eusd_block_index)
+        }
+        Err(e) => Err(SwapError::EUSDLedgerError(e)),
+    }
+}
+
+/// Checks that minting `eusd_amount` keeps the collateral ratio above
+/// the minimum and stays within the debt ceiling and the mint cap of the
+/// current epoch, then reserves it so that the concurrent swaps cannot
+/// exceed them while the deposit is pulled. The concurrent mints count
+/// in the collateral ratio as well.
+fn reserve_mint_capacity(
+    state: &mut CoreState,
+    caller: Principal,
+    eusd_amount: u64,
+    now: u64,
+) -> Result<MintReservation, SwapError> {
+    if state.get_collateral_ratio_after_mint(eusd_amount) < state.min_collateral_ratio {
+        return Err(SwapError::CollateralRatioTooLow);
+    }
+    if state.get_remaining_mint_capacity(now) < eusd_amount {
+        return Err(SwapError::MintCapReached);
+    }
+    Ok(MintReservation::new(state, caller, eusd_amount))
+}
+
 fn maybe_close_leverage_position() {
     let icp_collateral_amount = read_state(|s| s.icp_collateral_amount);
     let covered_icp_collateral_amount = read_state(|s| s.icp_collateral_covered_amount);
@@ -195,7 +540,10 @@ fn maybe_close_leverage_position() {
     );
     // Ordered set of margin ratios
     let mut margin_map: BTreeSet<(u64, LeveragePosition)> = Default::default();
-    let current_price = read_state(|s| s.get_last_icp_price().unwrap().rate);
+    let current_price = match read_state(|s| s.get_last_icp_price()) {
+        Some(price) => price.rate,
+        None => return,
+    };
     for (_, positions) in read_state(|s| s.leverage_positions.clone()) {
         for position in positions {
             let margin_ratio = compute_margin_ratio(
@@ -240,3 +588,326 @@ fn test_leverage_map() {
     let result = compute_margin_ratio(current_price, entry_price, amount, covered_amount);
     dbg!(result * 100 / 100_000_000);
 }
+
+#[test]
+fn test_mint_cap_rolling_window() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+
+    let mut state = CoreState::from(InitArgs {
+        debt_ceiling: Some(15 * E8S),
+        mint_cap_per_epoch: Some(10 * E8S),
+        mint_epoch_nanos: Some(100),
+        ..default_init_args()
+    });
+    let swap = |from_block_index, timestamp| Swap {
+        caller: Principal::anonymous(),
+        from: Asset::ICP,
+        from_block_index,
+        from_amount: 4 * E8S,
+        to: Asset::EUSD,
+        rate: E8S,
+        fee: 0,
+        timestamp,
+        to_account: None,
+        fee_rate: None,
+    };
+
+    state.open_swap(swap(0, 10));
+    state.open_swap(swap(1, 50));
+    assert_eq!(state.get_remaining_mint_capacity(60), 2 * E8S);
+    // The first mint left the epoch.
+    assert_eq!(state.get_remaining_mint_capacity(115), 6 * E8S);
+
+    state.finish_swap(&Asset::ICP, 0);
+    state.finish_swap(&Asset::ICP, 1);
+    state.open_swap(swap(2, 200));
+    assert_eq!(state.recent_mints.len(), 1);
+    // The debt ceiling is now the binding limit.
+    assert_eq!(state.get_remaining_mint_capacity(200), 3 * E8S);
+}
+
+#[test]
+fn test_reserve_mint_capacity() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::replace_state;
+
+    replace_state(CoreState::from(InitArgs {
+        debt_ceiling: Some(15 * E8S),
+        mint_cap_per_epoch: Some(10 * E8S),
+        ..default_init_args()
+    }));
+    let user = |n: u8| Principal::from_slice(&[n]);
+
+    // The concurrent swaps share the capacity while their deposits are pulled.
+    let reservation = mutate_state(|s| reserve_mint_capacity(s, user(1), 6 * E8S, 0))
+        .expect("failed to reserve the capacity");
+    assert_eq!(read_state(|s| s.get_remaining_mint_capacity(0)), 4 * E8S);
+    assert!(matches!(
+        mutate_state(|s| reserve_mint_capacity(s, user(2), 6 * E8S, 0)),
+        Err(SwapError::MintCapReached)
+    ));
+    let other_reservation = mutate_state(|s| reserve_mint_capacity(s, user(2), 4 * E8S, 0))
+        .expect("failed to reserve the capacity");
+    assert_eq!(read_state(|s| s.get_remaining_mint_capacity(0)), 0);
+
+    drop(reservation);
+    drop(other_reservation);
+    assert!(read_state(|s| s.mint_reservations.is_empty()));
+    assert_eq!(read_state(|s| s.get_remaining_mint_capacity(0)), 10 * E8S);
+}
+
+#[test]
+fn test_open_swaps_keyed_by_asset() {
+    use crate::lifecycle::init::default_init_args;
+
+    let mut state = CoreState::from(default_init_args());
+    let swap = |from: Asset, to: Asset| Swap {
+        caller: Principal::anonymous(),
+        from,
+        from_block_index: 0,
+        from_amount: E8S,
+        to,
+        rate: E8S,
+        fee: 0,
+        timestamp: 0,
+        to_account: None,
+        fee_rate: None,
+    };
+
+    // The ICP and eUSD ledgers both have a block 0.
+    state.open_swap(swap(Asset::ICP, Asset::EUSD));
+    state.open_swap(swap(Asset::EUSD, Asset::ICP));
+    assert_eq!(state.open_swaps.len(), 2);
+
+    state.finish_swap(&Asset::ICP, 0);
+    assert_eq!(
+        state.open_swaps.keys().collect::<Vec<_>>(),
+        vec![&(Asset::EUSD, 0)]
+    );
+    assert_eq!(state.total_eusd_minted, E8S);
+}
+
+#[test]
+fn test_redemption_fee_in_icp() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+
+    let mut state = CoreState::from(InitArgs {
+        treasury_fee_share: Some(E8S / 2),
+        ..default_init_args()
+    });
+    state.icp_collateral_amount = 10 * E8S;
+    let known_icp_balance = state.get_known_icp_balance();
+    // 10 eUSD to ICP at 5$ with a fee of 1 eUSD.
+    state.open_swap(Swap {
+        caller: Principal::anonymous(),
+        from: Asset::EUSD,
+        from_block_index: 0,
+        from_amount: 10 * E8S,
+        to: Asset::ICP,
+        rate: 5 * E8S,
+        fee: E8S,
+        timestamp: 0,
+        to_account: None,
+        fee_rate: None,
+    });
+
+    // The 0.2 ICP kept move from the collateral to the split.
+    assert_eq!(state.icp_collateral_amount, 98 * E8S / 10);
+    assert_eq!(state.treasury_amount, E8S / 10);
+    assert_eq!(state.get_known_icp_balance(), known_icp_balance);
+
+    state.finish_swap(&Asset::EUSD, 0);
+    assert_eq!(state.icp_collateral_amount, 8 * E8S);
+}
+
+#[test]
+fn test_quote_swap_at_worse_price() {
+    use crate::lifecycle::init::default_init_args;
+    use crate::price::TWAP_WINDOW_NANOS;
+    use crate::state::IcpPrice;
+
+    let mut state = CoreState::from(default_init_args());
+    let now = TWAP_WINDOW_NANOS;
+    state.insert_price(&Asset::ICP, 0, IcpPrice { rate: 4 * E8S });
+    state.insert_price(&Asset::ICP, now / 2, IcpPrice { rate: 8 * E8S });
+    // The average is 6$ and the spot 8$: a mint values the ICP at 6$ and a
+    // redemption pays it out at 8$.
+    let mint = quote_swap(&state, &Asset::ICP, &Asset::EUSD, E8S, now).unwrap();
+    assert_eq!(mint.rate, 6 * E8S);
+    let redeem = quote_swap(&state, &Asset::EUSD, &Asset::ICP, E8S, now).unwrap();
+    assert_eq!(redeem.rate, 8 * E8S);
+
+    // Once the spot drops under the average, the sides swap.
+    state.insert_price(&Asset::ICP, now, IcpPrice { rate: 2 * E8S });
+    let mint = quote_swap(&state, &Asset::ICP, &Asset::EUSD, E8S, now).unwrap();
+    assert_eq!(mint.rate, 2 * E8S);
+    let redeem = quote_swap(&state, &Asset::EUSD, &Asset::ICP, E8S, now).unwrap();
+    assert_eq!(redeem.rate, 6 * E8S);
+}
+
+#[test]
+fn test_regime_thresholds() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::{IcpPrice, ProtocolRegime};
+    use ic_ledger_types::Timestamp;
+
+    let mut state = CoreState::from(InitArgs {
+        min_collateral_ratio: Some(110_000_000),
+        recovery_collateral_ratio: Some(150_000_000),
+        recovery_fee_surcharge: Some(500_000),
+        ..default_init_args()
+    });
+    assert_eq!(state.get_regime(), ProtocolRegime::Normal);
+
+    // 10 ICP backing 10 eUSD.
+    state.icp_collateral_amount = 10 * E8S;
+    state.total_eusd_minted = 10 * E8S;
+    let set_icp_price = |state: &mut CoreState, rate| {
+        state
+            .icp_prices
+            .insert(Timestamp { timestamp_nanos: 0 }, IcpPrice { rate })
+    };
+
+    set_icp_price(&mut state, 2 * E8S);
+    assert_eq!(state.get_regime(), ProtocolRegime::Normal);
+    assert_eq!(
+        state.get_swap_fee_rate(SwapDirection::Mint),
+        state.fees.base_fee
+    );
+
+    set_icp_price(&mut state, 120_000_000);
+    assert_eq!(state.get_regime(), ProtocolRegime::Recovery);
+    assert_eq!(
+        state.get_swap_fee_rate(SwapDirection::Mint),
+        state.fees.base_fee + 500_000
+    );
+
+    set_icp_price(&mut state, E8S);
+    assert_eq!(state.get_regime(), ProtocolRegime::Critical);
+}
+
+#[test]
+fn test_reserved_mints_in_collateral_ratio() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::{replace_state, IcpPrice, ProtocolRegime};
+    use ic_ledger_types::Timestamp;
+
+    let mut state = CoreState::from(InitArgs {
+        min_collateral_ratio: Some(110_000_000),
+        ..default_init_args()
+    });
+    // 12 ICP at 1$ backing 10 eUSD.
+    state.icp_collateral_amount = 12 * E8S;
+    state.total_eusd_minted = 10 * E8S;
+    state
+        .icp_prices
+        .insert(Timestamp { timestamp_nanos: 0 }, IcpPrice { rate: E8S });
+
+    // The recovery ratio left at 0 is below the minimum.
+    assert!(state.check_collateral_ratios().is_err());
+    state.recovery_collateral_ratio = 110_000_000;
+    assert_eq!(state.check_settings(), Ok(()));
+    replace_state(state);
+    let user = |n: u8| Principal::from_slice(&[n]);
+
+    // (12 + 20) / (10 + 20) would end below 110%.
+    assert!(matches!(
+        mutate_state(|s| reserve_mint_capacity(s, user(1), 20 * E8S, 0)),
+        Err(SwapError::CollateralRatioTooLow)
+    ));
+
+    let reservation = mutate_state(|s| reserve_mint_capacity(s, user(1), 2 * E8S, 0))
+        .expect("failed to reserve the capacity");
+    assert_eq!(read_state(|s| s.get_regime()), ProtocolRegime::Normal);
+    assert_eq!(
+        read_state(|s| s.get_collateral_ratio_with_reserved_mints()),
+        116_666_666
+    );
+    // (14 + 10) / (12 + 10) is below 110% once the first mint lands.
+    assert!(matches!(
+        mutate_state(|s| reserve_mint_capacity(s, user(2), 10 * E8S, 0)),
+        Err(SwapError::CollateralRatioTooLow)
+    ));
+
+    drop(reservation);
+    // (12 + 10) / (10 + 10) is exactly 110%.
+    assert!(mutate_state(|s| reserve_mint_capacity(s, user(2), 10 * E8S, 0)).is_ok());
+}
+
+#[test]
+fn test_swap_fee_curves() {
+    let curves = SwapFeeCurves {
+        // Minting gets cheaper as the protocol is over-collateralized.
+        mint: PiecewiseLinearCurve::new(vec![(E8S, 1_000_000), (2 * E8S, 100_000)]),
+        // Redeeming gets cheaper as the protocol is under-collateralized.
+        redeem: PiecewiseLinearCurve::new(vec![(E8S, 100_000), (2 * E8S, 1_000_000)]),
+        coverage_multiplier: PiecewiseLinearCurve::new(vec![(0, E8S), (E8S, E8S / 2)]),
+    };
+    assert!(validate_swap_fee_curves(&curves).is_ok());
+    assert!(validate_swap_fee_curves(&SwapFeeCurves::flat(crate::state::DEFAULT_BASE_FEE)).is_ok());
+    assert_eq!(curves.max_fee_rate(), 1_000_000);
+
+    assert_eq!(curves.fee_rate(SwapDirection::Mint, E8S / 2, 0), 1_000_000);
+    assert_eq!(
+        curves.fee_rate(SwapDirection::Mint, 150_000_000, 0),
+        550_000
+    );
+    assert_eq!(curves.fee_rate(SwapDirection::Mint, u64::MAX, 0), 100_000);
+    assert_eq!(curves.fee_rate(SwapDirection::Redeem, E8S / 2, 0), 100_000);
+    assert_eq!(
+        curves.fee_rate(SwapDirection::Redeem, 3 * E8S, 0),
+        1_000_000
+    );
+    // A fully covered collateral halves the fee.
+    assert_eq!(
+        curves.fee_rate(SwapDirection::Redeem, 3 * E8S, E8S),
+        500_000
+    );
+
+    let invalid = SwapFeeCurves {
+        mint: PiecewiseLinearCurve::new(vec![(0, E8S)]),
+        ..curves.clone()
+    };
+    assert!(validate_swap_fee_curves(&invalid).is_err());
+    let invalid = SwapFeeCurves {
+        coverage_multiplier: PiecewiseLinearCurve::new(vec![(0, E8S + 1)]),
+        ..curves
+    };
+    assert!(validate_swap_fee_curves(&invalid).is_err());
+
+    // The recovery surcharge must keep the fee rate lower than 100%.
+    let curves = SwapFeeCurves::flat(60_000_000);
+    assert!(validate_swap_fee_curves(&curves).is_ok());
+    assert!(validate_swap_fee_rate(&curves, 39_999_999).is_ok());
+    assert!(validate_swap_fee_rate(&curves, 40_000_000).is_err());
+}
+
+#[test]
+fn test_swap_fee_follows_base_fee() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::{IcpPrice, DEFAULT_BASE_FEE};
+    use ic_ledger_types::Timestamp;
+
+    let mut state = CoreState::from(default_init_args());
+    assert_eq!(state.swap_fee_curves, SwapFeeCurves::flat(DEFAULT_BASE_FEE));
+    state.fees.base_fee = 500_000;
+    state.reinit(default_init_args());
+    assert_eq!(state.swap_fee_curves, SwapFeeCurves::flat(500_000));
+
+    assert!(CoreState::from(InitArgs {
+        recovery_fee_surcharge: Some(E8S - DEFAULT_BASE_FEE),
+        ..default_init_args()
+    })
+    .check_settings()
+    .is_err());
+
+    // A fee rate above 100% leaves nothing to swap.
+    state.swap_fee_curves = SwapFeeCurves::flat(2 * E8S);
+    state
+        .icp_prices
+        .insert(Timestamp { timestamp_nanos: 0 }, IcpPrice { rate: E8S });
+    assert_eq!(
+        quote_swap(&state, &Asset::ICP, &Asset::EUSD, E8S, 0),
+        Err(SwapError::AmountTooSmall)
+    );
+}
@@ -0,0 +1,146 @@
+use crate::guard::treasury_update_guard;
+use crate::guard::GuardError;
+use crate::logs::P0;
+use crate::management::transfer_icp;
+use crate::state::audit::record_withdraw_treasury;
+use crate::state::{mutate_state, read_state};
+use crate::ICP_TRANSFER_FEE;
+use candid::CandidType;
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+
+#[derive(CandidType, serde::Deserialize, Debug, PartialEq, Eq)]
+pub enum TreasuryError {
+    CallerNotController,
+    AlreadyProcessing,
+    TemporarilyUnavailable(String),
+    AmountTooSmall,
+    /// The treasury holds less than the requested amount.
+    InsufficientTreasury(u64),
+    LedgerError(TransferError),
+}
+
+impl From<GuardError> for TreasuryError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => {
+                Self::TemporarilyUnavailable("too many concurrent requests".to_string())
+            }
+        }
+    }
+}
+
+/// Sends `amount` of the treasury to `to`, the ledger fee is paid
+/// out of `amount`. Only the controllers can withdraw the treasury.
+pub async fn withdraw_treasury(amount: u64, to: Account) -> Result<u64, TreasuryError> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(TreasuryError::CallerNotController);
+    }
+    // Withdrawals are serialized so that they cannot exceed the treasury.
+    let _guard = treasury_update_guard(ic_cdk::id())?;
+
+    if amount <= ICP_TRANSFER_FEE {
+        return Err(TreasuryError::AmountTooSmall);
+    }
+    let treasury_amount = read_state(|s| s.treasury_amount);
+    if treasury_amount < amount {
+        return Err(TreasuryError::InsufficientTreasury(treasury_amount));
+    }
+
+    match transfer_icp(None, to, amount - ICP_TRANSFER_FEE).await {
+        Ok(block_index) => {
+            log!(
+                P0,
+                "[withdraw_treasury]: {} withdrew {} ICP to {:?} at block {}",
+                caller,
+                amount,
+                to,
+                block_index
+            );
+            mutate_state(|s| {
+                record_withdraw_treasury(s, amount, to, block_index, ic_cdk::api::time())
+            });
+            Ok(block_index)
+        }
+        Err(e) => Err(TreasuryError::LedgerError(e)),
+    }
+}
+
+#[test]
+fn test_fee_split() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::CoreState;
+    use crate::E8S;
+    use candid::Principal;
+
+    let mut state = CoreState::from(InitArgs {
+        insurance_fund_fee_share: Some(10_000_000),
+        treasury_fee_share: Some(20_000_000),
+        reserve_fee_share: Some(30_000_000),
+        ..default_init_args()
+    });
+    let user = Principal::from_slice(&[1]);
+    state.credit_liquidity(user, E8S);
+
+    // 10% to the insurance fund, 20% to the treasury, 30% to the reserve.
+    state.distribute_fee(1_000);
+    assert_eq!(state.insurance_fund_amount, 100);
+    assert_eq!(state.treasury_amount, 200);
+    assert_eq!(state.reserve_amount, 300);
+    assert_eq!(state.liquidity_rewards.get(&user), Some(&400));
+    assert_eq!(state.get_known_icp_balance(), E8S + 1_000);
+
+    // The reserve covers the shortfalls after the insurance fund.
+    state.cover_collateral_shortfall(350);
+    assert_eq!(state.insurance_fund_amount, 0);
+    assert_eq!(state.reserve_amount, 50);
+    assert_eq!(state.icp_collateral_amount, 350);
+    assert_eq!(state.liquidity_provided.get(&user), Some(&E8S));
+
+    state.withdraw_treasury(200);
+    assert_eq!(state.treasury_amount, 0);
+    assert_eq!(state.check_invariants(), Ok(()));
+
+    state.treasury_fee_share = 70_000_000;
+    assert!(state.check_fee_shares().is_err());
+}
+
+#[test]
+fn test_replay_withdraw_treasury() {
+    use crate::lifecycle::init::{default_init_args, InitArgs};
+    use crate::state::eventlog::{apply_events, Event};
+    use crate::state::CoreState;
+    use candid::Principal;
+
+    let mut state = CoreState::from(InitArgs {
+        treasury_fee_share: Some(20_000_000),
+        ..default_init_args()
+    });
+    state.distribute_fee(1_000);
+    let withdraw_treasury = |amount| Event::WithdrawTreasury {
+        amount,
+        to: Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        },
+        block_index: 0,
+        timestamp: 0,
+    };
+
+    let state = apply_events(state, vec![withdraw_treasury(150)].into_iter())
+        .expect("failed to replay the treasury withdrawal");
+    assert_eq!(state.treasury_amount, 50);
+    assert_eq!(state.total_treasury_fees, 200);
+    assert_eq!(state.total_treasury_withdrawn, 150);
+    assert_eq!(state.check_invariants(), Ok(()));
+
+    // The withdrawals cannot exceed the treasury.
+    assert!(apply_events(state.clone(), vec![withdraw_treasury(51)].into_iter()).is_err());
+
+    let mut state = state;
+    state.treasury_amount += 1;
+    assert!(state.check_invariants().is_err());
+}
@@ -15,6 +15,12 @@ fn xrc_wasm() -> Vec<u8> {
         .unwrap()
 }
 
+fn archive_wasm() -> Vec<u8> {
+    // cargo build --target wasm32-unknown-unknown --release -p archive --locked
+    std::fs::read("/Users/leo/Code/ellipticdao/target/wasm32-unknown-unknown/release/archive.wasm")
+        .unwrap()
+}
+
 fn icrc1_ledger_wasm() -> Vec<u8> {
     std::fs::read("/Users/leo/Code/ic/bazel-bin/rs/rosetta-api/icrc1/ledger/ledger_canister.wasm")
         .unwrap()
@@ -29,3 +35,35 @@ fn test_core() {
 fn test_swap() {
     core_sm_tests::test_swap::test_swap(core_wasm(), xrc_wasm(), icrc1_ledger_wasm())
 }
+
+#[test]
+fn test_multi_collateral() {
+    core_sm_tests::test_collateral::test_multi_collateral(
+        core_wasm(),
+        xrc_wasm(),
+        icrc1_ledger_wasm(),
+    )
+}
+
+#[test]
+fn test_global_settlement() {
+    core_sm_tests::test_settlement::test_global_settlement(
+        core_wasm(),
+        xrc_wasm(),
+        icrc1_ledger_wasm(),
+    )
+}
+
+#[test]
+fn test_liquidity_withdrawals() {
+    core_sm_tests::test_liquidity::test_liquidity_withdrawals(
+        core_wasm(),
+        xrc_wasm(),
+        icrc1_ledger_wasm(),
+    )
+}
+
+#[test]
+fn test_archive() {
+    core_sm_tests::test_archive::test_archive(archive_wasm())
+}